@@ -0,0 +1,3 @@
+ALTER TABLE crates
+    ADD COLUMN max_version TEXT,
+    ADD COLUMN description TEXT;
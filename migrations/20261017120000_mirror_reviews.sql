@@ -0,0 +1,32 @@
+CREATE TABLE mirror_reviews(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    crate_name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    base_version TEXT,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'approved', 'rejected')),
+    proposed_by TEXT NOT NULL,
+    reviewed_by TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    reviewed_at TIMESTAMPTZ
+);
+
+-- a version can only be proposed again once it got rejected
+CREATE UNIQUE INDEX mirror_reviews_open_version
+    ON mirror_reviews (crate_name, version)
+    WHERE status <> 'rejected';
+
+CREATE TABLE mirror_review_files(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    review_id BIGINT NOT NULL,
+    path TEXT NOT NULL,
+    change TEXT NOT NULL CHECK (change IN ('added', 'removed', 'modified')),
+    diff TEXT NOT NULL,
+
+    CONSTRAINT fk_review_id
+		FOREIGN KEY(review_id)
+			REFERENCES mirror_reviews(id)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+	UNIQUE (review_id, path)
+);
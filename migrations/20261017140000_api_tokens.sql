@@ -0,0 +1,8 @@
+-- sessions created on login have no scopes, crate patterns or expiry and can do everything
+ALTER TABLE user_sessions
+    ADD COLUMN name TEXT NOT NULL DEFAULT 'login',
+    ADD COLUMN scopes TEXT[] CHECK (scopes <@ ARRAY['publish-new', 'publish-update', 'yank', 'change-owners']),
+    ADD COLUMN crate_patterns TEXT[],
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at TIMESTAMPTZ,
+    ADD COLUMN last_used_at TIMESTAMPTZ;
@@ -0,0 +1,9 @@
+-- only the sha256 of a token is stored, the prefix is kept to tell tokens apart
+ALTER TABLE user_sessions ADD COLUMN token_prefix TEXT;
+
+UPDATE user_sessions SET
+    token_prefix = left(token, 8),
+    token = encode(sha256(convert_to(token, 'UTF8')), 'hex');
+
+ALTER TABLE user_sessions ALTER COLUMN token_prefix SET NOT NULL;
+ALTER TABLE user_sessions RENAME COLUMN token TO token_hash;
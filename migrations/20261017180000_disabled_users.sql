@@ -0,0 +1,2 @@
+-- disabled users can't log in, their sessions get deleted when disabling them
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT false;
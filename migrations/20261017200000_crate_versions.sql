@@ -0,0 +1,31 @@
+-- versions published before this migration only exist in the git index
+CREATE TABLE crate_versions(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    crate_name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    published_by TEXT,
+    published_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    yanked BOOLEAN NOT NULL DEFAULT false,
+    description TEXT,
+    license TEXT,
+    repository TEXT,
+    keywords TEXT[] NOT NULL DEFAULT '{}',
+    categories TEXT[] NOT NULL DEFAULT '{}',
+    rust_version TEXT,
+    features JSONB NOT NULL DEFAULT '{}',
+
+    CONSTRAINT fk_crate_name
+		FOREIGN KEY(crate_name)
+			REFERENCES crates(name)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+    CONSTRAINT fk_published_by
+		FOREIGN KEY(published_by)
+			REFERENCES users(ident)
+				ON UPDATE CASCADE
+					ON DELETE SET NULL,
+
+	UNIQUE (crate_name, version)
+);
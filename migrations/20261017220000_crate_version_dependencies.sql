@@ -0,0 +1,2 @@
+-- dependencies as sent by cargo on publish, see `registry::PublishDep`
+ALTER TABLE crate_versions ADD COLUMN dependencies JSONB NOT NULL DEFAULT '[]';
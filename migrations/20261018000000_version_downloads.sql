@@ -0,0 +1,16 @@
+-- downloads are only counted from here on, there is no way to recover older ones
+CREATE TABLE version_downloads(
+    version_id BIGINT NOT NULL,
+    date DATE NOT NULL,
+    downloads BIGINT NOT NULL DEFAULT 0,
+
+    CONSTRAINT fk_version_id
+		FOREIGN KEY(version_id)
+			REFERENCES crate_versions(id)
+				ON DELETE CASCADE,
+
+	PRIMARY KEY (version_id, date)
+);
+
+-- the download urls only contain the checksum
+CREATE INDEX crate_versions_checksum ON crate_versions (checksum);
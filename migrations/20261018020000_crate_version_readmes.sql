@@ -0,0 +1,3 @@
+-- the markdown as uploaded, it only gets rendered when someone looks at it
+ALTER TABLE crate_versions ADD COLUMN readme TEXT;
+ALTER TABLE crate_versions ADD COLUMN readme_file TEXT;
@@ -0,0 +1,18 @@
+-- one rustdoc build per version, requeueing a version resets its build
+CREATE TABLE doc_builds(
+    version_id BIGINT PRIMARY KEY,
+    status TEXT NOT NULL DEFAULT 'queued',
+    log TEXT NOT NULL DEFAULT '',
+    -- where the docs start, relative to /docs/<crate>/<version>/
+    doc_path TEXT,
+    queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ,
+
+    CONSTRAINT fk_version_id
+		FOREIGN KEY(version_id)
+			REFERENCES crate_versions(id)
+				ON DELETE CASCADE
+);
+
+CREATE INDEX doc_builds_queued ON doc_builds (queued_at) WHERE status = 'queued';
@@ -0,0 +1,28 @@
+-- who changed what in the registry, rows outlive the crates, versions and tokens they mention
+CREATE TABLE audit_events(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    action TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    crate_name TEXT NOT NULL,
+    version TEXT,
+    token_id BIGINT,
+    token_name TEXT,
+    request_id TEXT,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_events_crate_name ON audit_events (crate_name);
+CREATE INDEX audit_events_actor ON audit_events (actor);
+CREATE INDEX audit_events_created_at ON audit_events (created_at);
+
+-- the log is append-only, even for the registry itself
+CREATE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
@@ -0,0 +1,45 @@
+-- groups of users that can own crates together, so crates don't lose their owners when someone leaves
+CREATE TABLE teams(
+    name TEXT PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE team_members(
+    team_name TEXT NOT NULL,
+    user_ident TEXT NOT NULL,
+
+    CONSTRAINT fk_team_name
+		FOREIGN KEY(team_name)
+			REFERENCES teams(name)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+    CONSTRAINT fk_user_ident
+		FOREIGN KEY(user_ident)
+			REFERENCES users(ident)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+	PRIMARY KEY (team_name, user_ident)
+);
+
+CREATE TABLE crate_team_owners(
+    crate_name TEXT NOT NULL,
+    team_name TEXT NOT NULL,
+
+    CONSTRAINT fk_crate_name
+		FOREIGN KEY(crate_name)
+			REFERENCES crates(name)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+    CONSTRAINT fk_team_name
+		FOREIGN KEY(team_name)
+			REFERENCES teams(name)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+	PRIMARY KEY (crate_name, team_name)
+);
+
+CREATE INDEX team_members_user_ident ON team_members (user_ident);
@@ -0,0 +1,31 @@
+-- users only become crate owners once they accept, an invitation per crate and user at most
+CREATE TABLE crate_owner_invitations(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    crate_name TEXT NOT NULL,
+    invitee_ident TEXT NOT NULL,
+    inviter_ident TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+
+    CONSTRAINT fk_crate_name
+		FOREIGN KEY(crate_name)
+			REFERENCES crates(name)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+    CONSTRAINT fk_invitee_ident
+		FOREIGN KEY(invitee_ident)
+			REFERENCES users(ident)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+    CONSTRAINT fk_inviter_ident
+		FOREIGN KEY(inviter_ident)
+			REFERENCES users(ident)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE,
+
+	UNIQUE (crate_name, invitee_ident)
+);
+
+CREATE INDEX crate_owner_invitations_invitee_ident ON crate_owner_invitations (invitee_ident);
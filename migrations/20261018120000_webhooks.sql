@@ -0,0 +1,42 @@
+-- webhooks without a crate get the events of every crate
+CREATE TABLE webhooks(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    crate_name TEXT,
+    url TEXT NOT NULL,
+    -- signs the payloads, so it has to be kept in plain text
+    secret TEXT NOT NULL,
+    -- audit actions the webhook is called for, all of them when null
+    events TEXT[],
+    created_by TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT fk_crate_name
+		FOREIGN KEY(crate_name)
+			REFERENCES crates(name)
+				ON UPDATE CASCADE
+					ON DELETE CASCADE
+);
+
+CREATE INDEX webhooks_crate_name ON webhooks (crate_name);
+
+-- queued in the same transaction as the change they announce, so none get lost
+CREATE TABLE webhook_deliveries(
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    webhook_id BIGINT NOT NULL,
+    event TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ,
+
+    CONSTRAINT fk_webhook_id
+		FOREIGN KEY(webhook_id)
+			REFERENCES webhooks(id)
+				ON DELETE CASCADE
+);
+
+CREATE INDEX webhook_deliveries_pending ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);
@@ -0,0 +1,2 @@
+-- crate names differing only in case or `-` vs `_` are the same crate
+CREATE UNIQUE INDEX crates_canonical_name ON crates (lower(replace(name, '-', '_')));
@@ -0,0 +1,4 @@
+-- row triggers don't fire for TRUNCATE, it needs a statement level one
+CREATE TRIGGER audit_events_no_truncate
+    BEFORE TRUNCATE ON audit_events
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
@@ -1,20 +1,27 @@
-use std::{path::PathBuf, sync::Arc};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::models;
+use sqlx::types::chrono::{Duration, Utc};
 use sqlx::PgPool;
 
 use axum::{
     async_trait,
     body::{Bytes, StreamBody},
-    extract::{ContentLengthLimit, Extension, FromRequest, Path, RequestParts},
-    http::{header, StatusCode},
-    response::{Headers, IntoResponse, Json},
+    extract::{ContentLengthLimit, Extension, FromRequest, Path, Query, RequestParts},
+    http::{header, Method, StatusCode},
+    response::{Headers, Html, IntoResponse, Json, Response},
     routing::{delete, get, put},
     Router,
 };
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
 
-use crate::{apiresponse::ApiError, openid, registry, settings};
+use crate::{
+    apiresponse::ApiError, crate_file, docs, downloads, metrics, mirror, openid, ratelimit, readme,
+    registry, settings, sparse_index, storage, web, webhooks,
+};
 use serde_json::{json, Value};
 use tracing::info;
 
@@ -37,50 +44,172 @@ impl<B> FromRequest<B> for models::UserSession
 where
     B: Send,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = Response;
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        let pool: Option<&PgPool> = req.extensions().unwrap().get();
-        let pool = pool.unwrap();
-
-        let mut trans = pool
-            .begin()
+        let session = session_from_token(req)
             .await
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Database error"))?;
+            .map_err(IntoResponse::into_response)?;
 
-        let authorization = req
-            .headers()
-            .and_then(|headers| headers.get(header::AUTHORIZATION));
-
-        if let Some(authorization) = authorization {
-            if let Ok(authorization) = authorization.to_str() {
-                if let Ok(Some(session)) =
-                    models::UserSession::by_token(&mut trans, authorization).await
-                {
-                    return Ok(session);
-                } else {
-                    return Err((StatusCode::FORBIDDEN, "session does not exist"));
+        // a user's tokens share one limit for everything that changes something
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let limiters: Option<&Arc<ratelimit::RateLimiters>> = req.extensions().unwrap().get();
+            limiters
+                .unwrap()
+                .mutations
+                .check(&session.ident)
+                .map_err(|wait| ratelimit::RateLimited(wait).into_response())?;
+        }
+
+        Ok(session)
+    }
+}
+
+async fn session_from_token<B: Send>(
+    req: &mut RequestParts<B>,
+) -> Result<models::UserSession, (StatusCode, &'static str)> {
+    let pool: Option<&PgPool> = req.extensions().unwrap().get();
+    let pool = pool.unwrap();
+
+    let mut trans = pool
+        .begin()
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Database error"))?;
+
+    let authorization = req
+        .headers()
+        .and_then(|headers| headers.get(header::AUTHORIZATION));
+
+    if let Some(authorization) = authorization {
+        if let Ok(authorization) = authorization.to_str() {
+            if let Ok(Some(session)) =
+                models::UserSession::by_token(&mut trans, authorization).await
+            {
+                if session.is_expired() {
+                    return Err((StatusCode::FORBIDDEN, "token has expired"));
                 }
+
+                models::UserSession::touch(&mut trans, session.id)
+                    .await
+                    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Database error"))?;
+                trans
+                    .commit()
+                    .await
+                    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Database error"))?;
+
+                return Ok(session);
+            } else {
+                return Err((StatusCode::FORBIDDEN, "session does not exist"));
             }
         }
+    }
+
+    Err((StatusCode::FORBIDDEN, "`authorization` header is missing"))
+}
+
+fn require_scope(
+    session: &models::UserSession,
+    scope: models::TokenScope,
+    crate_name: &str,
+) -> Result<(), ApiError> {
+    if session.allows(scope, crate_name) {
+        Ok(())
+    } else {
+        Err(ApiError(
+            format!(
+                "This token does not have the `{}` scope for crate `{}`",
+                scope.as_str(),
+                crate_name
+            ),
+            StatusCode::OK,
+        ))
+    }
+}
+
+// managing tokens and mirrored crates needs a login session, not a scoped api token
+fn require_full_access(session: &models::UserSession) -> Result<(), ApiError> {
+    if session.has_full_access() {
+        Ok(())
+    } else {
+        Err(ApiError(
+            String::from("This can't be done with a scoped api token"),
+            StatusCode::OK,
+        ))
+    }
+}
+
+// Ties audit events to a request, a proxy in front of the registry can pass its own id along
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+#[async_trait]
+impl<B> FromRequest<B> for RequestId
+where
+    B: Send,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if let Some(request_id) = req.extensions().and_then(|x| x.get::<RequestId>()) {
+            return Ok(request_id.clone());
+        }
 
-        return Err((StatusCode::FORBIDDEN, "`authorization` header is missing"));
+        // anyone could send the header, it's only the proxy's if one is known to set it
+        let trusted = req
+            .extensions()
+            .and_then(|x| x.get::<Arc<settings::Settings>>())
+            .map(|x| x.trust_request_id)
+            .unwrap_or(false);
+        let request_id = req
+            .headers()
+            .filter(|_| trusted)
+            .and_then(|headers| headers.get("x-request-id"))
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| !x.is_empty() && x.len() <= 128)
+            .map(String::from)
+            .unwrap_or_else(|| {
+                thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(16)
+                    .map(char::from)
+                    .collect()
+            });
+
+        let request_id = RequestId(request_id);
+        if let Some(extensions) = req.extensions_mut() {
+            extensions.insert(request_id.clone());
+        }
+
+        Ok(request_id)
     }
 }
 
+fn audit_actor<'a>(
+    session: &'a models::UserSession,
+    request_id: &'a RequestId,
+) -> models::AuditActor<'a> {
+    models::AuditActor {
+        ident: &session.ident,
+        token_id: Some(session.id),
+        token_name: Some(&session.name),
+        request_id: Some(&request_id.0),
+    }
+}
+
+// The session comes first, nobody gets to upload 20 MB without a valid token
 async fn publish(
-    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, { 1024 * 20_000 }>,
+    session: models::UserSession,
     sender: Extension<registry::SyncSender>,
     settings: Extension<Arc<settings::Settings>>,
     pool: Extension<PgPool>,
-    session: models::UserSession,
+    request_id: RequestId,
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, { 1024 * 20_000 }>,
 ) -> Result<Json<Value>, ApiError> {
     info!("{}", (*settings).repo_path);
     info!("{}", (*settings).storage_path);
 
     let mut trans = pool.begin().await?;
 
-    // TODO: validate package name
     // todo: handle bad data
 
     if bytes.len() < 8 {
@@ -104,13 +233,30 @@ async fn publish(
             Err(_) => return Err(ApiError(String::from("Invalid crate json"), StatusCode::OK)),
         };
 
+    if let Err(why) = registry::validate_package_name(&crate_json.name) {
+        return Err(ApiError(
+            format!("Invalid crate name: {}", why),
+            StatusCode::OK,
+        ));
+    }
+
+    let version = match semver::Version::parse(&crate_json.vers) {
+        Ok(version) => version,
+        Err(_) => {
+            return Err(ApiError(
+                String::from("Invalid crate version"),
+                StatusCode::OK,
+            ))
+        }
+    };
+
     let crate_len = u32::from_le_bytes(
         bytes[4 + json_len as usize..8 + json_len as usize]
             .try_into()
             .unwrap(),
     );
 
-    if json_len as usize + crate_len as usize + 8 < bytes.len() {
+    if json_len as usize + crate_len as usize + 8 != bytes.len() {
         return Err(ApiError(
             String::from("Invalid publish request"),
             StatusCode::OK,
@@ -120,25 +266,93 @@ async fn publish(
     let crate_data: Vec<u8> =
         bytes[8 + json_len as usize..8 + json_len as usize + crate_len as usize].to_vec();
 
+    // unpacking can take a moment, keep it off the async threads
+    let (name, vers, max_size) = (
+        crate_json.name.clone(),
+        crate_json.vers.clone(),
+        settings.max_unpacked_crate_size,
+    );
+    let crate_data = tokio::task::spawn_blocking(move || {
+        crate_file::validate(&crate_data, &name, &vers, max_size).map(|_| crate_data)
+    })
+    .await
+    .unwrap()
+    .map_err(|why| ApiError(why.to_string(), StatusCode::OK))?;
+
     let mut hash = Sha256::new();
     hash.update(&crate_data);
     let hash = hash.finalize();
     let hash = hex::encode(hash);
 
-    if models::Crate::exists_by_ident(&mut trans, &crate_json.name).await? {
-        if !models::CrateOwner::exists(&mut trans, &crate_json.name, &session.ident).await? {
+    if models::CrateVersion::exists(&mut trans, &crate_json.name, &crate_json.vers).await? {
+        return Err(ApiError(
+            registry::PublishError::VersionExists(crate_json.vers).to_string(),
+            StatusCode::OK,
+        ));
+    }
+
+    let existing = models::Crate::by_canonical_name(&mut trans, &crate_json.name).await?;
+    if let Some(existing) = existing {
+        if existing.name != crate_json.name {
+            return Err(ApiError(
+                format!("crate was previously named `{}`", existing.name),
+                StatusCode::OK,
+            ));
+        }
+        if !models::CrateOwner::exists_or_team_member(&mut trans, &crate_json.name, &session.ident)
+            .await?
+        {
             return Err(ApiError(
                 String::from("User is not a crate owner"),
                 StatusCode::OK,
             ));
         }
+        require_scope(
+            &session,
+            models::TokenScope::PublishUpdate,
+            &crate_json.name,
+        )?;
     } else {
+        require_scope(&session, models::TokenScope::PublishNew, &crate_json.name)?;
         models::Crate::new(&mut trans, &crate_json.name).await?;
         models::CrateOwner::new(&mut trans, &crate_json.name, &session.ident).await?;
     }
 
-    trans.commit().await?;
+    let is_newest = match models::Crate::by_name(&mut trans, &crate_json.name)
+        .await?
+        .and_then(|x| x.max_version)
+    {
+        Some(max_version) => match semver::Version::parse(&max_version) {
+            Ok(max_version) => version > max_version,
+            Err(_) => true,
+        },
+        None => true,
+    };
+
+    if is_newest {
+        models::Crate::set_newest_version(
+            &mut trans,
+            &crate_json.name,
+            &crate_json.vers,
+            crate_json.description.as_str(),
+        )
+        .await?;
+    }
+
+    let crate_version =
+        models::CrateVersion::new(&mut trans, &crate_json, &hash, Some(&session.ident)).await?;
+    models::DocBuild::queue(&mut trans, crate_version.id).await?;
+    models::AuditEvent::record(
+        &mut trans,
+        &audit_actor(&session, &request_id),
+        models::AuditAction::Publish,
+        &crate_json.name,
+        Some(&crate_json.vers),
+        json!({ "checksum": hash }),
+    )
+    .await?;
 
+    // only commit once the registry accepted the version, so rejected publishes leave no trace
     match registry::run_task(
         registry::Operation::Publish(registry::Package::from_pub(crate_json, hash), crate_data),
         sender,
@@ -146,10 +360,16 @@ async fn publish(
     .await
     .unwrap()
     {
-        registry::RegistryResponse::Publish(res) => res.unwrap(),
+        registry::RegistryResponse::Publish(res) => {
+            if let Err(why) = res {
+                return Err(ApiError(why.to_string(), StatusCode::OK));
+            }
+        }
         _ => unreachable!("o no"),
     };
 
+    trans.commit().await?;
+
     Ok(Json(
         json!({ "warnings": {"invalid_categories": [], "invalid_badges": [],"other": []} }),
     ))
@@ -160,19 +380,37 @@ async fn yank(
     sender: Extension<registry::SyncSender>,
     pool: Extension<PgPool>,
     session: models::UserSession,
+    request_id: RequestId,
 ) -> Result<Json<Value>, ApiError> {
+    require_scope(&session, models::TokenScope::Yank, &crate_name)?;
+
     let mut trans = pool.begin().await?;
-    if models::CrateOwner::exists(&mut trans, &crate_name, &session.ident).await? {
+    if models::CrateOwner::exists_or_team_member(&mut trans, &crate_name, &session.ident).await? {
+        models::CrateVersion::set_yanked(&mut trans, &crate_name, &version, true).await?;
+        models::AuditEvent::record(
+            &mut trans,
+            &audit_actor(&session, &request_id),
+            models::AuditAction::Yank,
+            &crate_name,
+            Some(&version),
+            json!({}),
+        )
+        .await?;
+
         match registry::run_task(registry::Operation::Yank(crate_name, version, true), sender)
             .await
             .unwrap()
         {
             registry::RegistryResponse::Yank(res) => match res {
-                Ok(_) => Ok(Json(json!({"ok": true}))),
+                Ok(_) => {
+                    trans.commit().await?;
+                    Ok(Json(json!({"ok": true})))
+                }
                 Err(registry::YankError::CrateNotFound) => Err(ApiError(
                     String::from("crate should exist but doesnt?"),
                     StatusCode::OK,
                 )),
+                Err(err) => Err(ApiError(err.to_string(), StatusCode::OK)),
             },
             _ => unreachable!("o no"),
         }
@@ -189,9 +427,23 @@ async fn unyank(
     sender: Extension<registry::SyncSender>,
     pool: Extension<PgPool>,
     session: models::UserSession,
+    request_id: RequestId,
 ) -> Result<Json<Value>, ApiError> {
+    require_scope(&session, models::TokenScope::Yank, &crate_name)?;
+
     let mut trans = pool.begin().await?;
-    if models::CrateOwner::exists(&mut trans, &crate_name, &session.ident).await? {
+    if models::CrateOwner::exists_or_team_member(&mut trans, &crate_name, &session.ident).await? {
+        models::CrateVersion::set_yanked(&mut trans, &crate_name, &version, false).await?;
+        models::AuditEvent::record(
+            &mut trans,
+            &audit_actor(&session, &request_id),
+            models::AuditAction::Unyank,
+            &crate_name,
+            Some(&version),
+            json!({}),
+        )
+        .await?;
+
         match registry::run_task(
             registry::Operation::Yank(crate_name, version, false),
             sender,
@@ -200,11 +452,15 @@ async fn unyank(
         .unwrap()
         {
             registry::RegistryResponse::Yank(res) => match res {
-                Ok(_) => Ok(Json(json!({"ok": true}))),
+                Ok(_) => {
+                    trans.commit().await?;
+                    Ok(Json(json!({"ok": true})))
+                }
                 Err(registry::YankError::CrateNotFound) => Err(ApiError(
                     String::from("crate should exist but doesnt?"),
                     StatusCode::OK,
                 )),
+                Err(err) => Err(ApiError(err.to_string(), StatusCode::OK)),
             },
             _ => unreachable!("o no"),
         }
@@ -216,6 +472,404 @@ async fn unyank(
     }
 }
 
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    per_page: Option<u32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchJson {
+    pub crates: Vec<SearchCrateJson>,
+    pub meta: SearchMetaJson,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchCrateJson {
+    pub name: String,
+    pub max_version: String,
+    pub description: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchMetaJson {
+    pub total: i64,
+}
+
+async fn search(
+    Query(params): Query<SearchParams>,
+    pool: Extension<PgPool>,
+) -> Result<Json<SearchJson>, ApiError> {
+    // same default and upper bound as crates.io
+    let per_page = params.per_page.unwrap_or(10).clamp(1, 100);
+
+    let mut trans = pool.begin().await?;
+    let crates = models::Crate::search(&mut trans, &params.q, per_page as i64).await?;
+    let total = models::Crate::search_count(&mut trans, &params.q).await?;
+
+    Ok(Json(SearchJson {
+        crates: crates
+            .into_iter()
+            .map(|x| SearchCrateJson {
+                name: x.name,
+                max_version: x.max_version.unwrap_or_default(),
+                description: x.description,
+            })
+            .collect(),
+        meta: SearchMetaJson { total },
+    }))
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrateInfoJson {
+    #[serde(rename = "crate")]
+    pub krate: CrateJson,
+    pub versions: Vec<VersionJson>,
+    pub owners: Vec<UserJson>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrateJson {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub max_version: String,
+    pub newest_version: String,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub keywords: Vec<String>,
+    pub categories: Vec<String>,
+    pub repository: Option<String>,
+    pub versions: Vec<i64>,
+    pub downloads: i64,
+    pub recent_downloads: i64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionJson {
+    pub id: i64,
+    #[serde(rename = "crate")]
+    pub krate: String,
+    pub num: String,
+    pub dl_path: String,
+    pub readme_path: String,
+    pub checksum: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub yanked: bool,
+    pub license: Option<String>,
+    pub rust_version: Option<String>,
+    pub features: Value,
+    pub published_by: Option<UserJson>,
+    pub downloads: i64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DependencyJson {
+    pub crate_id: String,
+    pub req: String,
+    pub optional: bool,
+    pub default_features: bool,
+    pub features: Vec<String>,
+    pub target: Option<String>,
+    pub kind: String,
+}
+
+impl From<&models::CrateVersion> for VersionJson {
+    fn from(version: &models::CrateVersion) -> Self {
+        Self {
+            id: version.id,
+            krate: version.crate_name.clone(),
+            num: version.version.clone(),
+            dl_path: format!(
+                "/api/v1/crates/{}/{}/download",
+                version.crate_name, version.version
+            ),
+            readme_path: format!(
+                "/api/v1/crates/{}/{}/readme",
+                version.crate_name, version.version
+            ),
+            checksum: version.checksum.clone(),
+            created_at: version.published_at.to_rfc3339(),
+            updated_at: version.published_at.to_rfc3339(),
+            yanked: version.yanked,
+            license: version.license.clone(),
+            rust_version: version.rust_version.clone(),
+            features: version.features.clone(),
+            published_by: version.published_by.as_ref().map(|x| UserJson {
+                id: 0,
+                login: x.clone(),
+                name: None,
+                kind: String::from("user"),
+            }),
+            downloads: 0,
+        }
+    }
+}
+
+impl From<registry::PublishDep> for DependencyJson {
+    fn from(dep: registry::PublishDep) -> Self {
+        Self {
+            crate_id: dep.name,
+            req: dep.version_req,
+            optional: dep.optional,
+            default_features: dep.default_features,
+            features: dep.features,
+            target: dep.target.as_str().map(String::from),
+            kind: dep.kind,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionDownloadJson {
+    pub version: i64,
+    pub downloads: i64,
+    pub date: String,
+}
+
+// crates.io calls everything within the last 90 days recent
+const RECENT_DOWNLOAD_DAYS: i64 = 90;
+
+pub(crate) fn version_json(
+    version: &models::CrateVersion,
+    downloads: &HashMap<i64, i64>,
+) -> VersionJson {
+    VersionJson {
+        downloads: downloads.get(&version.id).copied().unwrap_or_default(),
+        ..VersionJson::from(version)
+    }
+}
+
+pub(crate) async fn download_totals(
+    trans: &mut models::PgTransaction<'_>,
+    crate_name: &str,
+) -> Result<HashMap<i64, i64>, ApiError> {
+    Ok(models::VersionDownload::totals_by_crate(trans, crate_name)
+        .await?
+        .into_iter()
+        .map(|x| (x.version_id, x.downloads))
+        .collect())
+}
+
+// highest version first like crates.io does, anything that isn't semver goes last
+pub(crate) fn sort_versions(versions: &mut [models::CrateVersion]) {
+    versions.sort_by_cached_key(|x| std::cmp::Reverse(semver::Version::parse(&x.version).ok()));
+}
+
+async fn crate_info(
+    Path(crate_name): Path<String>,
+    pool: Extension<PgPool>,
+) -> Result<Json<CrateInfoJson>, ApiError> {
+    let mut trans = pool.begin().await?;
+    let krate = match models::Crate::by_name(&mut trans, &crate_name).await? {
+        Some(krate) => krate,
+        None => {
+            return Err(ApiError(
+                String::from("crate does not exist!"),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+    let owners = owner_logins(&mut trans, &crate_name).await?;
+    let mut versions = models::CrateVersion::all_by_crate(&mut trans, &crate_name).await?;
+    let downloads = download_totals(&mut trans, &crate_name).await?;
+    let recent_downloads = models::VersionDownload::all_by_crate(
+        &mut trans,
+        &crate_name,
+        (Utc::now() - Duration::days(RECENT_DOWNLOAD_DAYS))
+            .naive_utc()
+            .date(),
+    )
+    .await?;
+
+    let max_version = krate.max_version.unwrap_or_default();
+    let newest = versions.iter().max_by_key(|x| x.id);
+    let max = versions
+        .iter()
+        .find(|x| x.version == max_version)
+        .or(newest);
+
+    let krate_json = CrateJson {
+        id: krate.name.clone(),
+        name: krate.name,
+        description: krate.description,
+        max_version: max_version.clone(),
+        newest_version: newest.map(|x| x.version.clone()).unwrap_or(max_version),
+        created_at: versions
+            .iter()
+            .map(|x| x.published_at)
+            .min()
+            .map(|x| x.to_rfc3339()),
+        updated_at: newest.map(|x| x.published_at.to_rfc3339()),
+        keywords: max.map(|x| x.keywords.clone()).unwrap_or_default(),
+        categories: max.map(|x| x.categories.clone()).unwrap_or_default(),
+        repository: max.and_then(|x| x.repository.clone()),
+        versions: Vec::new(),
+        downloads: downloads.values().sum(),
+        recent_downloads: recent_downloads.iter().map(|x| x.downloads).sum(),
+    };
+
+    sort_versions(&mut versions);
+
+    Ok(Json(CrateInfoJson {
+        krate: CrateJson {
+            versions: versions.iter().map(|x| x.id).collect(),
+            ..krate_json
+        },
+        versions: versions
+            .iter()
+            .map(|x| version_json(x, &downloads))
+            .collect(),
+        owners: owners
+            .into_iter()
+            .enumerate()
+            .map(|(i, (login, kind))| UserJson {
+                id: i as u32,
+                login,
+                name: None,
+                kind: String::from(kind),
+            })
+            .collect(),
+    }))
+}
+
+async fn crate_versions(
+    Path(crate_name): Path<String>,
+    pool: Extension<PgPool>,
+) -> Result<Json<Value>, ApiError> {
+    let mut trans = pool.begin().await?;
+    if !models::Crate::exists_by_ident(&mut trans, &crate_name).await? {
+        return Err(ApiError(
+            String::from("crate does not exist!"),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let mut versions = models::CrateVersion::all_by_crate(&mut trans, &crate_name).await?;
+    let downloads = download_totals(&mut trans, &crate_name).await?;
+    sort_versions(&mut versions);
+    let versions: Vec<VersionJson> = versions
+        .iter()
+        .map(|x| version_json(x, &downloads))
+        .collect();
+
+    Ok(Json(json!({ "versions": versions })))
+}
+
+async fn crate_version(
+    Path((crate_name, version)): Path<(String, String)>,
+    pool: Extension<PgPool>,
+) -> Result<Json<Value>, ApiError> {
+    let mut trans = pool.begin().await?;
+    let version = match models::CrateVersion::by_version(&mut trans, &crate_name, &version).await? {
+        Some(version) => version,
+        None => {
+            return Err(ApiError(
+                String::from("version does not exist!"),
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+
+    let dependencies: Vec<DependencyJson> =
+        serde_json::from_value::<Vec<registry::PublishDep>>(version.dependencies.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .map(DependencyJson::from)
+            .collect();
+    let downloads = download_totals(&mut trans, &crate_name).await?;
+
+    Ok(Json(json!({
+        "version": version_json(&version, &downloads),
+        "dependencies": dependencies,
+    })))
+}
+
+async fn crate_version_readme(
+    Path((crate_name, version)): Path<(String, String)>,
+    pool: Extension<PgPool>,
+) -> Result<Html<String>, ApiError> {
+    let mut trans = pool.begin().await?;
+    match models::CrateVersion::readme(&mut trans, &crate_name, &version).await? {
+        Some(models::VersionReadme {
+            readme: Some(readme),
+            readme_file,
+            repository,
+        }) => Ok(Html(readme::render(
+            &readme,
+            readme_file.as_deref(),
+            repository.as_deref(),
+        ))),
+        _ => Err(ApiError(
+            String::from("this version has no readme!"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocBuildJson {
+    pub status: String,
+    pub docs_path: Option<String>,
+    pub queued_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub log: String,
+}
+
+async fn crate_version_docs(
+    Path((crate_name, version)): Path<(String, String)>,
+    pool: Extension<PgPool>,
+) -> Result<Json<DocBuildJson>, ApiError> {
+    let mut trans = pool.begin().await?;
+    match models::DocBuild::by_version(&mut trans, &crate_name, &version).await? {
+        Some(build) => Ok(Json(DocBuildJson {
+            docs_path: build
+                .doc_path
+                .map(|x| format!("/docs/{}/{}/{}", crate_name, version, x)),
+            status: build.status,
+            queued_at: build.queued_at.to_rfc3339(),
+            started_at: build.started_at.map(|x| x.to_rfc3339()),
+            finished_at: build.finished_at.map(|x| x.to_rfc3339()),
+            log: build.log,
+        })),
+        None => Err(ApiError(
+            String::from("no docs have been built for this version!"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+async fn crate_downloads(
+    Path(crate_name): Path<String>,
+    pool: Extension<PgPool>,
+) -> Result<Json<Value>, ApiError> {
+    let mut trans = pool.begin().await?;
+    if !models::Crate::exists_by_ident(&mut trans, &crate_name).await? {
+        return Err(ApiError(
+            String::from("crate does not exist!"),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let since = (Utc::now() - Duration::days(RECENT_DOWNLOAD_DAYS))
+        .naive_utc()
+        .date();
+    let version_downloads: Vec<VersionDownloadJson> =
+        models::VersionDownload::all_by_crate(&mut trans, &crate_name, since)
+            .await?
+            .into_iter()
+            .map(|x| VersionDownloadJson {
+                version: x.version_id,
+                downloads: x.downloads,
+                date: x.date.to_string(),
+            })
+            .collect();
+
+    Ok(Json(json!({ "version_downloads": version_downloads })))
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OwnersJson {
     pub users: Vec<UserJson>,
@@ -226,6 +880,27 @@ pub struct UserJson {
     pub id: u32,
     pub login: String,
     pub name: Option<String>,
+    // `user` or `team`, team logins start with `team:`
+    pub kind: String,
+}
+
+// Users first, then teams with their `team:` login
+pub(crate) async fn owner_logins(
+    trans: &mut models::PgTransaction<'_>,
+    crate_name: &str,
+) -> Result<Vec<(String, &'static str)>, ApiError> {
+    let users = models::CrateOwner::all_owners(trans, crate_name).await?;
+    let teams = models::CrateTeamOwner::all_owners(trans, crate_name).await?;
+
+    Ok(users
+        .into_iter()
+        .map(|x| (x.user_ident, "user"))
+        .chain(
+            teams
+                .into_iter()
+                .map(|x| (format!("{}{}", models::TEAM_PREFIX, x.team_name), "team")),
+        )
+        .collect())
 }
 
 async fn owners(
@@ -234,15 +909,16 @@ async fn owners(
     _session: models::UserSession,
 ) -> Result<Json<OwnersJson>, ApiError> {
     let mut trans = pool.begin().await?;
-    let owners = models::CrateOwner::all_owners(&mut trans, &crate_name).await?;
     let owners_json = OwnersJson {
-        users: owners
-            .iter()
+        users: owner_logins(&mut trans, &crate_name)
+            .await?
+            .into_iter()
             .enumerate()
-            .map(|(i, x)| UserJson {
+            .map(|(i, (login, kind))| UserJson {
                 id: i as u32,
-                login: x.user_ident.clone(),
+                login,
                 name: None,
+                kind: String::from(kind),
             })
             .collect(),
     };
@@ -257,12 +933,17 @@ pub struct OwnerList {
 
 async fn add_owners(
     Path(crate_name): Path<String>,
+    settings: Extension<Arc<settings::Settings>>,
     pool: Extension<PgPool>,
     session: models::UserSession,
+    request_id: RequestId,
     axum::extract::Json(to_add): axum::extract::Json<OwnerList>,
 ) -> Result<Json<Value>, ApiError> {
+    require_scope(&session, models::TokenScope::ChangeOwners, &crate_name)?;
+
     let mut trans = pool.begin().await?;
-    if !models::CrateOwner::exists(&mut trans, &crate_name, &session.ident).await? {
+    // members of an owning team look after the crate as much as its users do
+    if !models::CrateOwner::exists_or_team_member(&mut trans, &crate_name, &session.ident).await? {
         return Err(ApiError(
             String::from("You do not own this crate"),
             StatusCode::OK,
@@ -276,33 +957,88 @@ async fn add_owners(
         ));
     }
 
+    let expires_at = Utc::now() + Duration::days(settings.owner_invitation_days as i64);
+    let mut msg = Vec::new();
     for owner in to_add.users {
-        if !models::User::exists_by_ident(&mut trans, &owner).await? {
-            return Err(ApiError(
-                format!("The user {} does not exist", owner),
-                StatusCode::OK,
+        let action = if let Some(team) = owner.strip_prefix(models::TEAM_PREFIX) {
+            if !models::Team::exists(&mut trans, team).await? {
+                return Err(ApiError(
+                    format!("The team {} does not exist", team),
+                    StatusCode::OK,
+                ));
+            }
+            // otherwise anyone could push their crates onto a team
+            if !models::TeamMember::exists(&mut trans, team, &session.ident).await? {
+                return Err(ApiError(
+                    format!("You can only add teams you are a member of, not {}", team),
+                    StatusCode::OK,
+                ));
+            }
+
+            if models::CrateTeamOwner::exists(&mut trans, &crate_name, team).await? {
+                msg.push(format!("team {} already owns {}", team, crate_name));
+                continue;
+            }
+            models::CrateTeamOwner::new(&mut trans, &crate_name, team).await?;
+            msg.push(format!(
+                "team {} has been added as an owner of {}",
+                team, crate_name
             ));
-        }
+            models::AuditAction::AddOwner
+        } else {
+            if !models::User::exists_by_ident(&mut trans, &owner).await? {
+                return Err(ApiError(
+                    format!("The user {} does not exist", owner),
+                    StatusCode::OK,
+                ));
+            }
 
-        if !models::CrateOwner::exists(&mut trans, &crate_name, &owner).await? {
-            models::CrateOwner::new(&mut trans, &crate_name, &owner).await?;
-        }
+            if models::CrateOwner::exists(&mut trans, &crate_name, &owner).await? {
+                msg.push(format!("user {} already owns {}", owner, crate_name));
+                continue;
+            }
+            // nobody becomes responsible for a crate without agreeing to it
+            models::OwnerInvitation::new(
+                &mut trans,
+                &crate_name,
+                &owner,
+                &session.ident,
+                expires_at,
+            )
+            .await?;
+            msg.push(format!(
+                "user {} has been invited to be an owner of {}",
+                owner, crate_name
+            ));
+            models::AuditAction::InviteOwner
+        };
+
+        models::AuditEvent::record(
+            &mut trans,
+            &audit_actor(&session, &request_id),
+            action,
+            &crate_name,
+            None,
+            json!({ "owner": owner }),
+        )
+        .await?;
     }
     trans.commit().await?;
 
-    Ok(Json(
-        json!({"ok": true, "msg": "added owners successfully"}),
-    ))
+    Ok(Json(json!({"ok": true, "msg": msg.join(", ")})))
 }
 
 async fn remove_owners(
     Path(crate_name): Path<String>,
     pool: Extension<PgPool>,
     session: models::UserSession,
+    request_id: RequestId,
     axum::extract::Json(to_delete): axum::extract::Json<OwnerList>,
 ) -> Result<Json<Value>, ApiError> {
+    require_scope(&session, models::TokenScope::ChangeOwners, &crate_name)?;
+
     let mut trans = pool.begin().await?;
-    if !models::CrateOwner::exists(&mut trans, &crate_name, &session.ident).await? {
+    if !models::CrateOwner::exists_or_team_member(&mut trans, &crate_name, &session.ident).await? {
         return Err(ApiError(
             String::from("You do not own this crate"),
             StatusCode::OK,
@@ -316,18 +1052,59 @@ async fn remove_owners(
         ));
     }
 
+    let mut removed_any = false;
     for owner in to_delete.users {
-        if !models::User::exists_by_ident(&mut trans, &owner).await? {
-            return Err(ApiError(
-                format!("The user {} does not exist", owner),
-                StatusCode::OK,
-            ));
-        }
+        let removed = if let Some(team) = owner.strip_prefix(models::TEAM_PREFIX) {
+            if !models::Team::exists(&mut trans, team).await? {
+                return Err(ApiError(
+                    format!("The team {} does not exist", team),
+                    StatusCode::OK,
+                ));
+            }
+
+            let removed = models::CrateTeamOwner::exists(&mut trans, &crate_name, team).await?;
+            if removed {
+                models::CrateTeamOwner::delete(&mut trans, &crate_name, team).await?;
+            }
+            removed
+        } else {
+            if !models::User::exists_by_ident(&mut trans, &owner).await? {
+                return Err(ApiError(
+                    format!("The user {} does not exist", owner),
+                    StatusCode::OK,
+                ));
+            }
+
+            // removing someone who was only invited takes back the invitation
+            models::OwnerInvitation::delete(&mut trans, &crate_name, &owner).await?;
+            let removed = models::CrateOwner::exists(&mut trans, &crate_name, &owner).await?;
+            if removed {
+                models::CrateOwner::delete(&mut trans, &crate_name, &owner).await?;
+                models::OwnerInvitation::delete_by_inviter(&mut trans, &crate_name, &owner).await?;
+            }
+            removed
+        };
 
-        if models::CrateOwner::exists(&mut trans, &crate_name, &owner).await? {
-            models::CrateOwner::delete(&mut trans, &crate_name, &owner).await?;
+        if removed {
+            removed_any = true;
+            models::AuditEvent::record(
+                &mut trans,
+                &audit_actor(&session, &request_id),
+                models::AuditAction::RemoveOwner,
+                &crate_name,
+                None,
+                json!({ "owner": owner }),
+            )
+            .await?;
         }
     }
+
+    if removed_any && !models::CrateOwner::any_exists(&mut trans, &crate_name).await? {
+        return Err(ApiError(
+            String::from("A crate needs at least one user or team owner"),
+            StatusCode::OK,
+        ));
+    }
     trans.commit().await?;
 
     Ok(Json(
@@ -335,54 +1112,1166 @@ async fn remove_owners(
     ))
 }
 
-async fn dl(
-    Path(hash): Path<String>,
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OwnerInvitationJson {
+    pub id: i64,
+    pub crate_name: String,
+    pub invited_by_username: String,
+    pub created_at: String,
+    pub expires_at: String,
+}
+
+impl From<models::OwnerInvitation> for OwnerInvitationJson {
+    fn from(invitation: models::OwnerInvitation) -> Self {
+        Self {
+            id: invitation.id,
+            crate_name: invitation.crate_name,
+            invited_by_username: invitation.inviter_ident,
+            created_at: invitation.created_at.to_rfc3339(),
+            expires_at: invitation.expires_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvitationResponse {
+    crate_owner_invite: InvitationAnswer,
+}
+
+#[derive(Deserialize)]
+pub struct InvitationAnswer {
+    accepted: bool,
+}
+
+async fn owner_invitations(
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    let mut trans = pool.begin().await?;
+    let invitations: Vec<OwnerInvitationJson> =
+        models::OwnerInvitation::all_by_invitee(&mut trans, &session.ident)
+            .await?
+            .into_iter()
+            .map(OwnerInvitationJson::from)
+            .collect();
+
+    Ok(Json(json!({ "crate_owner_invitations": invitations })))
+}
+
+async fn answer_owner_invitation(
+    Path(crate_name): Path<String>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+    request_id: RequestId,
+    axum::extract::Json(answer): axum::extract::Json<InvitationResponse>,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    let accepted = answer.crate_owner_invite.accepted;
+    let mut trans = pool.begin().await?;
+    let outcome =
+        respond_to_invitation(&mut trans, &session, &request_id, &crate_name, accepted).await?;
+    trans.commit().await?;
+
+    if let InvitationOutcome::Stale(err) = outcome {
+        return Err(err);
+    }
+
+    Ok(Json(
+        json!({ "crate_owner_invite": { "crate_name": crate_name, "accepted": accepted } }),
+    ))
+}
+
+pub(crate) enum InvitationOutcome {
+    Accepted,
+    Declined,
+    // the invitation got dropped without making anyone an owner, the error says why
+    Stale(ApiError),
+}
+
+// Accepting makes the invitee an owner, either way the invitation is gone afterwards
+// once the transaction is committed
+pub(crate) async fn respond_to_invitation(
+    trans: &mut models::PgTransaction<'_>,
+    session: &models::UserSession,
+    request_id: &RequestId,
+    crate_name: &str,
+    accepted: bool,
+) -> Result<InvitationOutcome, ApiError> {
+    let invitation =
+        match models::OwnerInvitation::pending(trans, crate_name, &session.ident).await? {
+            Some(invitation) => invitation,
+            None => {
+                return Err(ApiError(
+                    format!("You have no pending invitation for {}", crate_name),
+                    StatusCode::OK,
+                ))
+            }
+        };
+
+    // e.g. the inviter left the team that owns the crate
+    let stale = accepted
+        && !models::CrateOwner::exists_or_team_member(trans, crate_name, &invitation.inviter_ident)
+            .await?;
+    models::OwnerInvitation::delete(trans, crate_name, &session.ident).await?;
+
+    if stale {
+        return Ok(InvitationOutcome::Stale(ApiError(
+            format!(
+                "{} is no longer an owner of {}, so the invitation isn't valid anymore",
+                invitation.inviter_ident, crate_name
+            ),
+            StatusCode::OK,
+        )));
+    }
+    if !accepted {
+        return Ok(InvitationOutcome::Declined);
+    }
+
+    if !models::CrateOwner::exists(trans, crate_name, &session.ident).await? {
+        models::CrateOwner::new(trans, crate_name, &session.ident).await?;
+        models::AuditEvent::record(
+            trans,
+            &audit_actor(session, request_id),
+            models::AuditAction::AddOwner,
+            crate_name,
+            None,
+            json!({ "owner": session.ident, "invited_by": invitation.inviter_ident }),
+        )
+        .await?;
+    }
+
+    Ok(InvitationOutcome::Accepted)
+}
+
+#[derive(Deserialize)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub crate_patterns: Option<Vec<String>>,
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiTokenJson {
+    pub id: i64,
+    pub name: String,
+    pub token_prefix: String,
+    pub scopes: Option<Vec<String>>,
+    pub crate_patterns: Option<Vec<String>>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    // only returned once, right after creating the token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl From<models::UserSession> for ApiTokenJson {
+    fn from(session: models::UserSession) -> Self {
+        Self {
+            id: session.id,
+            name: session.name,
+            token_prefix: session.token_prefix,
+            scopes: session.scopes,
+            crate_patterns: session.crate_patterns,
+            created_at: session.created_at.to_rfc3339(),
+            expires_at: session.expires_at.map(|x| x.to_rfc3339()),
+            last_used_at: session.last_used_at.map(|x| x.to_rfc3339()),
+            token: None,
+        }
+    }
+}
+
+const DEFAULT_TOKEN_EXPIRY_DAYS: u32 = 90;
+const MAX_TOKEN_EXPIRY_DAYS: u32 = 365;
+
+async fn tokens(
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    let mut trans = pool.begin().await?;
+    let tokens: Vec<ApiTokenJson> = models::UserSession::all_by_ident(&mut trans, &session.ident)
+        .await?
+        .into_iter()
+        .map(ApiTokenJson::from)
+        .collect();
+
+    Ok(Json(json!({ "api_tokens": tokens })))
+}
+
+async fn new_token(
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+    axum::extract::Json(new_token): axum::extract::Json<NewApiToken>,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    let mut trans = pool.begin().await?;
+    let (token, plaintext) = create_api_token(&mut trans, &session.ident, &new_token).await?;
+    trans.commit().await?;
+
+    let mut token_json = ApiTokenJson::from(token);
+    token_json.token = Some(plaintext);
+
+    Ok(Json(json!({ "api_token": token_json })))
+}
+
+// Shared with the token page of the web ui
+pub(crate) async fn create_api_token(
+    trans: &mut models::PgTransaction<'_>,
+    ident: &str,
+    new_token: &NewApiToken,
+) -> Result<(models::UserSession, String), ApiError> {
+    if new_token.name.trim().is_empty() {
+        return Err(ApiError(
+            String::from("Token name cannot be empty"),
+            StatusCode::OK,
+        ));
+    }
+
+    if new_token.scopes.is_empty() {
+        return Err(ApiError(
+            String::from("Api tokens need at least one scope"),
+            StatusCode::OK,
+        ));
+    }
+
+    let scopes = new_token
+        .scopes
+        .iter()
+        .map(|x| {
+            models::TokenScope::parse(x)
+                .ok_or_else(|| ApiError(format!("Unknown token scope {}", x), StatusCode::OK))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(crate_patterns) = &new_token.crate_patterns {
+        if crate_patterns.is_empty() {
+            return Err(ApiError(
+                String::from("Leave out crate_patterns to allow all crates"),
+                StatusCode::OK,
+            ));
+        }
+
+        for pattern in crate_patterns {
+            if let Err(why) = registry::validate_crate_pattern(pattern) {
+                return Err(ApiError(
+                    format!("Invalid crate pattern {}: {}", pattern, why),
+                    StatusCode::OK,
+                ));
+            }
+        }
+    }
+
+    let expires_in_days = new_token
+        .expires_in_days
+        .unwrap_or(DEFAULT_TOKEN_EXPIRY_DAYS);
+    if expires_in_days == 0 || expires_in_days > MAX_TOKEN_EXPIRY_DAYS {
+        return Err(ApiError(
+            format!(
+                "Tokens have to expire within 1 to {} days",
+                MAX_TOKEN_EXPIRY_DAYS
+            ),
+            StatusCode::OK,
+        ));
+    }
+
+    if models::UserSession::exists_by_name(trans, ident, &new_token.name).await? {
+        return Err(ApiError(
+            format!("You already have a token named {}", new_token.name),
+            StatusCode::OK,
+        ));
+    }
+
+    Ok(models::UserSession::new_api_token(
+        trans,
+        ident,
+        &new_token.name,
+        Some(&scopes),
+        new_token.crate_patterns.as_deref(),
+        Utc::now() + Duration::days(expires_in_days as i64),
+    )
+    .await?)
+}
+
+async fn revoke_token(
+    Path(id): Path<i64>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    let mut trans = pool.begin().await?;
+    if !models::UserSession::delete_by_id(&mut trans, &session.ident, id).await? {
+        return Err(ApiError(
+            String::from("token does not exist!"),
+            StatusCode::OK,
+        ));
+    }
+    trans.commit().await?;
+
+    Ok(Json(
+        json!({"ok": true, "msg": "revoked token successfully"}),
+    ))
+}
+
+fn mirror_snapshot(settings: &settings::Settings) -> Result<mirror::Snapshot, ApiError> {
+    match (&settings.mirror_index_path, &settings.mirror_crates_path) {
+        (Some(index_path), Some(crates_path)) => Ok(mirror::Snapshot::new(index_path, crates_path)),
+        _ => Err(ApiError(
+            String::from("No mirror snapshot is configured"),
+            StatusCode::OK,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MirrorImportList {
+    crates: Vec<MirrorImportCrate>,
+}
+
+#[derive(Deserialize)]
+pub struct MirrorImportCrate {
+    name: String,
+    versions: Option<Vec<String>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorReviewJson {
+    pub id: i64,
+    pub crate_name: String,
+    pub version: String,
+    pub base_version: Option<String>,
+    pub status: String,
+    pub proposed_by: String,
+    pub reviewed_by: Option<String>,
+    pub created_at: String,
+    pub reviewed_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<MirrorReviewFileJson>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorReviewFileJson {
+    pub path: String,
+    pub change: String,
+    pub diff: String,
+}
+
+impl From<models::MirrorReview> for MirrorReviewJson {
+    fn from(review: models::MirrorReview) -> Self {
+        Self {
+            id: review.id,
+            crate_name: review.crate_name,
+            version: review.version,
+            base_version: review.base_version,
+            status: review.status,
+            proposed_by: review.proposed_by,
+            reviewed_by: review.reviewed_by,
+            created_at: review.created_at.to_rfc3339(),
+            reviewed_at: review.reviewed_at.map(|x| x.to_rfc3339()),
+            files: None,
+        }
+    }
+}
+
+async fn mirror_import(
     settings: Extension<Arc<settings::Settings>>,
-) -> impl IntoResponse {
-    let mut file_path = PathBuf::from(&settings.storage_path);
-    if hash.len() != 64 || hash.contains('.') || hash.contains('/') {
-        return Err((StatusCode::NOT_FOUND, "File not found!"));
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+    axum::extract::Json(to_import): axum::extract::Json<MirrorImportList>,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    let snapshot = mirror_snapshot(&settings)?;
+    let mut reviews: Vec<MirrorReviewJson> = Vec::new();
+
+    for krate in to_import.crates {
+        let proposed = mirror::propose(
+            &snapshot,
+            &krate.name,
+            krate.versions.as_deref(),
+            &session.ident,
+            settings.max_unpacked_crate_size,
+            &pool,
+        )
+        .await
+        .map_err(|why| ApiError(why.to_string(), StatusCode::OK))?;
+
+        reviews.extend(proposed.into_iter().map(MirrorReviewJson::from));
+    }
+
+    Ok(Json(json!({"ok": true, "reviews": reviews})))
+}
+
+#[derive(Deserialize)]
+pub struct MirrorReviewFilter {
+    status: Option<String>,
+}
+
+async fn mirror_reviews(
+    Query(filter): Query<MirrorReviewFilter>,
+    pool: Extension<PgPool>,
+    _session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    let status = match filter.status.as_deref() {
+        None | Some("pending") => models::ReviewStatus::Pending,
+        Some("approved") => models::ReviewStatus::Approved,
+        Some("rejected") => models::ReviewStatus::Rejected,
+        Some(other) => {
+            return Err(ApiError(
+                format!("Unknown review status {}", other),
+                StatusCode::OK,
+            ))
+        }
+    };
+
+    let mut trans = pool.begin().await?;
+    let reviews: Vec<MirrorReviewJson> = models::MirrorReview::all_by_status(&mut trans, status)
+        .await?
+        .into_iter()
+        .map(MirrorReviewJson::from)
+        .collect();
+
+    Ok(Json(json!({ "reviews": reviews })))
+}
+
+async fn mirror_review(
+    Path(id): Path<i64>,
+    pool: Extension<PgPool>,
+    _session: models::UserSession,
+) -> Result<Json<MirrorReviewJson>, ApiError> {
+    let mut trans = pool.begin().await?;
+    let review = match models::MirrorReview::by_id(&mut trans, id).await? {
+        Some(review) => review,
+        None => {
+            return Err(ApiError(
+                String::from("review does not exist!"),
+                StatusCode::OK,
+            ))
+        }
+    };
+    let files = models::MirrorReviewFile::all_by_review(&mut trans, id).await?;
+
+    let mut review_json = MirrorReviewJson::from(review);
+    review_json.files = Some(
+        files
+            .into_iter()
+            .map(|x| MirrorReviewFileJson {
+                path: x.path,
+                change: x.change,
+                diff: x.diff,
+            })
+            .collect(),
+    );
+
+    Ok(Json(review_json))
+}
+
+async fn approve_mirror_review(
+    Path(id): Path<i64>,
+    sender: Extension<registry::SyncSender>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+    request_id: RequestId,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    if !settings.is_admin(&session.ident) {
+        return Err(ApiError(
+            String::from("Only registry admins can review mirrored crates"),
+            StatusCode::OK,
+        ));
+    }
+
+    let snapshot = mirror_snapshot(&settings)?;
+
+    let mut trans = pool.begin().await?;
+    let review = match models::MirrorReview::by_id(&mut trans, id).await? {
+        Some(review) => review,
+        None => {
+            return Err(ApiError(
+                String::from("review does not exist!"),
+                StatusCode::OK,
+            ))
+        }
+    };
+    trans.commit().await?;
+
+    let reviewer = audit_actor(&session, &request_id);
+    mirror::approve(&snapshot, &review, &reviewer, &sender, &pool)
+        .await
+        .map_err(|why| ApiError(why.to_string(), StatusCode::OK))?;
+
+    Ok(Json(
+        json!({"ok": true, "msg": "approved and published crate successfully"}),
+    ))
+}
+
+async fn reject_mirror_review(
+    Path(id): Path<i64>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    if !settings.is_admin(&session.ident) {
+        return Err(ApiError(
+            String::from("Only registry admins can review mirrored crates"),
+            StatusCode::OK,
+        ));
+    }
+
+    let mut trans = pool.begin().await?;
+    match models::MirrorReview::by_id_for_update(&mut trans, id).await? {
+        Some(review) if review.status == models::ReviewStatus::Pending.as_str() => {}
+        Some(_) => {
+            return Err(ApiError(
+                String::from("review is not pending anymore"),
+                StatusCode::OK,
+            ))
+        }
+        None => {
+            return Err(ApiError(
+                String::from("review does not exist!"),
+                StatusCode::OK,
+            ))
+        }
+    }
+
+    models::MirrorReview::set_status(
+        &mut trans,
+        id,
+        models::ReviewStatus::Rejected,
+        &session.ident,
+    )
+    .await?;
+    trans.commit().await?;
+
+    Ok(Json(
+        json!({"ok": true, "msg": "rejected crate successfully"}),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct AuditParams {
+    #[serde(rename = "crate")]
+    crate_name: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    before_id: Option<i64>,
+    per_page: Option<u32>,
+}
+
+impl AuditParams {
+    fn filter(&self) -> Result<models::AuditFilter, ApiError> {
+        let action = match self.action.as_deref() {
+            Some(action) => Some(models::AuditAction::parse(action).ok_or_else(|| {
+                ApiError(format!("Unknown audit action {}", action), StatusCode::OK)
+            })?),
+            None => None,
+        };
+
+        Ok(models::AuditFilter {
+            crate_name: self.crate_name.clone(),
+            actor: self.actor.clone(),
+            action,
+            since: parse_audit_time(self.since.as_deref())?,
+            until: parse_audit_time(self.until.as_deref())?,
+        })
+    }
+}
+
+fn parse_audit_time(
+    time: Option<&str>,
+) -> Result<Option<sqlx::types::chrono::DateTime<Utc>>, ApiError> {
+    time.map(|x| {
+        sqlx::types::chrono::DateTime::parse_from_rfc3339(x)
+            .map(|x| x.with_timezone(&Utc))
+            .map_err(|_| ApiError(format!("Invalid RFC 3339 time {}", x), StatusCode::OK))
+    })
+    .transpose()
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEventJson {
+    pub id: i64,
+    pub action: String,
+    pub actor: String,
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub version: Option<String>,
+    pub token_id: Option<i64>,
+    pub token_name: Option<String>,
+    pub request_id: Option<String>,
+    pub details: Value,
+    pub created_at: String,
+}
+
+impl From<models::AuditEvent> for AuditEventJson {
+    fn from(event: models::AuditEvent) -> Self {
+        Self {
+            id: event.id,
+            action: event.action,
+            actor: event.actor,
+            crate_name: event.crate_name,
+            version: event.version,
+            token_id: event.token_id,
+            token_name: event.token_name,
+            request_id: event.request_id,
+            details: event.details,
+            created_at: event.created_at.to_rfc3339(),
+        }
+    }
+}
+
+const DEFAULT_AUDIT_PAGE: u32 = 100;
+const MAX_AUDIT_PAGE: u32 = 1000;
+
+async fn audit_events(
+    Query(params): Query<AuditParams>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    require_full_access(&session)?;
+
+    if !settings.is_admin(&session.ident) {
+        return Err(ApiError(
+            String::from("Only registry admins can read the audit log"),
+            StatusCode::OK,
+        ));
+    }
+
+    let filter = params.filter()?;
+    let per_page = params
+        .per_page
+        .unwrap_or(DEFAULT_AUDIT_PAGE)
+        .clamp(1, MAX_AUDIT_PAGE);
+
+    let mut trans = pool.begin().await?;
+    let events: Vec<AuditEventJson> =
+        models::AuditEvent::search(&mut trans, &filter, params.before_id, per_page as i64)
+            .await?
+            .into_iter()
+            .map(AuditEventJson::from)
+            .collect();
+
+    Ok(Json(json!({ "events": events })))
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    pub url: String,
+    // audit actions like `publish` or `yank`, all of them when missing
+    pub events: Option<Vec<String>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookJson {
+    pub id: i64,
+    #[serde(rename = "crate")]
+    pub crate_name: Option<String>,
+    pub url: String,
+    pub events: Option<Vec<String>>,
+    pub created_by: String,
+    pub created_at: String,
+    // only returned once, right after creating the webhook
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<models::Webhook> for WebhookJson {
+    fn from(webhook: models::Webhook) -> Self {
+        Self {
+            id: webhook.id,
+            crate_name: webhook.crate_name,
+            url: webhook.url,
+            events: webhook.events,
+            created_by: webhook.created_by,
+            created_at: webhook.created_at.to_rfc3339(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookDeliveryJson {
+    pub id: i64,
+    pub event: String,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: String,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+}
+
+impl From<models::WebhookDelivery> for WebhookDeliveryJson {
+    fn from(delivery: models::WebhookDelivery) -> Self {
+        Self {
+            id: delivery.id,
+            event: delivery.event,
+            status: delivery.status,
+            attempts: delivery.attempts,
+            next_attempt_at: delivery.next_attempt_at.to_rfc3339(),
+            last_error: delivery.last_error,
+            created_at: delivery.created_at.to_rfc3339(),
+            delivered_at: delivery.delivered_at.map(|x| x.to_rfc3339()),
+        }
     }
+}
+
+const WEBHOOK_DELIVERIES_SHOWN: i64 = 50;
 
-    file_path.push(hash);
-    file_path.set_extension("crate");
+// crate owners and their teams manage the webhooks of a crate, admins the registry-wide ones
+async fn require_webhook_access(
+    trans: &mut models::PgTransaction<'_>,
+    session: &models::UserSession,
+    settings: &settings::Settings,
+    crate_name: Option<&str>,
+) -> Result<(), ApiError> {
+    require_full_access(session)?;
 
-    let file = match tokio::fs::File::open(file_path).await {
-        Ok(file) => file,
-        Err(_) => return Err((StatusCode::NOT_FOUND, "File not found!")),
+    let allowed = match crate_name {
+        Some(crate_name) => {
+            models::CrateOwner::exists_or_team_member(trans, crate_name, &session.ident).await?
+        }
+        None => settings.is_admin(&session.ident),
     };
+    if allowed {
+        Ok(())
+    } else {
+        Err(ApiError(
+            String::from("You can't manage these webhooks"),
+            StatusCode::OK,
+        ))
+    }
+}
+
+pub(crate) async fn create_webhook(
+    trans: &mut models::PgTransaction<'_>,
+    crate_name: Option<&str>,
+    created_by: &str,
+    new_webhook: &NewWebhook,
+    allowed_hosts: &[String],
+) -> Result<WebhookJson, ApiError> {
+    if let Err(why) = webhooks::check_url(&new_webhook.url, allowed_hosts).await {
+        return Err(ApiError(
+            format!("Invalid webhook url: {}", why),
+            StatusCode::OK,
+        ));
+    }
+    if let Some(events) = &new_webhook.events {
+        if events.is_empty() {
+            return Err(ApiError(
+                String::from("Webhooks need at least one event"),
+                StatusCode::OK,
+            ));
+        }
+        if let Some(event) = events
+            .iter()
+            .find(|x| models::AuditAction::parse(x).is_none())
+        {
+            return Err(ApiError(
+                format!("Unknown webhook event {}", event),
+                StatusCode::OK,
+            ));
+        }
+    }
+
+    let secret: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let webhook = models::Webhook::new(
+        trans,
+        crate_name,
+        &new_webhook.url,
+        &secret,
+        new_webhook.events.as_deref(),
+        created_by,
+    )
+    .await?;
+
+    Ok(WebhookJson {
+        secret: Some(secret),
+        ..WebhookJson::from(webhook)
+    })
+}
+
+async fn list_webhooks(
+    crate_name: Option<&str>,
+    settings: &settings::Settings,
+    pool: &PgPool,
+    session: &models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    let mut trans = pool.begin().await?;
+    require_webhook_access(&mut trans, session, settings, crate_name).await?;
+
+    let webhooks: Vec<WebhookJson> = models::Webhook::all_by_crate(&mut trans, crate_name)
+        .await?
+        .into_iter()
+        .map(WebhookJson::from)
+        .collect();
+
+    Ok(Json(json!({ "webhooks": webhooks })))
+}
+
+async fn add_webhook(
+    crate_name: Option<&str>,
+    settings: &settings::Settings,
+    pool: &PgPool,
+    session: &models::UserSession,
+    new_webhook: &NewWebhook,
+) -> Result<Json<WebhookJson>, ApiError> {
+    let mut trans = pool.begin().await?;
+    require_webhook_access(&mut trans, session, settings, crate_name).await?;
+
+    let webhook = create_webhook(
+        &mut trans,
+        crate_name,
+        &session.ident,
+        new_webhook,
+        &settings.webhook_allowed_hosts,
+    )
+    .await?;
+    trans.commit().await?;
+
+    Ok(Json(webhook))
+}
+
+async fn remove_webhook(
+    crate_name: Option<&str>,
+    id: i64,
+    settings: &settings::Settings,
+    pool: &PgPool,
+    session: &models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    let mut trans = pool.begin().await?;
+    require_webhook_access(&mut trans, session, settings, crate_name).await?;
+
+    if !models::Webhook::delete(&mut trans, id, crate_name).await? {
+        return Err(ApiError(
+            String::from("webhook does not exist!"),
+            StatusCode::OK,
+        ));
+    }
+    trans.commit().await?;
+
+    Ok(Json(
+        json!({"ok": true, "msg": "deleted webhook successfully"}),
+    ))
+}
+
+async fn list_webhook_deliveries(
+    crate_name: Option<&str>,
+    id: i64,
+    settings: &settings::Settings,
+    pool: &PgPool,
+    session: &models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    let mut trans = pool.begin().await?;
+    require_webhook_access(&mut trans, session, settings, crate_name).await?;
+
+    if models::Webhook::by_id(&mut trans, id, crate_name)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError(
+            String::from("webhook does not exist!"),
+            StatusCode::OK,
+        ));
+    }
+    let deliveries: Vec<WebhookDeliveryJson> =
+        models::WebhookDelivery::all_by_webhook(&mut trans, id, WEBHOOK_DELIVERIES_SHOWN)
+            .await?
+            .into_iter()
+            .map(WebhookDeliveryJson::from)
+            .collect();
+
+    Ok(Json(json!({ "deliveries": deliveries })))
+}
+
+async fn crate_webhooks(
+    Path(crate_name): Path<String>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    list_webhooks(Some(&crate_name), &settings, &pool, &session).await
+}
+
+async fn new_crate_webhook(
+    Path(crate_name): Path<String>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+    axum::extract::Json(new_webhook): axum::extract::Json<NewWebhook>,
+) -> Result<Json<WebhookJson>, ApiError> {
+    add_webhook(Some(&crate_name), &settings, &pool, &session, &new_webhook).await
+}
+
+async fn delete_crate_webhook(
+    Path((crate_name, id)): Path<(String, i64)>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    remove_webhook(Some(&crate_name), id, &settings, &pool, &session).await
+}
+
+async fn crate_webhook_deliveries(
+    Path((crate_name, id)): Path<(String, i64)>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    list_webhook_deliveries(Some(&crate_name), id, &settings, &pool, &session).await
+}
+
+async fn registry_webhooks(
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    list_webhooks(None, &settings, &pool, &session).await
+}
+
+async fn new_registry_webhook(
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+    axum::extract::Json(new_webhook): axum::extract::Json<NewWebhook>,
+) -> Result<Json<WebhookJson>, ApiError> {
+    add_webhook(None, &settings, &pool, &session, &new_webhook).await
+}
+
+async fn delete_registry_webhook(
+    Path(id): Path<i64>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    remove_webhook(None, id, &settings, &pool, &session).await
+}
+
+async fn registry_webhook_deliveries(
+    Path(id): Path<i64>,
+    settings: Extension<Arc<settings::Settings>>,
+    pool: Extension<PgPool>,
+    session: models::UserSession,
+) -> Result<Json<Value>, ApiError> {
+    list_webhook_deliveries(None, id, &settings, &pool, &session).await
+}
+
+// What the `dl` url in the index config points to
+async fn download(
+    Path((crate_name, version)): Path<(String, String)>,
+    settings: Extension<Arc<settings::Settings>>,
+    storage: Extension<Arc<dyn storage::Storage>>,
+    downloads: Extension<downloads::DownloadCounter>,
+) -> Response {
+    if registry::validate_package_name(&crate_name).is_err() {
+        return (StatusCode::NOT_FOUND, "File not found!").into_response();
+    }
+
+    // the index has the checksum, so serving the file doesn't wait on the database
+    let index_path = registry::get_package_git_path(&settings.repo_path, &crate_name);
+    let pkg = tokio::fs::read_to_string(index_path)
+        .await
+        .ok()
+        .and_then(|index| {
+            index
+                .lines()
+                .filter_map(|x| serde_json::from_str::<registry::Package>(x).ok())
+                .find(|x| x.vers == version)
+        });
 
-    let stream = ReaderStream::new(file);
-    let body = StreamBody::new(stream);
+    match pkg {
+        Some(pkg) => {
+            serve_crate_file(
+                &pkg.cksum,
+                &storage,
+                &downloads,
+                downloads::Download::Version(pkg.name, pkg.vers),
+            )
+            .await
+        }
+        None => (StatusCode::NOT_FOUND, "File not found!").into_response(),
+    }
+}
+
+// Registries set up before `download` existed point cargo here
+async fn dl(
+    Path(hash): Path<String>,
+    storage: Extension<Arc<dyn storage::Storage>>,
+    downloads: Extension<downloads::DownloadCounter>,
+) -> Response {
+    if hash.len() != 64 || hash.contains('.') || hash.contains('/') {
+        return (StatusCode::NOT_FOUND, "File not found!").into_response();
+    }
+
+    serve_crate_file(
+        &hash,
+        &storage,
+        &downloads,
+        downloads::Download::Checksum(hash.clone()),
+    )
+    .await
+}
 
+async fn serve_crate_file(
+    hash: &str,
+    storage: &Arc<dyn storage::Storage>,
+    downloads: &downloads::DownloadCounter,
+    counted_as: downloads::Download,
+) -> Response {
     let headers = Headers([
         (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
         (header::CONTENT_DISPOSITION, "attachment; filename=\"\""),
     ]);
 
-    Ok((headers, body))
+    let download = storage.get(hash).await;
+    if let Ok(Some(_)) = download {
+        downloads.record(counted_as);
+    }
+
+    match download {
+        Ok(Some(storage::CrateDownload::File(file))) => {
+            if let Ok(meta) = file.metadata().await {
+                metrics::metrics().record_download(meta.len());
+            }
+            (headers, StreamBody::new(ReaderStream::new(file))).into_response()
+        }
+        Ok(Some(storage::CrateDownload::Bytes(bytes))) => {
+            metrics::metrics().record_download(bytes.len() as u64);
+            (headers, bytes).into_response()
+        }
+        Ok(Some(storage::CrateDownload::Redirect(url))) => (
+            StatusCode::TEMPORARY_REDIRECT,
+            Headers([(header::LOCATION, url)]),
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "File not found!").into_response(),
+        Err(err) => {
+            tracing::error!("Failed to read crate file: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Storage error").into_response()
+        }
+    }
 }
 
 fn build_router(
     sender: registry::SyncSender,
+    storage: Arc<dyn storage::Storage>,
+    downloads: downloads::DownloadCounter,
     settings: Arc<settings::Settings>,
     pool: PgPool,
     openid_client: openid_client::Client,
 ) -> Router {
+    let limiters = Arc::new(ratelimit::RateLimiters::from_settings(
+        &settings.rate_limits,
+    ));
+    let login_limit =
+        ratelimit::IpRateLimitLayer::new(limiters.login.clone(), limiters.trust_forwarded_for);
+    let download_limit =
+        ratelimit::IpRateLimitLayer::new(limiters.downloads.clone(), limiters.trust_forwarded_for);
+
     Router::new()
-        .route("/me", get(openid::me))
-        .route("/callback", get(openid_client::axum::code_callback::<()>))
+        .route("/me", get(openid::me).layer(login_limit.clone()))
+        .route(
+            "/callback",
+            get(openid_client::axum::code_callback::<()>).layer(login_limit),
+        )
+        .route("/api/v1/crates", get(search))
         .route("/api/v1/crates/new", put(publish))
+        .route("/api/v1/crates/:crate_name", get(crate_info))
+        .route("/api/v1/crates/:crate_name/versions", get(crate_versions))
+        .route("/api/v1/crates/:crate_name/downloads", get(crate_downloads))
+        .route("/api/v1/crates/:crate_name/:version", get(crate_version))
+        .route(
+            "/api/v1/crates/:crate_name/:version/readme",
+            get(crate_version_readme),
+        )
+        .route(
+            "/api/v1/crates/:crate_name/:version/docs",
+            get(crate_version_docs),
+        )
         .route("/api/v1/crates/:crate_name/:version/yank", delete(yank))
         .route("/api/v1/crates/:crate_name/:version/unyank", put(unyank))
-        .route("/api/v1/dl/:hash", get(dl))
+        .route(
+            "/api/v1/crates/:crate_name/:version/download",
+            get(download).layer(download_limit.clone()),
+        )
+        .route("/api/v1/dl/:hash", get(dl).layer(download_limit))
+        .route("/api/v1/me/tokens", get(tokens).put(new_token))
+        .route("/api/v1/me/tokens/:id", delete(revoke_token))
+        .route("/api/v1/me/crate_owner_invitations", get(owner_invitations))
+        .route(
+            "/api/v1/me/crate_owner_invitations/:crate_name",
+            put(answer_owner_invitation),
+        )
+        .route("/api/v1/mirror/import", put(mirror_import))
+        .route("/api/v1/mirror/reviews", get(mirror_reviews))
+        .route("/api/v1/mirror/reviews/:id", get(mirror_review))
+        .route(
+            "/api/v1/mirror/reviews/:id/approve",
+            put(approve_mirror_review),
+        )
+        .route(
+            "/api/v1/mirror/reviews/:id/reject",
+            put(reject_mirror_review),
+        )
+        .route("/api/v1/admin/audit", get(audit_events))
+        .route(
+            "/api/v1/admin/webhooks",
+            get(registry_webhooks).put(new_registry_webhook),
+        )
+        .route(
+            "/api/v1/admin/webhooks/:id",
+            delete(delete_registry_webhook),
+        )
+        .route(
+            "/api/v1/admin/webhooks/:id/deliveries",
+            get(registry_webhook_deliveries),
+        )
+        .route(
+            "/api/v1/crates/:crate_name/webhooks",
+            get(crate_webhooks).put(new_crate_webhook),
+        )
+        .route(
+            "/api/v1/crates/:crate_name/webhooks/:id",
+            delete(delete_crate_webhook),
+        )
+        .route(
+            "/api/v1/crates/:crate_name/webhooks/:id/deliveries",
+            get(crate_webhook_deliveries),
+        )
+        .route("/index/*path", get(sparse_index::index_file))
         .route(
             "/api/v1/crates/:crate_name/owners",
             get(owners).put(add_owners).delete(remove_owners),
         )
+        .merge(web::router())
+        .merge(docs::router())
+        .route("/metrics", get(metrics::serve))
+        .layer(metrics::MetricsLayer)
         .layer(axum::extract::Extension(sender))
+        .layer(axum::extract::Extension(storage))
+        .layer(axum::extract::Extension(downloads))
         .layer(axum::extract::Extension(settings))
         .layer(axum::extract::Extension(pool))
+        .layer(axum::extract::Extension(limiters))
         .layer(axum::extract::Extension(Arc::new(openid_client)))
 }
 
@@ -394,26 +2283,69 @@ pub enum ApiServerError {
     SqlxMigration(#[from] sqlx::migrate::MigrateError),
     #[error("Hyper error: {0}")]
     Hyper(#[from] hyper::Error),
+    #[error("Invalid bind address: {0}")]
+    BindAddress(#[from] std::net::AddrParseError),
 }
 
 pub async fn serve(
     sender: registry::SyncSender,
+    storage: Arc<dyn storage::Storage>,
     settings: settings::Settings,
     pool: PgPool,
 ) -> Result<(), ApiServerError> {
+    let bind_address: std::net::SocketAddr = settings.bind_address.parse()?;
     let settings = Arc::new(settings);
+    let (downloads, flush_task) = downloads::spawn(pool.clone(), downloads::FLUSH_INTERVAL);
+
+    info!(
+        "Listening on {}, reachable at {}",
+        bind_address, settings.api_url
+    );
 
-    axum::Server::bind(&"0.0.0.0:8080".parse().unwrap())
+    axum::Server::bind(&bind_address)
         .serve(
             build_router(
                 sender,
+                storage,
+                downloads,
                 settings.clone(),
                 pool.clone(),
                 openid::build_client(settings, pool).await,
             )
-            .into_make_service(),
+            // the peer address is what downloads and logins are rate limited by
+            .into_make_service_with_connect_info::<std::net::SocketAddr, _>(),
         )
+        .with_graceful_shutdown(shutdown_signal())
         .await?;
 
+    // the router took the last download counter with it, wait for the final flush
+    if let Err(err) = flush_task.await {
+        tracing::error!("Download counter failed: {}", err);
+    }
+
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutting down");
+}
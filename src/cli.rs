@@ -0,0 +1,779 @@
+use std::path::Path;
+
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::types::chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::info;
+
+use crate::{apiserver, docs, init_registry, models, registry, settings, storage, webhooks};
+
+#[derive(Parser)]
+#[clap(name = "registmily", about = "A cargo crate registry")]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the api server, the default without a subcommand
+    Serve,
+    /// Create the index repo and the crate storage
+    Init,
+    /// Run the database migrations
+    Migrate,
+    /// Manage users
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Manage session and api tokens
+    #[clap(subcommand)]
+    Token(TokenCommand),
+    /// Manage crates
+    #[clap(subcommand)]
+    Crate(CrateCommand),
+    /// Manage teams that can own crates
+    #[clap(subcommand)]
+    Team(TeamCommand),
+    /// Read the audit log
+    #[clap(subcommand)]
+    Audit(AuditCommand),
+    /// Manage webhooks of crates and the whole registry
+    #[clap(subcommand)]
+    Webhook(WebhookCommand),
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Add a user, e.g. one that hasn't logged in yet
+    Add { ident: String },
+    /// Keep a user from logging in and delete all their tokens
+    Disable { ident: String },
+    /// List all users
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Issue a token, without scopes it can do everything like a login session
+    Issue {
+        ident: String,
+        #[clap(long, default_value = "cli")]
+        name: String,
+        #[clap(long = "scope")]
+        scopes: Vec<String>,
+        #[clap(long = "crate")]
+        crate_patterns: Vec<String>,
+        #[clap(long, default_value = "90")]
+        expires_in_days: u32,
+    },
+    /// List the tokens of a user
+    List { ident: String },
+    /// Revoke a token of a user by its id
+    Revoke { ident: String, id: i64 },
+}
+
+#[derive(Subcommand)]
+pub enum TeamCommand {
+    /// Add a team, it can be made a crate owner as `team:<name>`
+    Add { name: String },
+    /// Delete a team, the crates it owns keep their other owners
+    Delete { name: String },
+    /// List all teams
+    List,
+    /// List the members of a team, optionally adding or removing some first
+    Members {
+        name: String,
+        #[clap(long)]
+        add: Vec<String>,
+        #[clap(long)]
+        remove: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CrateCommand {
+    /// List the owners of a crate, optionally adding or removing some first
+    Owners {
+        name: String,
+        #[clap(long)]
+        add: Vec<String>,
+        #[clap(long)]
+        remove: Vec<String>,
+    },
+    /// Yank a version of a crate
+    Yank {
+        name: String,
+        version: String,
+        #[clap(long)]
+        undo: bool,
+    },
+    /// Delete a crate from the index and the database
+    Delete { name: String },
+    /// Queue a version for building its docs again
+    Docs { name: String, version: String },
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommand {
+    /// Print audit events as json lines, oldest first
+    Export {
+        #[clap(long = "crate")]
+        crate_name: Option<String>,
+        #[clap(long)]
+        actor: Option<String>,
+        #[clap(long)]
+        action: Option<String>,
+        /// RFC 3339 time of the first event to include
+        #[clap(long)]
+        since: Option<String>,
+        /// RFC 3339 time after the last event to include
+        #[clap(long)]
+        until: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum WebhookCommand {
+    /// Add a webhook and print its secret, without a crate it gets the events of all crates
+    Add {
+        url: String,
+        #[clap(long = "crate")]
+        crate_name: Option<String>,
+        /// Audit actions to send, e.g. `publish`, all of them if none are given
+        #[clap(long = "event")]
+        events: Vec<String>,
+    },
+    /// List the webhooks of a crate, or the registry-wide ones
+    List {
+        #[clap(long = "crate")]
+        crate_name: Option<String>,
+    },
+    /// Delete a webhook by its id
+    Delete {
+        id: i64,
+        #[clap(long = "crate")]
+        crate_name: Option<String>,
+    },
+    /// Show the latest deliveries of a webhook
+    Deliveries {
+        id: i64,
+        #[clap(long = "crate")]
+        crate_name: Option<String>,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("Sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("Failed to run database migrations: {0}")]
+    SqlxMigration(#[from] sqlx::migrate::MigrateError),
+    #[error("{0}")]
+    ApiServer(#[from] apiserver::ApiServerError),
+    #[error("Failed to initialize the registry: {0}")]
+    Init(#[from] init_registry::InitError),
+    #[error("Failed to yank: {0}")]
+    Yank(#[from] registry::YankError),
+    #[error("Failed to delete: {0}")]
+    Delete(#[from] registry::DeleteError),
+    #[error("Failed to start the doc builder: {0}")]
+    Docs(#[from] docs::DocsError),
+    #[error("Failed to read the index: {0}")]
+    Index(#[from] std::io::Error),
+    #[error("Invalid entry in the index: {0}")]
+    IndexEntry(#[from] serde_json::Error),
+    #[error("Registry task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+pub async fn run(command: Command, config: settings::Settings) -> Result<(), CliError> {
+    match command {
+        Command::Serve => serve(config).await,
+        Command::Init => {
+            init_registry::setup_registry(
+                Path::new(&config.repo_path),
+                Path::new(&config.storage_path),
+                &config.api_url,
+            )?;
+            info!("Initialized registry in {}", config.repo_path);
+            Ok(())
+        }
+        Command::Migrate => {
+            let pool = connect(&config).await?;
+            info!("Running migrations");
+            sqlx::migrate!("./migrations").run(&pool).await?;
+            backfill_versions(&config.repo_path, &pool).await
+        }
+        Command::User(command) => user(command, &connect(&config).await?).await,
+        Command::Token(command) => token(command, &connect(&config).await?).await,
+        Command::Crate(command) => {
+            let pool = connect(&config).await?;
+            krate(command, config, &pool).await
+        }
+        Command::Team(command) => team(command, &connect(&config).await?).await,
+        Command::Audit(command) => audit(command, &connect(&config).await?).await,
+        Command::Webhook(command) => {
+            let pool = connect(&config).await?;
+            webhook(command, &config, &pool).await
+        }
+    }
+}
+
+async fn connect(config: &settings::Settings) -> Result<PgPool, sqlx::Error> {
+    info!("Connecting to DB");
+
+    PgPoolOptions::new()
+        .max_connections(config.database_connections)
+        .connect(&config.database_url)
+        .await
+}
+
+async fn serve(config: settings::Settings) -> Result<(), CliError> {
+    let repo_path = config.repo_path.clone();
+    let storage = storage::from_settings(&config);
+    let handler_storage = storage.clone();
+    let runtime = tokio::runtime::Handle::current();
+
+    let (sender, recv) = tokio::sync::mpsc::channel(registry::QUEUE_SIZE);
+    let jh =
+        std::thread::spawn(move || registry::handler(&repo_path, handler_storage, runtime, recv));
+
+    info!("Starting up");
+
+    let pool = connect(&config).await?;
+
+    info!("Running migrations");
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    backfill_versions(&config.repo_path, &pool).await?;
+
+    docs::spawn(&config, pool.clone(), storage.clone())?;
+    webhooks::spawn(pool.clone(), config.webhook_allowed_hosts.clone());
+
+    info!("Database setup done, starting api server");
+
+    apiserver::serve(sender, storage, config, pool).await?;
+
+    jh.join().unwrap();
+
+    Ok(())
+}
+
+// Versions published before the crate_versions table existed are only in the index
+async fn backfill_versions(repo_path: &str, pool: &PgPool) -> Result<(), CliError> {
+    let mut trans = pool.begin().await?;
+    let mut added = 0;
+
+    for path in registry::index_files(repo_path) {
+        let packages = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str::<registry::Package>)
+            .collect::<Result<Vec<_>, _>>()?;
+        let name = match packages.first() {
+            Some(pkg) => pkg.name.clone(),
+            None => continue,
+        };
+
+        let krate = match models::Crate::by_name(&mut trans, &name).await? {
+            Some(krate) => krate,
+            None => models::Crate::new(&mut trans, &name).await?,
+        };
+        let known: Vec<String> = models::CrateVersion::all_by_crate(&mut trans, &name)
+            .await?
+            .into_iter()
+            .map(|x| x.version)
+            .collect();
+
+        if krate.max_version.is_none() {
+            let max_version = packages
+                .iter()
+                .filter_map(|x| semver::Version::parse(&x.vers).ok().map(|v| (v, &x.vers)))
+                .max()
+                .map(|(_, vers)| vers.clone());
+            if let Some(max_version) = max_version {
+                models::Crate::set_newest_version(&mut trans, &name, &max_version, None).await?;
+            }
+        }
+
+        for pkg in packages {
+            if known.contains(&pkg.vers) {
+                continue;
+            }
+            let (checksum, yanked) = (pkg.cksum.clone(), pkg.yanked);
+            let version =
+                models::CrateVersion::new(&mut trans, &pkg.into(), &checksum, None).await?;
+            if yanked {
+                models::CrateVersion::set_yanked(&mut trans, &name, &version.version, true).await?;
+            }
+            added += 1;
+        }
+    }
+
+    trans.commit().await?;
+
+    if added > 0 {
+        info!("Added {} versions from the index to the database", added);
+    }
+
+    Ok(())
+}
+
+async fn user(command: UserCommand, pool: &PgPool) -> Result<(), CliError> {
+    let mut trans = pool.begin().await?;
+
+    match command {
+        UserCommand::Add { ident } => {
+            if models::User::exists_by_ident(&mut trans, &ident).await? {
+                return Err(CliError::Invalid(format!("user {} already exists", ident)));
+            }
+            models::User::new(&mut trans, &ident).await?;
+            println!("added user {}", ident);
+        }
+        UserCommand::Disable { ident } => {
+            if !models::User::exists_by_ident(&mut trans, &ident).await? {
+                return Err(CliError::Invalid(format!("user {} does not exist", ident)));
+            }
+            models::User::set_disabled(&mut trans, &ident, true).await?;
+            models::UserSession::delete_by_ident(&mut trans, &ident).await?;
+            println!("disabled user {}", ident);
+        }
+        UserCommand::List => {
+            for user in models::User::all(&mut trans).await? {
+                if user.disabled {
+                    println!("{} (disabled)", user.ident);
+                } else {
+                    println!("{}", user.ident);
+                }
+            }
+        }
+    }
+
+    trans.commit().await?;
+
+    Ok(())
+}
+
+async fn token(command: TokenCommand, pool: &PgPool) -> Result<(), CliError> {
+    let mut trans = pool.begin().await?;
+
+    match command {
+        TokenCommand::Issue {
+            ident,
+            name,
+            scopes,
+            crate_patterns,
+            expires_in_days,
+        } => {
+            match models::User::by_ident(&mut trans, &ident).await? {
+                Some(user) if user.disabled => {
+                    return Err(CliError::Invalid(format!("user {} is disabled", ident)))
+                }
+                Some(_) => {}
+                None => return Err(CliError::Invalid(format!("user {} does not exist", ident))),
+            }
+
+            let scopes = scopes
+                .iter()
+                .map(|x| {
+                    models::TokenScope::parse(x)
+                        .ok_or_else(|| CliError::Invalid(format!("unknown token scope {}", x)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if scopes.is_empty() && !crate_patterns.is_empty() {
+                return Err(CliError::Invalid(String::from(
+                    "api tokens need at least one scope",
+                )));
+            }
+
+            for pattern in &crate_patterns {
+                registry::validate_crate_pattern(pattern).map_err(|why| {
+                    CliError::Invalid(format!("invalid crate pattern {}: {}", pattern, why))
+                })?;
+            }
+
+            let (_, token) = models::UserSession::new_api_token(
+                &mut trans,
+                &ident,
+                &name,
+                Some(&scopes[..]).filter(|x| !x.is_empty()),
+                Some(&crate_patterns[..]).filter(|x| !x.is_empty()),
+                Utc::now() + Duration::days(expires_in_days as i64),
+            )
+            .await?;
+
+            println!("{}", token);
+        }
+        TokenCommand::List { ident } => {
+            for session in models::UserSession::all_by_ident(&mut trans, &ident).await? {
+                println!(
+                    "{}\t{}\t{}...\texpires {}",
+                    session.id,
+                    session.name,
+                    session.token_prefix,
+                    session
+                        .expires_at
+                        .map(|x| x.to_rfc3339())
+                        .unwrap_or_else(|| String::from("never"))
+                );
+            }
+        }
+        TokenCommand::Revoke { ident, id } => {
+            if !models::UserSession::delete_by_id(&mut trans, &ident, id).await? {
+                return Err(CliError::Invalid(format!(
+                    "user {} has no token with id {}",
+                    ident, id
+                )));
+            }
+            println!("revoked token {}", id);
+        }
+    }
+
+    trans.commit().await?;
+
+    Ok(())
+}
+
+async fn team(command: TeamCommand, pool: &PgPool) -> Result<(), CliError> {
+    let mut trans = pool.begin().await?;
+
+    match command {
+        TeamCommand::Add { name } => {
+            // team names end up in owner logins, so they follow the crate name rules
+            if let Err(why) = registry::validate_package_name(&name) {
+                return Err(CliError::Invalid(format!("invalid team name: {}", why)));
+            }
+            if models::Team::exists(&mut trans, &name).await? {
+                return Err(CliError::Invalid(format!("team {} already exists", name)));
+            }
+            models::Team::new(&mut trans, &name).await?;
+            println!("added team {}", name);
+        }
+        TeamCommand::Delete { name } => {
+            if !models::Team::exists(&mut trans, &name).await? {
+                return Err(CliError::Invalid(format!("team {} does not exist", name)));
+            }
+            models::Team::delete(&mut trans, &name).await?;
+            println!("deleted team {}", name);
+        }
+        TeamCommand::List => {
+            for team in models::Team::all(&mut trans).await? {
+                println!("{}", team.name);
+            }
+        }
+        TeamCommand::Members { name, add, remove } => {
+            if !models::Team::exists(&mut trans, &name).await? {
+                return Err(CliError::Invalid(format!("team {} does not exist", name)));
+            }
+            for member in add {
+                if !models::User::exists_by_ident(&mut trans, &member).await? {
+                    return Err(CliError::Invalid(format!("user {} does not exist", member)));
+                }
+                models::TeamMember::new(&mut trans, &name, &member).await?;
+            }
+            for member in remove {
+                models::TeamMember::delete(&mut trans, &name, &member).await?;
+            }
+
+            for member in models::TeamMember::all_by_team(&mut trans, &name).await? {
+                println!("{}", member.user_ident);
+            }
+        }
+    }
+
+    trans.commit().await?;
+
+    Ok(())
+}
+
+async fn krate(
+    command: CrateCommand,
+    config: settings::Settings,
+    pool: &PgPool,
+) -> Result<(), CliError> {
+    let name = match &command {
+        CrateCommand::Owners { name, .. }
+        | CrateCommand::Yank { name, .. }
+        | CrateCommand::Delete { name }
+        | CrateCommand::Docs { name, .. } => name.clone(),
+    };
+    if let Err(why) = registry::validate_package_name(&name) {
+        return Err(CliError::Invalid(format!("invalid crate name: {}", why)));
+    }
+
+    let mut trans = pool.begin().await?;
+    if !models::Crate::exists_by_ident(&mut trans, &name).await? {
+        return Err(CliError::Invalid(format!("crate {} does not exist", name)));
+    }
+
+    // git2 isn't thread safe, so the registry gets its own blocking thread just like in `serve`
+    let storage = storage::from_settings(&config);
+    let runtime = tokio::runtime::Handle::current();
+    let repo_path = config.repo_path.clone();
+
+    match command {
+        CrateCommand::Owners { add, remove, .. } => {
+            for owner in add {
+                let added = if let Some(team) = owner.strip_prefix(models::TEAM_PREFIX) {
+                    if !models::Team::exists(&mut trans, team).await? {
+                        return Err(CliError::Invalid(format!("team {} does not exist", team)));
+                    }
+                    let added = !models::CrateTeamOwner::exists(&mut trans, &name, team).await?;
+                    if added {
+                        models::CrateTeamOwner::new(&mut trans, &name, team).await?;
+                    }
+                    added
+                } else {
+                    if !models::User::exists_by_ident(&mut trans, &owner).await? {
+                        return Err(CliError::Invalid(format!("user {} does not exist", owner)));
+                    }
+                    let added = !models::CrateOwner::exists(&mut trans, &name, &owner).await?;
+                    if added {
+                        models::CrateOwner::new(&mut trans, &name, &owner).await?;
+                    }
+                    added
+                };
+
+                if added {
+                    models::AuditEvent::record(
+                        &mut trans,
+                        &models::AuditActor::cli(),
+                        models::AuditAction::AddOwner,
+                        &name,
+                        None,
+                        json!({ "owner": owner }),
+                    )
+                    .await?;
+                }
+            }
+            let mut removed_any = false;
+            for owner in remove {
+                let removed = if let Some(team) = owner.strip_prefix(models::TEAM_PREFIX) {
+                    let removed = models::CrateTeamOwner::exists(&mut trans, &name, team).await?;
+                    if removed {
+                        models::CrateTeamOwner::delete(&mut trans, &name, team).await?;
+                    }
+                    removed
+                } else {
+                    let removed = models::CrateOwner::exists(&mut trans, &name, &owner).await?;
+                    if removed {
+                        models::CrateOwner::delete(&mut trans, &name, &owner).await?;
+                        models::OwnerInvitation::delete_by_inviter(&mut trans, &name, &owner)
+                            .await?;
+                    }
+                    removed
+                };
+
+                if removed {
+                    removed_any = true;
+                    models::AuditEvent::record(
+                        &mut trans,
+                        &models::AuditActor::cli(),
+                        models::AuditAction::RemoveOwner,
+                        &name,
+                        None,
+                        json!({ "owner": owner }),
+                    )
+                    .await?;
+                }
+            }
+            if removed_any && !models::CrateOwner::any_exists(&mut trans, &name).await? {
+                return Err(CliError::Invalid(format!(
+                    "crate {} needs at least one user or team owner",
+                    name
+                )));
+            }
+
+            for owner in models::CrateOwner::all_owners(&mut trans, &name).await? {
+                println!("{}", owner.user_ident);
+            }
+            for owner in models::CrateTeamOwner::all_owners(&mut trans, &name).await? {
+                println!("{}{}", models::TEAM_PREFIX, owner.team_name);
+            }
+        }
+        CrateCommand::Yank { version, undo, .. } => {
+            models::CrateVersion::set_yanked(&mut trans, &name, &version, !undo).await?;
+            let action = if undo {
+                models::AuditAction::Unyank
+            } else {
+                models::AuditAction::Yank
+            };
+            models::AuditEvent::record(
+                &mut trans,
+                &models::AuditActor::cli(),
+                action,
+                &name,
+                Some(&version),
+                json!({}),
+            )
+            .await?;
+
+            tokio::task::spawn_blocking(move || {
+                registry::Registry::new(&repo_path, storage, runtime).yank(name, version, !undo)
+            })
+            .await??;
+        }
+        CrateCommand::Delete { .. } => {
+            models::Crate::delete(&mut trans, &name).await?;
+            models::AuditEvent::record(
+                &mut trans,
+                &models::AuditActor::cli(),
+                models::AuditAction::Delete,
+                &name,
+                None,
+                json!({}),
+            )
+            .await?;
+
+            let crate_name = name.clone();
+            tokio::task::spawn_blocking(move || {
+                registry::Registry::new(&repo_path, storage, runtime).delete(&crate_name)
+            })
+            .await??;
+            println!("deleted crate {}", name);
+        }
+        CrateCommand::Docs { version, .. } => {
+            let crate_version = models::CrateVersion::by_version(&mut trans, &name, &version)
+                .await?
+                .ok_or_else(|| {
+                    CliError::Invalid(format!("crate {} has no version {}", name, version))
+                })?;
+            models::DocBuild::queue(&mut trans, crate_version.id).await?;
+            println!("queued docs for {} {}", name, version);
+        }
+    }
+
+    trans.commit().await?;
+
+    Ok(())
+}
+
+async fn webhook(
+    command: WebhookCommand,
+    config: &settings::Settings,
+    pool: &PgPool,
+) -> Result<(), CliError> {
+    let mut trans = pool.begin().await?;
+
+    match command {
+        WebhookCommand::Add {
+            url,
+            crate_name,
+            events,
+        } => {
+            if let Some(crate_name) = &crate_name {
+                if !models::Crate::exists_by_ident(&mut trans, crate_name).await? {
+                    return Err(CliError::Invalid(format!(
+                        "crate {} does not exist",
+                        crate_name
+                    )));
+                }
+            }
+
+            let new_webhook = apiserver::NewWebhook {
+                url,
+                events: Some(events).filter(|x| !x.is_empty()),
+            };
+            let webhook = apiserver::create_webhook(
+                &mut trans,
+                crate_name.as_deref(),
+                "cli",
+                &new_webhook,
+                &config.webhook_allowed_hosts,
+            )
+            .await
+            .map_err(|why| CliError::Invalid(why.0))?;
+            println!("{}\t{}", webhook.id, webhook.secret.unwrap_or_default());
+        }
+        WebhookCommand::List { crate_name } => {
+            for webhook in models::Webhook::all_by_crate(&mut trans, crate_name.as_deref()).await? {
+                println!(
+                    "{}\t{}\t{}",
+                    webhook.id,
+                    webhook.url,
+                    webhook
+                        .events
+                        .map(|x| x.join(","))
+                        .unwrap_or_else(|| String::from("all events"))
+                );
+            }
+        }
+        WebhookCommand::Delete { id, crate_name } => {
+            if !models::Webhook::delete(&mut trans, id, crate_name.as_deref()).await? {
+                return Err(CliError::Invalid(format!("there is no webhook {}", id)));
+            }
+            println!("deleted webhook {}", id);
+        }
+        WebhookCommand::Deliveries { id, crate_name } => {
+            if models::Webhook::by_id(&mut trans, id, crate_name.as_deref())
+                .await?
+                .is_none()
+            {
+                return Err(CliError::Invalid(format!("there is no webhook {}", id)));
+            }
+            for delivery in models::WebhookDelivery::all_by_webhook(&mut trans, id, 50).await? {
+                println!(
+                    "{}\t{}\t{}\t{} attempts\t{}",
+                    delivery.id,
+                    delivery.event,
+                    delivery.status,
+                    delivery.attempts,
+                    delivery.last_error.unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    trans.commit().await?;
+
+    Ok(())
+}
+
+async fn audit(command: AuditCommand, pool: &PgPool) -> Result<(), CliError> {
+    let mut trans = pool.begin().await?;
+
+    match command {
+        AuditCommand::Export {
+            crate_name,
+            actor,
+            action,
+            since,
+            until,
+        } => {
+            let action = match action {
+                Some(action) => Some(models::AuditAction::parse(&action).ok_or_else(|| {
+                    CliError::Invalid(format!("unknown audit action {}", action))
+                })?),
+                None => None,
+            };
+            let filter = models::AuditFilter {
+                crate_name,
+                actor,
+                action,
+                since: since.as_deref().map(parse_time).transpose()?,
+                until: until.as_deref().map(parse_time).transpose()?,
+            };
+
+            for event in models::AuditEvent::export(&mut trans, &filter).await? {
+                println!(
+                    "{}",
+                    serde_json::to_string(&apiserver::AuditEventJson::from(event))
+                        .expect("audit events are valid json")
+                );
+            }
+        }
+    }
+
+    trans.commit().await?;
+
+    Ok(())
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, CliError> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|x| x.with_timezone(&Utc))
+        .map_err(|_| CliError::Invalid(format!("invalid RFC 3339 time {}", time)))
+}
@@ -0,0 +1,205 @@
+use std::io::{self, Read};
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CrateFileError {
+    #[error("crate file is not a valid gzip compressed tarball: {0}")]
+    InvalidArchive(#[from] io::Error),
+    #[error("crate file unpacks to more than {0} bytes")]
+    TooLarge(u64),
+    #[error("`{0}` in the crate file is not inside the `{1}` directory")]
+    OutsideRoot(String, String),
+    #[error("link `{0}` in the crate file points outside the `{1}` directory")]
+    LinkOutsideRoot(String, String),
+    #[error("crate file has no `Cargo.toml`")]
+    MissingManifest,
+    #[error("invalid `Cargo.toml` in the crate file: {0}")]
+    InvalidManifest(#[from] toml::de::Error),
+    #[error("`Cargo.toml` is for crate `{0}` but `{1}` was published")]
+    NameMismatch(String, String),
+    #[error("`Cargo.toml` has version `{0}` but `{1}` was published")]
+    VersionMismatch(String, String),
+}
+
+// The normalized manifest cargo puts into the crate file, older cargo calls `package` `project`
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(alias = "project")]
+    package: Option<ManifestPackage>,
+}
+
+#[derive(Deserialize)]
+struct ManifestPackage {
+    name: String,
+    version: String,
+}
+
+// Stops decompressing after `remaining` bytes so a gzip bomb can't keep the server busy
+pub(crate) struct SizeLimit<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R> SizeLimit<R> {
+    pub(crate) fn new(inner: R, max_size: u64) -> Self {
+        Self {
+            inner,
+            remaining: max_size,
+            exceeded: false,
+        }
+    }
+
+    // Whether reading stopped because of the limit, the error it gave is a plain io error
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: Read> Read for SizeLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // only too large if there's actually more to come
+            if self.inner.read(&mut [0])? == 0 {
+                return Ok(0);
+            }
+            self.exceeded = true;
+            return Err(io::Error::other("size limit exceeded"));
+        }
+
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+// Unpacks the `.crate` file in memory and checks it's what the publish request says it is
+pub fn validate(
+    crate_file: &[u8],
+    name: &str,
+    version: &str,
+    max_unpacked_size: u64,
+) -> Result<(), CrateFileError> {
+    let root = format!("{}-{}", name, version);
+
+    let mut reader = SizeLimit::new(GzDecoder::new(crate_file), max_unpacked_size);
+    let manifest = check_entries(tar::Archive::new(&mut reader), &root);
+    if reader.exceeded() {
+        return Err(CrateFileError::TooLarge(max_unpacked_size));
+    }
+
+    let manifest: Manifest = toml::from_slice(&manifest?.ok_or(CrateFileError::MissingManifest)?)?;
+    let package = manifest.package.ok_or(CrateFileError::MissingManifest)?;
+
+    if package.name != name {
+        return Err(CrateFileError::NameMismatch(
+            package.name,
+            String::from(name),
+        ));
+    }
+    let same_version = match (
+        semver::Version::parse(&package.version),
+        semver::Version::parse(version),
+    ) {
+        (Ok(manifest_version), Ok(version)) => manifest_version == version,
+        _ => package.version == version,
+    };
+    if !same_version {
+        return Err(CrateFileError::VersionMismatch(
+            package.version,
+            String::from(version),
+        ));
+    }
+
+    Ok(())
+}
+
+// Returns the content of `<root>/Cargo.toml`, the last one if there are several as that's
+// the one unpacking leaves behind
+fn check_entries<R: Read>(
+    mut archive: tar::Archive<R>,
+    root: &str,
+) -> Result<Option<Vec<u8>>, CrateFileError> {
+    let mut manifest = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let display_path = path.to_string_lossy().into_owned();
+
+        let components = match inside_root(&path, root, Vec::new()) {
+            Some(components) => components,
+            None => {
+                return Err(CrateFileError::OutsideRoot(
+                    display_path,
+                    String::from(root),
+                ))
+            }
+        };
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let link_name = match entry.link_name()? {
+                Some(link_name) => link_name.into_owned(),
+                None => {
+                    return Err(CrateFileError::LinkOutsideRoot(
+                        display_path,
+                        String::from(root),
+                    ))
+                }
+            };
+            // symlinks are relative to their directory, hard links to the archive root
+            let base = if entry_type.is_symlink() {
+                components[..components.len() - 1].to_vec()
+            } else {
+                Vec::new()
+            };
+            if inside_root(&link_name, root, base).is_none() {
+                return Err(CrateFileError::LinkOutsideRoot(
+                    display_path,
+                    String::from(root),
+                ));
+            }
+        }
+
+        if entry_type.is_file() && components.len() == 2 && components[1] == "Cargo.toml" {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            manifest = Some(content);
+        }
+    }
+
+    Ok(manifest)
+}
+
+// Resolves `path` on top of `base` and returns its components if it never leaves `root`
+fn inside_root(path: &Path, root: &str, base: Vec<String>) -> Option<Vec<String>> {
+    let mut components = base;
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str()?.to_owned()),
+            Component::CurDir => {}
+            Component::ParentDir if components.len() > 1 => {
+                components.pop();
+            }
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+        if matches!(components.first(), Some(first) if first != root) {
+            return None;
+        }
+    }
+
+    if components.is_empty() {
+        None
+    } else {
+        Some(components)
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::StreamBody,
+    extract::{self, Extension},
+    http::{header, StatusCode, Uri},
+    response::{Headers, IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use flate2::read::GzDecoder;
+use serde_derive::Deserialize;
+use sqlx::PgPool;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info};
+
+use crate::{apiresponse::ApiError, models, registry, settings, storage, web};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const BUILD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// only the end of the log is kept, that's where the errors are
+const MAX_LOG_LEN: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum DocsError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("storage error: {0}")]
+    Storage(#[from] storage::StorageError),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid Cargo.toml: {0}")]
+    Manifest(#[from] toml::de::Error),
+    #[error("failed to write Cargo.toml: {0}")]
+    ManifestWrite(#[from] toml::ser::Error),
+    #[error("build task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("the crate file {0} is missing from the storage")]
+    CrateMissing(String),
+    #[error("the build timed out after {0} seconds")]
+    Timeout(u64),
+    #[error("docs_sandbox is empty, crates won't get built outside of a sandbox")]
+    NoSandbox,
+}
+
+// Builds the docs of queued versions one after another. `cargo doc` runs in the sandbox
+// command from the settings and resolves dependencies from a local registry made out of
+// the index repo and the crate files in the storage, so it never needs the network.
+pub struct Builder {
+    pool: PgPool,
+    storage: Arc<dyn storage::Storage>,
+    build_path: PathBuf,
+    repo_path: PathBuf,
+    sandbox: Vec<String>,
+}
+
+struct BuildOutput {
+    log: String,
+    // None if the build failed
+    doc_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    name: String,
+    vers: String,
+    cksum: String,
+}
+
+pub fn spawn(
+    settings: &settings::Settings,
+    pool: PgPool,
+    storage: Arc<dyn storage::Storage>,
+) -> Result<Option<JoinHandle<()>>, DocsError> {
+    let build_path = match &settings.docs_build_path {
+        Some(build_path) => build_path,
+        None => return Ok(None),
+    };
+    if settings.docs_sandbox.is_empty() {
+        return Err(DocsError::NoSandbox);
+    }
+    std::fs::create_dir_all(build_path)?;
+
+    // both end up in the sandbox and the cargo config, so they have to be absolute
+    let builder = Builder {
+        pool,
+        storage,
+        build_path: std::fs::canonicalize(build_path)?,
+        repo_path: std::fs::canonicalize(&settings.repo_path)?,
+        sandbox: settings.docs_sandbox.clone(),
+    };
+
+    Ok(Some(tokio::spawn(builder.run())))
+}
+
+impl Builder {
+    async fn run(self) {
+        info!("Doc builder started in {}", self.build_path.display());
+
+        loop {
+            match self.start_next().await {
+                Ok(Some(build)) => self.process(build).await,
+                Ok(None) => {
+                    // builds of crashed builders, ours or another instance's, time out here
+                    if let Err(err) = self.requeue_unfinished().await {
+                        error!("Failed to requeue unfinished doc builds: {}", err);
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await
+                }
+                Err(err) => {
+                    error!("Failed to fetch the next doc build: {}", err);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn requeue_unfinished(&self) -> models::DbResult<()> {
+        let mut trans = self.pool.begin().await?;
+        models::DocBuild::requeue_unfinished(&mut trans, BUILD_TIMEOUT).await?;
+        trans.commit().await
+    }
+
+    async fn start_next(&self) -> models::DbResult<Option<models::DocBuild>> {
+        let mut trans = self.pool.begin().await?;
+        let build = models::DocBuild::start_next(&mut trans).await?;
+        trans.commit().await?;
+        Ok(build)
+    }
+
+    async fn process(&self, build: models::DocBuild) {
+        info!("Building docs for {} {}", build.crate_name, build.version);
+
+        let (status, log, doc_path) = match self.build(&build).await {
+            Ok(BuildOutput {
+                log,
+                doc_path: Some(doc_path),
+            }) => (models::DocBuildStatus::Succeeded, log, Some(doc_path)),
+            Ok(BuildOutput {
+                log,
+                doc_path: None,
+            }) => (models::DocBuildStatus::Failed, log, None),
+            Err(err) => (models::DocBuildStatus::Failed, err.to_string(), None),
+        };
+        info!(
+            "Docs for {} {} {}",
+            build.crate_name,
+            build.version,
+            status.as_str()
+        );
+
+        let result: models::DbResult<()> = async {
+            let mut trans = self.pool.begin().await?;
+            models::DocBuild::finish(
+                &mut trans,
+                build.version_id,
+                status,
+                &log,
+                doc_path.as_deref(),
+            )
+            .await?;
+            trans.commit().await
+        }
+        .await;
+        if let Err(err) = result {
+            error!("Failed to store the doc build result: {}", err);
+        }
+    }
+
+    async fn build(&self, build: &models::DocBuild) -> Result<BuildOutput, DocsError> {
+        let mut dir = self.build_path.clone();
+        dir.push("builds");
+        dir.push(format!("{}-{}", build.crate_name, build.version));
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await?;
+        let result = self.build_in(&dir, build).await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        result
+    }
+
+    async fn build_in(
+        &self,
+        dir: &Path,
+        build: &models::DocBuild,
+    ) -> Result<BuildOutput, DocsError> {
+        let local_registry = self.sync_local_registry().await?;
+
+        let crate_file = self
+            .read_crate(&build.checksum)
+            .await?
+            .ok_or_else(|| DocsError::CrateMissing(build.checksum.clone()))?;
+        let unpack_dir = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            tar::Archive::new(GzDecoder::new(&crate_file[..])).unpack(unpack_dir)
+        })
+        .await??;
+
+        let source = dir.join(format!("{}-{}", build.crate_name, build.version));
+        let lib_name = prepare_source(&source, &local_registry, &build.crate_name)?;
+
+        let mut args = self.sandbox_args(dir, &local_registry);
+        args.extend(["cargo", "doc", "--no-deps", "--offline"].map(String::from));
+
+        let mut command = Command::new(&args[0]);
+        command
+            .args(&args[1..])
+            .current_dir(&source)
+            .env("CARGO_HOME", dir.join("cargo-home"))
+            .env("CARGO_TARGET_DIR", dir.join("target"))
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        let output = match tokio::time::timeout(BUILD_TIMEOUT, command.output()).await {
+            Ok(output) => output?,
+            Err(_) => return Err(DocsError::Timeout(BUILD_TIMEOUT.as_secs())),
+        };
+        let mut log = String::from_utf8_lossy(&output.stdout).into_owned();
+        log.push_str(&String::from_utf8_lossy(&output.stderr));
+
+        if !output.status.success() {
+            return Ok(BuildOutput {
+                log: truncate_log(log),
+                doc_path: None,
+            });
+        }
+
+        let doc_root = dir.join("target").join("doc");
+        let doc_path = match find_doc_path(&doc_root, &lib_name)? {
+            Some(doc_path) => doc_path,
+            None => {
+                log.push_str("\ncargo doc didn't generate any documentation");
+                return Ok(BuildOutput {
+                    log: truncate_log(log),
+                    doc_path: None,
+                });
+            }
+        };
+
+        for file in list_files(&doc_root)? {
+            let relative = file
+                .strip_prefix(&doc_root)
+                .unwrap_or(&file)
+                .components()
+                .map(|x| x.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if relative == ".lock" {
+                continue;
+            }
+
+            self.storage
+                .put_doc(
+                    &format!("{}/{}/{}", build.crate_name, build.version, relative),
+                    tokio::fs::read(&file).await?,
+                )
+                .await?;
+        }
+
+        Ok(BuildOutput {
+            log: truncate_log(log),
+            doc_path: Some(doc_path),
+        })
+    }
+
+    // `{dir}` is the build directory, `{registry}` the local registry and `{index}` the index
+    // repo it links to. `{cargo_home}` and `{rustup_home}` are where the toolchain is installed.
+    fn sandbox_args(&self, dir: &Path, local_registry: &Path) -> Vec<String> {
+        let home = std::env::var("HOME").unwrap_or_default();
+        let cargo_home = std::env::var("CARGO_HOME").unwrap_or_else(|_| format!("{}/.cargo", home));
+        let rustup_home =
+            std::env::var("RUSTUP_HOME").unwrap_or_else(|_| format!("{}/.rustup", home));
+
+        self.sandbox
+            .iter()
+            .map(|x| {
+                x.replace("{dir}", &dir.to_string_lossy())
+                    .replace("{registry}", &local_registry.to_string_lossy())
+                    .replace("{index}", &self.repo_path.to_string_lossy())
+                    .replace("{cargo_home}", &cargo_home)
+                    .replace("{rustup_home}", &rustup_home)
+            })
+            .collect()
+    }
+
+    // cargo's local registries are a directory with the index and `<name>-<version>.crate` files,
+    // crate files only get fetched the first time they're needed
+    async fn sync_local_registry(&self) -> Result<PathBuf, DocsError> {
+        let local_registry = self.build_path.join("registry");
+        tokio::fs::create_dir_all(&local_registry).await?;
+
+        let index = local_registry.join("index");
+        if tokio::fs::symlink_metadata(&index).await.is_err() {
+            tokio::fs::symlink(&self.repo_path, &index).await?;
+        }
+
+        let repo_path = self.repo_path.clone();
+        for entry in tokio::task::spawn_blocking(move || index_entries(&repo_path)).await?? {
+            let path = local_registry.join(format!("{}-{}.crate", entry.name, entry.vers));
+            if tokio::fs::metadata(&path).await.is_ok() {
+                continue;
+            }
+
+            // e.g. versions that were mirrored without their crate file, cargo only
+            // complains about them if something actually depends on them
+            if let Some(crate_file) = self.read_crate(&entry.cksum).await? {
+                tokio::fs::write(&path, crate_file).await?;
+            }
+        }
+
+        Ok(local_registry)
+    }
+
+    async fn read_crate(&self, checksum: &str) -> Result<Option<Vec<u8>>, DocsError> {
+        match self.storage.get(checksum).await? {
+            Some(storage::CrateDownload::File(mut file)) => {
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).await?;
+                Ok(Some(content))
+            }
+            Some(storage::CrateDownload::Bytes(bytes)) => Ok(Some(bytes.to_vec())),
+            Some(storage::CrateDownload::Redirect(url)) => Ok(Some(
+                reqwest::get(url)
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?
+                    .to_vec(),
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+// Points cargo at the local registry and drops the dev-dependencies, which docs don't need
+// and which often aren't in this registry. Returns the name of the library target.
+fn prepare_source(
+    source: &Path,
+    local_registry: &Path,
+    crate_name: &str,
+) -> Result<String, DocsError> {
+    let manifest_path = source.join("Cargo.toml");
+    let mut manifest: toml::Value = toml::from_str(&std::fs::read_to_string(&manifest_path)?)?;
+
+    let mut indexes = BTreeSet::new();
+    if let Some(manifest) = manifest.as_table_mut() {
+        strip_dev_dependencies(manifest, &mut indexes);
+        if let Some(toml::Value::Table(targets)) = manifest.get_mut("target") {
+            for target in targets.values_mut() {
+                if let Some(target) = target.as_table_mut() {
+                    strip_dev_dependencies(target, &mut indexes);
+                }
+            }
+        }
+    }
+    std::fs::write(&manifest_path, toml::to_string(&manifest)?)?;
+    // it may pin versions that aren't in this registry
+    let _ = std::fs::remove_file(source.join("Cargo.lock"));
+
+    let mut sources = toml::value::Table::new();
+    sources.insert(
+        String::from("registmily-local"),
+        toml_table(&[("local-registry", &local_registry.to_string_lossy())]),
+    );
+    sources.insert(
+        String::from("crates-io"),
+        toml_table(&[("replace-with", "registmily-local")]),
+    );
+    // dependencies from this registry are written with the index url the publisher used
+    for (i, index) in indexes.iter().enumerate() {
+        sources.insert(
+            format!("registry-{}", i),
+            toml_table(&[("registry", index), ("replace-with", "registmily-local")]),
+        );
+    }
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("source"), toml::Value::Table(sources));
+
+    std::fs::create_dir_all(source.join(".cargo"))?;
+    std::fs::write(
+        source.join(".cargo").join("config.toml"),
+        toml::to_string(&config)?,
+    )?;
+
+    Ok(manifest
+        .get("lib")
+        .and_then(|x| x.get("name"))
+        .and_then(|x| x.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| crate_name.replace('-', "_")))
+}
+
+fn strip_dev_dependencies(table: &mut toml::value::Table, indexes: &mut BTreeSet<String>) {
+    table.remove("dev-dependencies");
+    table.remove("dev_dependencies");
+
+    for key in ["dependencies", "build-dependencies", "build_dependencies"] {
+        if let Some(toml::Value::Table(deps)) = table.get(key) {
+            for dep in deps.values() {
+                if let Some(index) = dep.get("registry-index").and_then(|x| x.as_str()) {
+                    indexes.insert(String::from(index));
+                }
+            }
+        }
+    }
+}
+
+fn toml_table(entries: &[(&str, &str)]) -> toml::Value {
+    toml::Value::Table(
+        entries
+            .iter()
+            .map(|(key, value)| (String::from(*key), toml::Value::from(*value)))
+            .collect(),
+    )
+}
+
+// The library docs if there are any, otherwise whatever rustdoc generated, e.g. for binaries
+fn find_doc_path(doc_root: &Path, lib_name: &str) -> Result<Option<String>, DocsError> {
+    if doc_root.join(lib_name).join("index.html").is_file() {
+        return Ok(Some(format!("{}/index.html", lib_name)));
+    }
+
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(doc_root)? {
+        let entry = entry?;
+        if entry.path().join("index.html").is_file() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+
+    Ok(names.first().map(|x| format!("{}/index.html", x)))
+}
+
+fn list_files(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn index_entries(repo_path: &Path) -> std::io::Result<Vec<IndexEntry>> {
+    let mut entries = Vec::new();
+    for file in list_files(repo_path)? {
+        let relative = file.strip_prefix(repo_path).unwrap_or(&file);
+        if relative.starts_with(".git") || relative == Path::new("config.json") {
+            continue;
+        }
+
+        // lines the registry thread is just writing simply get picked up by the next build
+        for line in std::fs::read_to_string(&file)?.lines() {
+            if let Ok(entry) = serde_json::from_str(line) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn truncate_log(log: String) -> String {
+    if log.len() <= MAX_LOG_LEN {
+        return log;
+    }
+
+    let mut start = log.len() - MAX_LOG_LEN;
+    while !log.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[...]\n{}", &log[start..])
+}
+
+async fn docs_root(
+    extract::Path((crate_name, version)): extract::Path<(String, String)>,
+    pool: Extension<PgPool>,
+    session: web::WebSession,
+) -> Result<Response, ApiError> {
+    docs_page(crate_name, version, pool, session).await
+}
+
+// Sends finished docs to their entry point, everything else gets the build status and log
+async fn docs_page(
+    crate_name: String,
+    version: String,
+    pool: Extension<PgPool>,
+    web::WebSession(session): web::WebSession,
+) -> Result<Response, ApiError> {
+    let mut trans = pool.begin().await?;
+    let build = match models::DocBuild::by_version(&mut trans, &crate_name, &version).await? {
+        Some(build) => build,
+        None => {
+            return Ok((
+                StatusCode::NOT_FOUND,
+                web::page(
+                    "Not found",
+                    session.as_ref(),
+                    "<h1>Not found</h1><p>No docs have been built for this version.</p>",
+                ),
+            )
+                .into_response())
+        }
+    };
+
+    if let Some(doc_path) = &build.doc_path {
+        if let Ok(uri) = format!("/docs/{}/{}/{}", crate_name, version, doc_path).parse::<Uri>() {
+            return Ok(Redirect::to(uri).into_response());
+        }
+    }
+
+    let since = match build.status.as_str() {
+        "queued" => Some(build.queued_at),
+        "building" => build.started_at,
+        _ => build.finished_at,
+    };
+    let body = format!(
+        "<h1>Docs for <a href=\"/crates/{0}/{1}\">{0} {1}</a></h1><p>Build {2}{3}</p><pre>{4}</pre>",
+        web::escape(&crate_name),
+        web::escape(&version),
+        web::escape(&build.status),
+        since
+            .map(|x| format!(" since {}", x.format("%Y-%m-%d %H:%M UTC")))
+            .unwrap_or_default(),
+        web::escape(&build.log)
+    );
+
+    Ok(web::page(&format!("Docs for {}", crate_name), session.as_ref(), &body).into_response())
+}
+
+async fn doc_file(
+    extract::Path((crate_name, version, path)): extract::Path<(String, String, String)>,
+    storage: Extension<Arc<dyn storage::Storage>>,
+    pool: Extension<PgPool>,
+    session: web::WebSession,
+) -> Result<Response, ApiError> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return docs_page(crate_name, version, pool, session).await;
+    }
+
+    // the path ends up on the filesystem for local storage, so nothing may point outside
+    let valid = registry::validate_package_name(&crate_name).is_ok()
+        && semver::Version::parse(&version).is_ok()
+        && !path.contains('\\')
+        && path
+            .split('/')
+            .all(|x| !x.is_empty() && x != "." && x != "..");
+    if !valid {
+        return Ok((StatusCode::NOT_FOUND, "File not found!").into_response());
+    }
+
+    // the html comes from the publisher, without an origin of its own it can't act as the
+    // logged in user
+    let headers = Headers([
+        (header::CONTENT_TYPE, storage::content_type(path)),
+        (header::CONTENT_SECURITY_POLICY, "sandbox allow-scripts"),
+    ]);
+    match storage
+        .get_doc(&format!("{}/{}/{}", crate_name, version, path))
+        .await
+    {
+        Ok(Some(storage::CrateDownload::File(file))) => {
+            Ok((headers, StreamBody::new(ReaderStream::new(file))).into_response())
+        }
+        Ok(Some(storage::CrateDownload::Bytes(bytes))) => Ok((headers, bytes).into_response()),
+        Ok(Some(storage::CrateDownload::Redirect(url))) => Ok((
+            StatusCode::TEMPORARY_REDIRECT,
+            Headers([(header::LOCATION, url)]),
+        )
+            .into_response()),
+        Ok(None) => Ok((StatusCode::NOT_FOUND, "File not found!").into_response()),
+        Err(err) => {
+            error!("Failed to read doc file: {}", err);
+            Ok((StatusCode::INTERNAL_SERVER_ERROR, "Storage error").into_response())
+        }
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/docs/:crate_name/:version", get(docs_root))
+        .route("/docs/:crate_name/:version/*path", get(doc_file))
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use sqlx::types::chrono::{NaiveDate, Utc};
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+use crate::models;
+
+// How long downloads are collected in memory before they get written to the database
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+// What a download is counted for, a checksum only counts if it belongs to a single version
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Download {
+    Version(String, String),
+    Checksum(String),
+}
+
+// Hands downloads over to a background task so serving a crate never waits on the database
+#[derive(Clone)]
+pub struct DownloadCounter {
+    sender: mpsc::Sender<Download>,
+}
+
+impl DownloadCounter {
+    pub fn record(&self, download: Download) {
+        if self.sender.try_send(download).is_err() {
+            warn!("Download counter can't keep up, dropping a download");
+        }
+    }
+}
+
+// The task flushes one last time and exits once every counter has been dropped
+pub fn spawn(pool: PgPool, flush_interval: Duration) -> (DownloadCounter, JoinHandle<()>) {
+    let (sender, recv) = mpsc::channel(u16::MAX as usize);
+    let handle = tokio::spawn(count_downloads(pool, recv, flush_interval));
+
+    (DownloadCounter { sender }, handle)
+}
+
+async fn count_downloads(
+    pool: PgPool,
+    mut recv: mpsc::Receiver<Download>,
+    flush_interval: Duration,
+) {
+    let mut pending = HashMap::new();
+    let mut interval = tokio::time::interval(flush_interval);
+
+    loop {
+        tokio::select! {
+            download = recv.recv() => match download {
+                Some(download) => {
+                    let date = Utc::now().naive_utc().date();
+                    *pending.entry((download, date)).or_default() += 1;
+                }
+                None => break,
+            },
+            _ = interval.tick() => flush(&pool, &mut pending).await,
+        }
+    }
+
+    flush(&pool, &mut pending).await;
+}
+
+// Counts stay pending if writing them fails and get retried with the next flush
+async fn flush(pool: &PgPool, pending: &mut HashMap<(Download, NaiveDate), i64>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let result: models::DbResult<()> = async {
+        let mut trans = pool.begin().await?;
+        for ((download, date), count) in pending.iter() {
+            match download {
+                Download::Version(crate_name, version) => {
+                    models::VersionDownload::add_by_version(
+                        &mut trans, crate_name, version, *date, *count,
+                    )
+                    .await?
+                }
+                Download::Checksum(checksum) => {
+                    models::VersionDownload::add(&mut trans, checksum, *date, *count).await?
+                }
+            }
+        }
+        trans.commit().await
+    }
+    .await;
+
+    match result {
+        Ok(()) => pending.clear(),
+        Err(err) => error!("Failed to write download counts: {}", err),
+    }
+}
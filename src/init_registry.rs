@@ -1,11 +1,12 @@
+use git2::{Repository, RepositoryInitOptions, Signature};
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
-use std::path::Path;
-
-use std::process::Command;
-
-use std::env;
+use serde_json::Value;
 use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use thiserror::Error;
+use tracing::info;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct RegistryIndex {
@@ -13,41 +14,92 @@ struct RegistryIndex {
     pub api: String,
 }
 
-pub fn setup_registry(git_path: &Path, storage_path: &Path, api_url: &str) {
+#[derive(Error, Debug)]
+pub enum InitError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error("invalid config.json in the index: {0}")]
+    InvalidConfig(#[from] serde_json::Error),
+    #[error("config.json in the index is not a json object")]
+    ConfigNotObject,
+}
+
+// Creates the index repo and the storage folder. Running it again on an existing index
+// only updates the urls in config.json, keeping any other keys, and commits if they changed.
+pub fn setup_registry(
+    git_path: &Path,
+    storage_path: &Path,
+    api_url: &str,
+) -> Result<(), InitError> {
+    let api_url = api_url.trim_end_matches('/');
     let index = RegistryIndex {
-        dl: format!("{}/api/v1/dl/{{sha256-checksum}}", api_url),
+        dl: format!("{}/api/v1/crates/{{crate}}/{{version}}/download", api_url),
         api: String::from(api_url),
     };
-    fs::create_dir_all(git_path).unwrap();
-    let old_dir = env::current_dir().unwrap();
-    env::set_current_dir(git_path).unwrap();
-
-    Command::new("git")
-        .arg("init")
-        .arg("-b")
-        .arg("main")
-        .arg(".")
-        .output()
-        .expect("Failed to create repo");
-
-    let json_str = serde_json::to_string(&index).unwrap();
-
-    fs::write("config.json", json_str).unwrap();
-
-    Command::new("git")
-        .arg("add")
-        .arg("config.json")
-        .output()
-        .expect("Failed to add config.json");
-
-    Command::new("git")
-        .arg("commit")
-        .arg("-m")
-        .arg("Initialized registry")
-        .output()
-        .expect("Failed to commit");
-
-    env::set_current_dir(old_dir).unwrap();
-
-    fs::create_dir_all(storage_path).unwrap();
+
+    fs::create_dir_all(git_path)?;
+    let repo = match Repository::open(git_path) {
+        Ok(repo) => repo,
+        Err(_) => {
+            info!("Creating index repo in {}", git_path.display());
+            let mut opts = RepositoryInitOptions::new();
+            opts.initial_head("main");
+            Repository::init_opts(git_path, &opts)?
+        }
+    };
+
+    let mut config_path = git_path.to_path_buf();
+    config_path.push("config.json");
+
+    let mut config = match fs::read_to_string(&config_path) {
+        Ok(content) => match serde_json::from_str(&content)? {
+            Value::Object(config) => config,
+            _ => return Err(InitError::ConfigNotObject),
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => serde_json::Map::new(),
+        Err(err) => return Err(err.into()),
+    };
+    if let Value::Object(urls) = serde_json::to_value(&index)? {
+        config.extend(urls);
+    }
+    fs::write(&config_path, serde_json::to_string(&config)?)?;
+
+    let mut repo_index = repo.index()?;
+    repo_index.add_path(Path::new("config.json"))?;
+    repo_index.write()?;
+    let tree_id = repo_index.write_tree()?;
+
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(_) => None,
+    };
+
+    if parent.as_ref().map(|x| x.tree_id()) != Some(tree_id) {
+        // a fresh server often has no git identity configured yet
+        let sig = repo
+            .signature()
+            .or_else(|_| Signature::now("registmily", "registmily@localhost"))?;
+        let message = if parent.is_some() {
+            "Updated registry config"
+        } else {
+            "Initialized registry"
+        };
+        let parents = parent.iter().collect::<Vec<_>>();
+
+        repo.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            message,
+            &repo.find_tree(tree_id)?,
+            &parents,
+        )?;
+        info!("{} in {}", message, git_path.display());
+    }
+
+    fs::create_dir_all(storage_path)?;
+
+    Ok(())
 }
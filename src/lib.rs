@@ -1,7 +1,19 @@
 pub mod apiresponse;
 pub mod apiserver;
+pub mod cli;
+pub mod crate_file;
+pub mod docs;
+pub mod downloads;
 pub mod init_registry;
+pub mod metrics;
+pub mod mirror;
 pub mod models;
 mod openid;
+pub mod ratelimit;
+pub mod readme;
 pub mod registry;
 pub mod settings;
+pub mod sparse_index;
+pub mod storage;
+mod web;
+pub mod webhooks;
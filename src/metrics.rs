@@ -0,0 +1,273 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Extension, MatchedPath},
+    http::{header, Request, Response, StatusCode},
+    response::{Headers, IntoResponse},
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::PgPool;
+use tower::{Layer, Service};
+use tracing::error;
+
+use crate::{registry, settings};
+
+// Global so the registry thread can report without getting handed anything
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    registry_operations: IntCounterVec,
+    registry_operation_duration: HistogramVec,
+    registry_queue_depth: IntGauge,
+    git_duration: HistogramVec,
+    git_failures: IntCounterVec,
+    db_connections: IntGauge,
+    db_idle_connections: IntGauge,
+    db_max_connections: IntGauge,
+    download_bytes: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("registmily")), None)
+            .expect("the metrics prefix is valid");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Handled http requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling http requests",
+            ),
+            &["method", "route"],
+        )
+        .unwrap();
+        let registry_operations = IntCounterVec::new(
+            Opts::new(
+                "registry_operations_total",
+                "Publishes and yanks done by the registry thread",
+            ),
+            &["operation", "result"],
+        )
+        .unwrap();
+        // publishing uploads the crate file and pushes the index, so it can take a while
+        let registry_operation_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "registry_operation_duration_seconds",
+                "Time the registry thread spent on an operation",
+            )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+            &["operation"],
+        )
+        .unwrap();
+        let registry_queue_depth = IntGauge::new(
+            "registry_queue_depth",
+            "Operations waiting for the registry thread",
+        )
+        .unwrap();
+        let git_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "git_duration_seconds",
+                "Time spent committing and pushing the index",
+            )
+            .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["step"],
+        )
+        .unwrap();
+        let git_failures = IntCounterVec::new(
+            Opts::new("git_failures_total", "Failed index commits and pushes"),
+            &["step"],
+        )
+        .unwrap();
+        let db_connections = IntGauge::new("db_connections", "Open database connections").unwrap();
+        let db_idle_connections =
+            IntGauge::new("db_idle_connections", "Idle database connections").unwrap();
+        let db_max_connections = IntGauge::new(
+            "db_max_connections",
+            "Database connections the pool may open",
+        )
+        .unwrap();
+        let download_bytes = IntCounter::new(
+            "download_bytes_total",
+            "Bytes of crate files served, redirects to S3 not included",
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_request_duration.clone()),
+            Box::new(registry_operations.clone()),
+            Box::new(registry_operation_duration.clone()),
+            Box::new(registry_queue_depth.clone()),
+            Box::new(git_duration.clone()),
+            Box::new(git_failures.clone()),
+            Box::new(db_connections.clone()),
+            Box::new(db_idle_connections.clone()),
+            Box::new(db_max_connections.clone()),
+            Box::new(download_bytes.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            registry_operations,
+            registry_operation_duration,
+            registry_queue_depth,
+            git_duration,
+            git_failures,
+            db_connections,
+            db_idle_connections,
+            db_max_connections,
+            download_bytes,
+        }
+    }
+
+    pub fn record_request(&self, method: &str, route: &str, status: StatusCode, elapsed: Duration) {
+        self.http_requests
+            .with_label_values(&[method, route, status.as_str()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_registry_operation(&self, operation: &str, ok: bool, elapsed: Duration) {
+        let result = if ok { "ok" } else { "error" };
+        self.registry_operations
+            .with_label_values(&[operation, result])
+            .inc();
+        self.registry_operation_duration
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // `step` is either `commit` or `push`
+    pub fn record_git(&self, step: &str, ok: bool, elapsed: Duration) {
+        self.git_duration
+            .with_label_values(&[step])
+            .observe(elapsed.as_secs_f64());
+        if !ok {
+            self.git_failures.with_label_values(&[step]).inc();
+        }
+    }
+
+    pub fn record_download(&self, bytes: u64) {
+        self.download_bytes.inc_by(bytes);
+    }
+
+    // Gauges of the queue and the pool are read right when they're scraped
+    pub fn render(
+        &self,
+        sender: &registry::SyncSender,
+        pool: &PgPool,
+        settings: &settings::Settings,
+    ) -> Result<Vec<u8>, prometheus::Error> {
+        self.registry_queue_depth
+            .set(registry::QUEUE_SIZE.saturating_sub(sender.capacity()) as i64);
+        self.db_connections.set(pool.size() as i64);
+        self.db_idle_connections.set(pool.num_idle() as i64);
+        self.db_max_connections
+            .set(settings.database_connections as i64);
+
+        self.encode()
+    }
+
+    // Prometheus' text format
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+pub async fn serve(
+    sender: Extension<registry::SyncSender>,
+    pool: Extension<PgPool>,
+    settings: Extension<Arc<settings::Settings>>,
+) -> axum::response::Response {
+    match metrics().render(&sender, &pool, &settings) {
+        Ok(buffer) => (
+            Headers([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)]),
+            buffer,
+        )
+            .into_response(),
+        Err(err) => {
+            error!("Failed to render metrics: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Metrics error").into_response()
+        }
+    }
+}
+
+// Counts and times every request by its route, e.g. `/api/v1/crates/:crate_name`, so
+// crate names don't end up in the labels
+#[derive(Clone)]
+pub struct MetricsLayer;
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for MetricsService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let method = req.method().to_string();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|x| String::from(x.as_str()))
+            .unwrap_or_else(|| String::from("unmatched"));
+        let start = Instant::now();
+
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let response = future.await?;
+            metrics().record_request(&method, &route, response.status(), start.elapsed());
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+
+use flate2::read::GzDecoder;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::info;
+
+use crate::crate_file::{CrateFileError, SizeLimit};
+use crate::models;
+use crate::registry::{self, CrateFile, Package};
+
+#[derive(Error, Debug)]
+pub enum MirrorError {
+    #[error("Invalid crate name: {0}")]
+    InvalidName(#[from] registry::CrateNameError),
+    #[error("crate `{0}` is not in the mirror snapshot")]
+    CrateNotFound(String),
+    #[error("version `{1}` of crate `{0}` is not in the mirror snapshot")]
+    VersionNotFound(String, String),
+    #[error("crate file for `{0}` version `{1}` is missing from the mirror snapshot")]
+    MissingCrateFile(String, String),
+    #[error("checksum of `{0}` version `{1}` does not match the snapshot index")]
+    ChecksumMismatch(String, String),
+    #[error("crate `{0}` is owned by local users and can't be mirrored")]
+    LocalCrate(String),
+    #[error("crate `{0}` clashes with the existing crate `{1}`")]
+    NameTaken(String, String),
+    #[error("review for `{0}` version `{1}` is not pending anymore")]
+    NotPending(String, String),
+    #[error("invalid crate file in the mirror snapshot: {0}")]
+    CrateFile(#[from] CrateFileError),
+    #[error("invalid entry in snapshot index: {0}")]
+    InvalidIndex(#[from] serde_json::Error),
+    #[error("failed to add mirrored crate to the index: {0}")]
+    Publish(#[from] registry::PublishError),
+    #[error("registry handler failed: {0}")]
+    Registry(&'static str),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
+
+// A local copy of crates.io, a checkout of its index and a directory with the
+// `.crate` files named `<name>-<version>.crate`, so mirroring works without network.
+pub struct Snapshot {
+    index_path: String,
+    crates_path: PathBuf,
+}
+
+// All files of an unpacked crate, keyed by their path inside the `<name>-<version>/` folder
+pub type CrateSources = BTreeMap<String, Vec<u8>>;
+
+#[derive(Debug)]
+pub struct FileDiff {
+    pub path: String,
+    pub change: models::FileChange,
+    pub diff: String,
+}
+
+impl Snapshot {
+    pub fn new(index_path: &str, crates_path: &str) -> Self {
+        Self {
+            index_path: String::from(index_path),
+            crates_path: PathBuf::from(crates_path),
+        }
+    }
+
+    pub async fn versions(&self, name: &str) -> Result<Vec<Package>, MirrorError> {
+        registry::validate_package_name(name)?;
+
+        // the crates.io index only uses lowercase file names
+        let path = registry::get_package_git_path(&self.index_path, &name.to_lowercase());
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(MirrorError::CrateNotFound(String::from(name)))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(parse_index_line)
+            .collect()
+    }
+
+    pub async fn crate_file(&self, pkg: &Package) -> Result<CrateFile, MirrorError> {
+        let mut path = self.crates_path.clone();
+        path.push(format!("{}-{}.crate", pkg.name, pkg.vers));
+
+        let crate_file = match tokio::fs::read(&path).await {
+            Ok(crate_file) => crate_file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(MirrorError::MissingCrateFile(
+                    pkg.name.clone(),
+                    pkg.vers.clone(),
+                ))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if hex::encode(Sha256::digest(&crate_file)) != pkg.cksum {
+            return Err(MirrorError::ChecksumMismatch(
+                pkg.name.clone(),
+                pkg.vers.clone(),
+            ));
+        }
+
+        Ok(crate_file)
+    }
+}
+
+// crates.io moves features using the `dep:` and `?` syntax into `features2`,
+// our index keeps them in `features` just like for crates published to us.
+pub fn parse_index_line(line: &str) -> Result<Package, MirrorError> {
+    let mut entry: Value = serde_json::from_str(line)?;
+
+    if let Some(Value::Object(features2)) = entry.get_mut("features2").map(Value::take) {
+        if let Some(Value::Object(features)) = entry.get_mut("features") {
+            features.extend(features2);
+        }
+    }
+
+    Ok(serde_json::from_value(entry)?)
+}
+
+fn select_versions(
+    all_versions: &[Package],
+    name: &str,
+    versions: Option<&[String]>,
+) -> Result<Vec<Package>, MirrorError> {
+    match versions {
+        Some(versions) => versions
+            .iter()
+            .map(|vers| {
+                semver::Version::parse(vers)
+                    .ok()
+                    .and_then(|version| {
+                        all_versions
+                            .iter()
+                            .find(|x| registry::is_same_version(&x.vers, &version))
+                    })
+                    .cloned()
+                    .ok_or_else(|| MirrorError::VersionNotFound(String::from(name), vers.clone()))
+            })
+            .collect(),
+        None => Ok(all_versions.iter().filter(|x| !x.yanked).cloned().collect()),
+    }
+}
+
+pub fn unpack_crate(
+    crate_file: &[u8],
+    max_unpacked_size: u64,
+) -> Result<CrateSources, MirrorError> {
+    let mut reader = SizeLimit::new(GzDecoder::new(crate_file), max_unpacked_size);
+    let sources = read_sources(tar::Archive::new(&mut reader));
+    if reader.exceeded() {
+        return Err(CrateFileError::TooLarge(max_unpacked_size).into());
+    }
+    sources
+}
+
+fn read_sources<R: Read>(mut archive: tar::Archive<R>) -> Result<CrateSources, MirrorError> {
+    let mut sources = CrateSources::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry
+            .path()?
+            .components()
+            .skip(1)
+            .map(|x| x.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        sources.insert(path, content);
+    }
+
+    Ok(sources)
+}
+
+pub fn diff_crates(old: &CrateSources, new: &CrateSources) -> Vec<FileDiff> {
+    let empty = Vec::new();
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (change, old_content, new_content) = match (old.get(path), new.get(path)) {
+                (None, Some(new_content)) => (models::FileChange::Added, &empty, new_content),
+                (Some(old_content), None) => (models::FileChange::Removed, old_content, &empty),
+                (Some(old_content), Some(new_content)) if old_content != new_content => {
+                    (models::FileChange::Modified, old_content, new_content)
+                }
+                _ => return None,
+            };
+
+            Some(FileDiff {
+                path: path.clone(),
+                change,
+                diff: diff_file(path, old_content, new_content),
+            })
+        })
+        .collect()
+}
+
+fn diff_file(path: &str, old: &[u8], new: &[u8]) -> String {
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(&format!("a/{}", path), &format!("b/{}", path))
+            .to_string(),
+        _ => format!("Binary files a/{0} and b/{0} differ\n", path),
+    }
+}
+
+// Creates a pending review for every selected version, diffed against the highest
+// approved version below it. Nothing reaches the index until the review is approved.
+pub async fn propose(
+    snapshot: &Snapshot,
+    name: &str,
+    versions: Option<&[String]>,
+    proposed_by: &str,
+    max_unpacked_size: u64,
+    pool: &PgPool,
+) -> Result<Vec<models::MirrorReview>, MirrorError> {
+    let all_versions = snapshot.versions(name).await?;
+    let selected = select_versions(&all_versions, name, versions)?;
+
+    let mut trans = pool.begin().await?;
+    let mut reviews = Vec::new();
+
+    for pkg in selected {
+        if models::MirrorReview::open_exists(&mut trans, &pkg.name, &pkg.vers).await? {
+            continue;
+        }
+
+        let version = semver::Version::parse(&pkg.vers)
+            .map_err(|_| registry::PublishError::InvalidVersion(pkg.vers.clone()))?;
+        let base_version = models::MirrorReview::approved_versions(&mut trans, &pkg.name)
+            .await?
+            .iter()
+            .filter_map(|x| semver::Version::parse(x).ok())
+            .filter(|x| *x < version)
+            .max();
+        let base_pkg = base_version.and_then(|base_version| {
+            all_versions
+                .iter()
+                .find(|x| registry::is_same_version(&x.vers, &base_version))
+        });
+
+        let old_sources = match base_pkg {
+            Some(base_pkg) => {
+                unpack_crate(&snapshot.crate_file(base_pkg).await?, max_unpacked_size)?
+            }
+            None => CrateSources::new(),
+        };
+        let new_sources = unpack_crate(&snapshot.crate_file(&pkg).await?, max_unpacked_size)?;
+
+        let review = models::MirrorReview::new(
+            &mut trans,
+            &pkg.name,
+            &pkg.vers,
+            base_pkg.map(|x| x.vers.as_str()),
+            proposed_by,
+        )
+        .await?;
+
+        for file in diff_crates(&old_sources, &new_sources) {
+            models::MirrorReviewFile::new(
+                &mut trans,
+                review.id,
+                &file.path,
+                file.change,
+                &file.diff,
+            )
+            .await?;
+        }
+
+        info!(
+            "{} proposed mirroring {} {}",
+            proposed_by, pkg.name, pkg.vers
+        );
+        reviews.push(review);
+    }
+
+    trans.commit().await?;
+
+    Ok(reviews)
+}
+
+// Publishes the reviewed version with the checksum crates.io has in its index.
+pub async fn approve(
+    snapshot: &Snapshot,
+    review: &models::MirrorReview,
+    reviewer: &models::AuditActor<'_>,
+    sender: &registry::SyncSender,
+    pool: &PgPool,
+) -> Result<(), MirrorError> {
+    let pkg = snapshot
+        .versions(&review.crate_name)
+        .await?
+        .into_iter()
+        .find(|x| x.vers == review.version)
+        .ok_or_else(|| {
+            MirrorError::VersionNotFound(review.crate_name.clone(), review.version.clone())
+        })?;
+    let crate_file = snapshot.crate_file(&pkg).await?;
+
+    let mut trans = pool.begin().await?;
+
+    // the review may have been approved or rejected since it was read
+    match models::MirrorReview::by_id_for_update(&mut trans, review.id).await? {
+        Some(locked) if locked.status == models::ReviewStatus::Pending.as_str() => {}
+        _ => {
+            return Err(MirrorError::NotPending(
+                review.crate_name.clone(),
+                review.version.clone(),
+            ))
+        }
+    }
+
+    // mirrored crates exist without owners, so nobody can publish over them
+    if let Some(existing) = models::Crate::by_canonical_name(&mut trans, &pkg.name).await? {
+        if existing.name != pkg.name {
+            return Err(MirrorError::NameTaken(pkg.name, existing.name));
+        }
+        if !models::CrateOwner::all_owners(&mut trans, &pkg.name)
+            .await?
+            .is_empty()
+            || !models::CrateTeamOwner::all_owners(&mut trans, &pkg.name)
+                .await?
+                .is_empty()
+        {
+            return Err(MirrorError::LocalCrate(pkg.name));
+        }
+    } else {
+        models::Crate::new(&mut trans, &pkg.name).await?;
+    }
+
+    let version = semver::Version::parse(&pkg.vers)
+        .map_err(|_| registry::PublishError::InvalidVersion(pkg.vers.clone()))?;
+    let is_newest = match models::Crate::by_name(&mut trans, &pkg.name)
+        .await?
+        .and_then(|x| x.max_version)
+    {
+        Some(max_version) => match semver::Version::parse(&max_version) {
+            Ok(max_version) => version > max_version,
+            Err(_) => true,
+        },
+        None => true,
+    };
+
+    if is_newest {
+        models::Crate::set_newest_version(&mut trans, &pkg.name, &pkg.vers, None).await?;
+    }
+
+    models::MirrorReview::set_status(
+        &mut trans,
+        review.id,
+        models::ReviewStatus::Approved,
+        reviewer.ident,
+    )
+    .await?;
+
+    if models::CrateVersion::exists(&mut trans, &pkg.name, &pkg.vers).await? {
+        return Err(registry::PublishError::VersionExists(pkg.vers).into());
+    }
+    // the index entry is all we know about a mirrored version, the reviewer didn't publish it
+    let metadata = registry::PublishPackage::from(pkg.clone());
+    models::CrateVersion::new(&mut trans, &metadata, &pkg.cksum, None).await?;
+    models::AuditEvent::record(
+        &mut trans,
+        reviewer,
+        models::AuditAction::MirrorApprove,
+        &pkg.name,
+        Some(&pkg.vers),
+        serde_json::json!({ "checksum": pkg.cksum, "review_id": review.id }),
+    )
+    .await?;
+
+    let crate_name = pkg.name.clone();
+    match registry::run_task(
+        registry::Operation::Publish(pkg, crate_file),
+        axum::extract::Extension(sender.clone()),
+    )
+    .await
+    .map_err(MirrorError::Registry)?
+    {
+        registry::RegistryResponse::Publish(Ok(())) => {}
+        registry::RegistryResponse::Publish(Err(why)) => return Err(why.into()),
+        _ => unreachable!("o no"),
+    }
+
+    trans.commit().await?;
+
+    info!(
+        "{} approved mirroring {} {}",
+        reviewer.ident, crate_name, review.version
+    );
+
+    Ok(())
+}
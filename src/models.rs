@@ -1,9 +1,24 @@
 pub type DbResult<T> = Result<T, sqlx::Error>;
 pub type PgTransaction<'c> = sqlx::Transaction<'c, sqlx::Postgres>;
 
+mod audit;
+mod docs;
+mod invitations;
+mod mirror;
 mod registry;
+mod teams;
+mod webhooks;
 
-pub use registry::{Crate, CrateOwner, User, UserSession};
+pub use audit::{AuditAction, AuditActor, AuditEvent, AuditFilter};
+pub use docs::{DocBuild, DocBuildStatus};
+pub use invitations::OwnerInvitation;
+pub use mirror::{FileChange, MirrorReview, MirrorReviewFile, ReviewStatus};
+pub use registry::{
+    Crate, CrateOwner, CrateVersion, TokenScope, User, UserSession, VersionDownload,
+    VersionDownloadTotal, VersionReadme,
+};
+pub use teams::{CrateTeamOwner, Team, TeamMember, TEAM_PREFIX};
+pub use webhooks::{DueWebhookDelivery, Webhook, WebhookDelivery, WebhookDeliveryStatus};
 
 #[derive(Debug, sqlx::FromRow)]
 struct Exists {
@@ -15,3 +30,14 @@ impl From<Exists> for bool {
         e.exists.unwrap_or_default()
     }
 }
+
+#[derive(Debug, sqlx::FromRow)]
+struct Count {
+    count: Option<i64>,
+}
+
+impl From<Count> for i64 {
+    fn from(c: Count) -> Self {
+        c.count.unwrap_or_default()
+    }
+}
@@ -0,0 +1,172 @@
+#![allow(unused)]
+
+use super::{DbResult, PgTransaction};
+use sqlx::types::chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Publish,
+    Yank,
+    Unyank,
+    InviteOwner,
+    AddOwner,
+    RemoveOwner,
+    Delete,
+    MirrorApprove,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Publish => "publish",
+            AuditAction::Yank => "yank",
+            AuditAction::Unyank => "unyank",
+            AuditAction::InviteOwner => "invite_owner",
+            AuditAction::AddOwner => "add_owner",
+            AuditAction::RemoveOwner => "remove_owner",
+            AuditAction::Delete => "delete",
+            AuditAction::MirrorApprove => "mirror_approve",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "publish" => Some(AuditAction::Publish),
+            "yank" => Some(AuditAction::Yank),
+            "unyank" => Some(AuditAction::Unyank),
+            "invite_owner" => Some(AuditAction::InviteOwner),
+            "add_owner" => Some(AuditAction::AddOwner),
+            "remove_owner" => Some(AuditAction::RemoveOwner),
+            "delete" => Some(AuditAction::Delete),
+            "mirror_approve" => Some(AuditAction::MirrorApprove),
+            _ => None,
+        }
+    }
+}
+
+// Who is behind a change, the token and request are missing for changes made with the cli
+#[derive(Debug, Clone, Copy)]
+pub struct AuditActor<'a> {
+    pub ident: &'a str,
+    pub token_id: Option<i64>,
+    pub token_name: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+impl AuditActor<'static> {
+    pub fn cli() -> Self {
+        Self {
+            ident: "cli",
+            token_id: None,
+            token_name: None,
+            request_id: None,
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub action: String,
+    pub actor: String,
+    pub crate_name: String,
+    pub version: Option<String>,
+    pub token_id: Option<i64>,
+    pub token_name: Option<String>,
+    pub request_id: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AuditFilter {
+    pub crate_name: Option<String>,
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl AuditEvent {
+    pub async fn record(
+        transaction: &mut PgTransaction<'_>,
+        actor: &AuditActor<'_>,
+        action: AuditAction,
+        crate_name: &str,
+        version: Option<&str>,
+        details: serde_json::Value,
+    ) -> DbResult<Self> {
+        let event = sqlx::query_as!(
+            Self,
+            "INSERT INTO audit_events \
+            (action, actor, crate_name, version, token_id, token_name, request_id, details) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+            RETURNING id, action, actor, crate_name, version, token_id, token_name, request_id, details, created_at",
+            action.as_str(),
+            actor.ident,
+            crate_name,
+            version,
+            actor.token_id,
+            actor.token_name,
+            actor.request_id,
+            details
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        // every audited change is announced to the webhooks that want it
+        super::WebhookDelivery::queue(transaction, &event).await?;
+
+        Ok(event)
+    }
+
+    // Newest first, `before_id` is the id of the last event on the previous page
+    pub async fn search(
+        transaction: &mut PgTransaction<'_>,
+        filter: &AuditFilter,
+        before_id: Option<i64>,
+        limit: i64,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, action, actor, crate_name, version, token_id, token_name, request_id, details, created_at \
+            FROM audit_events \
+            WHERE ($1::TEXT IS NULL OR crate_name = $1) AND ($2::TEXT IS NULL OR actor = $2) \
+            AND ($3::TEXT IS NULL OR action = $3) AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4) \
+            AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5) AND ($6::BIGINT IS NULL OR id < $6) \
+            ORDER BY id DESC LIMIT $7",
+            filter.crate_name.as_deref(),
+            filter.actor.as_deref(),
+            filter.action.map(|x| x.as_str()),
+            filter.since,
+            filter.until,
+            before_id,
+            limit
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    // Oldest first, for handing the whole log to someone else
+    pub async fn export(
+        transaction: &mut PgTransaction<'_>,
+        filter: &AuditFilter,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, action, actor, crate_name, version, token_id, token_name, request_id, details, created_at \
+            FROM audit_events \
+            WHERE ($1::TEXT IS NULL OR crate_name = $1) AND ($2::TEXT IS NULL OR actor = $2) \
+            AND ($3::TEXT IS NULL OR action = $3) AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4) \
+            AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5) \
+            ORDER BY id",
+            filter.crate_name.as_deref(),
+            filter.actor.as_deref(),
+            filter.action.map(|x| x.as_str()),
+            filter.since,
+            filter.until
+        )
+        .fetch_all(transaction)
+        .await
+    }
+}
@@ -0,0 +1,130 @@
+#![allow(unused)]
+
+use std::time::Duration;
+
+use super::{DbResult, PgTransaction};
+use sqlx::types::chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocBuildStatus {
+    Queued,
+    Building,
+    Succeeded,
+    Failed,
+}
+
+impl DocBuildStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DocBuildStatus::Queued => "queued",
+            DocBuildStatus::Building => "building",
+            DocBuildStatus::Succeeded => "succeeded",
+            DocBuildStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct DocBuild {
+    pub version_id: i64,
+    pub crate_name: String,
+    pub version: String,
+    pub checksum: String,
+    pub status: String,
+    pub log: String,
+    pub doc_path: Option<String>,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl DocBuild {
+    pub async fn queue(transaction: &mut PgTransaction<'_>, version_id: i64) -> DbResult<()> {
+        sqlx::query!(
+            "INSERT INTO doc_builds (version_id) VALUES ($1) \
+            ON CONFLICT (version_id) DO UPDATE \
+            SET status = 'queued', log = '', doc_path = NULL, queued_at = now(), started_at = NULL, finished_at = NULL",
+            version_id
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    // Takes the oldest queued build and marks it as building, several builders won't
+    // ever pick the same one
+    pub async fn start_next(transaction: &mut PgTransaction<'_>) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "UPDATE doc_builds SET status = 'building', started_at = now() \
+            FROM crate_versions \
+            WHERE doc_builds.version_id = ( \
+                SELECT version_id FROM doc_builds WHERE status = 'queued' \
+                ORDER BY queued_at LIMIT 1 FOR UPDATE SKIP LOCKED \
+            ) AND crate_versions.id = doc_builds.version_id \
+            RETURNING doc_builds.version_id, crate_versions.crate_name, crate_versions.version, \
+            crate_versions.checksum, doc_builds.status, doc_builds.log, doc_builds.doc_path, \
+            doc_builds.queued_at, doc_builds.started_at, doc_builds.finished_at"
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    // Builds that were running when their builder went down never finish otherwise. Only
+    // ones running for longer than `timeout` are requeued, the rest might still be building.
+    pub async fn requeue_unfinished(
+        transaction: &mut PgTransaction<'_>,
+        timeout: Duration,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE doc_builds SET status = 'queued', started_at = NULL \
+            WHERE status = 'building' AND started_at <= now() - make_interval(secs => $1)",
+            timeout.as_secs_f64()
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn finish(
+        transaction: &mut PgTransaction<'_>,
+        version_id: i64,
+        status: DocBuildStatus,
+        log: &str,
+        doc_path: Option<&str>,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE doc_builds SET status = $2, log = $3, doc_path = $4, finished_at = now() \
+            WHERE version_id = $1",
+            version_id,
+            status.as_str(),
+            log,
+            doc_path
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn by_version(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT doc_builds.version_id, crate_versions.crate_name, crate_versions.version, \
+            crate_versions.checksum, doc_builds.status, doc_builds.log, doc_builds.doc_path, \
+            doc_builds.queued_at, doc_builds.started_at, doc_builds.finished_at \
+            FROM doc_builds JOIN crate_versions ON crate_versions.id = doc_builds.version_id \
+            WHERE crate_versions.crate_name = $1 AND crate_versions.version = $2",
+            crate_name,
+            version
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+}
@@ -0,0 +1,105 @@
+#![allow(unused)]
+
+use super::{DbResult, PgTransaction};
+use sqlx::types::chrono::{DateTime, Utc};
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct OwnerInvitation {
+    pub id: i64,
+    pub crate_name: String,
+    pub invitee_ident: String,
+    pub inviter_ident: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OwnerInvitation {
+    // Inviting someone again replaces their old invitation, expired or not
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        invitee_ident: &str,
+        inviter_ident: &str,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<Self> {
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO crate_owner_invitations (crate_name, invitee_ident, inviter_ident, expires_at) \
+            VALUES ($1, $2, $3, $4) \
+            ON CONFLICT (crate_name, invitee_ident) DO UPDATE \
+            SET inviter_ident = $3, created_at = now(), expires_at = $4 \
+            RETURNING id, crate_name, invitee_ident, inviter_ident, created_at, expires_at",
+            crate_name,
+            invitee_ident,
+            inviter_ident,
+            expires_at
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    // Expired invitations are treated as if they didn't exist
+    pub async fn pending(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        invitee_ident: &str,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, invitee_ident, inviter_ident, created_at, expires_at \
+            FROM crate_owner_invitations \
+            WHERE crate_name = $1 AND invitee_ident = $2 AND expires_at > now()",
+            crate_name,
+            invitee_ident
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn all_by_invitee(
+        transaction: &mut PgTransaction<'_>,
+        invitee_ident: &str,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, invitee_ident, inviter_ident, created_at, expires_at \
+            FROM crate_owner_invitations \
+            WHERE invitee_ident = $1 AND expires_at > now() ORDER BY created_at",
+            invitee_ident
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    // returns whether there was an invitation, expired ones included
+    pub async fn delete(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        invitee_ident: &str,
+    ) -> DbResult<bool> {
+        sqlx::query!(
+            "DELETE FROM crate_owner_invitations WHERE crate_name = $1 AND invitee_ident = $2",
+            crate_name,
+            invitee_ident
+        )
+        .execute(transaction)
+        .await
+        .map(|x| x.rows_affected() > 0)
+    }
+
+    // Invitations are only as good as the owner who sent them
+    pub async fn delete_by_inviter(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        inviter_ident: &str,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "DELETE FROM crate_owner_invitations WHERE crate_name = $1 AND inviter_ident = $2",
+            crate_name,
+            inviter_ident
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+}
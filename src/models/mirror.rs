@@ -0,0 +1,205 @@
+#![allow(unused)]
+
+use super::{DbResult, Exists, PgTransaction};
+use sqlx::types::chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl ReviewStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReviewStatus::Pending => "pending",
+            ReviewStatus::Approved => "approved",
+            ReviewStatus::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl FileChange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileChange::Added => "added",
+            FileChange::Removed => "removed",
+            FileChange::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct MirrorReview {
+    pub id: i64,
+    pub crate_name: String,
+    pub version: String,
+    pub base_version: Option<String>,
+    pub status: String,
+    pub proposed_by: String,
+    pub reviewed_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub reviewed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct MirrorReviewFile {
+    pub review_id: i64,
+    pub path: String,
+    pub change: String,
+    pub diff: String,
+}
+
+impl MirrorReview {
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+        base_version: Option<&str>,
+        proposed_by: &str,
+    ) -> DbResult<Self> {
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO mirror_reviews (crate_name, version, base_version, proposed_by) \
+            VALUES ($1, $2, $3, $4) \
+            RETURNING id, crate_name, version, base_version, status, proposed_by, reviewed_by, created_at, reviewed_at",
+            crate_name,
+            version,
+            base_version,
+            proposed_by
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    pub async fn by_id(transaction: &mut PgTransaction<'_>, id: i64) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, version, base_version, status, proposed_by, reviewed_by, created_at, reviewed_at \
+            FROM mirror_reviews WHERE id = $1",
+            id
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    // Locks the review until the transaction ends, so it's only reviewed once
+    pub async fn by_id_for_update(
+        transaction: &mut PgTransaction<'_>,
+        id: i64,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, version, base_version, status, proposed_by, reviewed_by, created_at, reviewed_at \
+            FROM mirror_reviews WHERE id = $1 FOR UPDATE",
+            id
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn all_by_status(
+        transaction: &mut PgTransaction<'_>,
+        status: ReviewStatus,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, version, base_version, status, proposed_by, reviewed_by, created_at, reviewed_at \
+            FROM mirror_reviews WHERE status = $1 ORDER BY id",
+            status.as_str()
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    pub async fn open_exists(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS(SELECT 1 FROM mirror_reviews WHERE crate_name = $1 AND version = $2 AND status <> 'rejected')",
+            crate_name,
+            version
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
+    pub async fn approved_versions(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+    ) -> DbResult<Vec<String>> {
+        Ok(sqlx::query!(
+            "SELECT version FROM mirror_reviews WHERE crate_name = $1 AND status = 'approved'",
+            crate_name
+        )
+        .fetch_all(transaction)
+        .await?
+        .into_iter()
+        .map(|x| x.version)
+        .collect())
+    }
+
+    pub async fn set_status(
+        transaction: &mut PgTransaction<'_>,
+        id: i64,
+        status: ReviewStatus,
+        reviewed_by: &str,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE mirror_reviews SET status = $2, reviewed_by = $3, reviewed_at = now() WHERE id = $1",
+            id,
+            status.as_str(),
+            reviewed_by
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+}
+
+impl MirrorReviewFile {
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        review_id: i64,
+        path: &str,
+        change: FileChange,
+        diff: &str,
+    ) -> DbResult<Self> {
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO mirror_review_files (review_id, path, change, diff) VALUES ($1, $2, $3, $4) \
+            RETURNING review_id, path, change, diff",
+            review_id,
+            path,
+            change.as_str(),
+            diff
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    pub async fn all_by_review(
+        transaction: &mut PgTransaction<'_>,
+        review_id: i64,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT review_id, path, change, diff FROM mirror_review_files WHERE review_id = $1 ORDER BY path",
+            review_id
+        )
+        .fetch_all(transaction)
+        .await
+    }
+}
@@ -1,23 +1,109 @@
 #![allow(unused)]
 
-use super::{DbResult, Exists, PgTransaction};
+use super::{Count, DbResult, Exists, PgTransaction};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use sqlx::types::chrono::{DateTime, NaiveDate, Utc};
+
+use crate::registry;
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct User {
     pub ident: String,
+    pub disabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+    PublishNew,
+    PublishUpdate,
+    Yank,
+    ChangeOwners,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::PublishNew => "publish-new",
+            TokenScope::PublishUpdate => "publish-update",
+            TokenScope::Yank => "yank",
+            TokenScope::ChangeOwners => "change-owners",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "publish-new" => Some(TokenScope::PublishNew),
+            "publish-update" => Some(TokenScope::PublishUpdate),
+            "yank" => Some(TokenScope::Yank),
+            "change-owners" => Some(TokenScope::ChangeOwners),
+            _ => None,
+        }
+    }
 }
 
+// Both the sessions created on login and the api tokens users create themselves,
+// only the latter are limited by scopes, crate patterns and an expiry date.
 #[derive(Debug, sqlx::FromRow)]
 pub struct UserSession {
+    pub id: i64,
     pub ident: String,
-    pub token: String,
+    pub token_hash: String,
+    pub token_prefix: String,
+    pub name: String,
+    pub scopes: Option<Vec<String>>,
+    pub crate_patterns: Option<Vec<String>>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct Crate {
     pub name: String,
+    pub max_version: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct CrateVersion {
+    pub id: i64,
+    pub crate_name: String,
+    pub version: String,
+    pub checksum: String,
+    pub published_by: Option<String>,
+    pub published_at: DateTime<Utc>,
+    pub yanked: bool,
+    pub description: Option<String>,
+    pub license: Option<String>,
+    pub repository: Option<String>,
+    pub keywords: Vec<String>,
+    pub categories: Vec<String>,
+    pub rust_version: Option<String>,
+    pub features: serde_json::Value,
+    pub dependencies: serde_json::Value,
+}
+
+// Kept out of CrateVersion since it can get big and only the readme page needs it
+#[derive(Debug, sqlx::FromRow)]
+pub struct VersionReadme {
+    pub readme: Option<String>,
+    pub readme_file: Option<String>,
+    pub repository: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct VersionDownload {
+    pub version_id: i64,
+    pub date: NaiveDate,
+    pub downloads: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct VersionDownloadTotal {
+    pub version_id: i64,
+    pub downloads: i64,
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -44,13 +130,47 @@ impl User {
     pub async fn new(transaction: &mut PgTransaction<'_>, ident: &str) -> DbResult<Self> {
         sqlx::query_as!(
             Self,
-            "INSERT INTO users (ident) VALUES ($1) RETURNING ident",
+            "INSERT INTO users (ident) VALUES ($1) RETURNING ident, disabled",
             ident
         )
         .fetch_one(transaction)
         .await
     }
 
+    pub async fn by_ident(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT ident, disabled FROM users WHERE ident = $1",
+            ident
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn all(transaction: &mut PgTransaction<'_>) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(Self, "SELECT ident, disabled FROM users ORDER BY ident")
+            .fetch_all(transaction)
+            .await
+    }
+
+    pub async fn set_disabled(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+        disabled: bool,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE users SET disabled = $2 WHERE ident = $1",
+            ident,
+            disabled
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+
     pub async fn delete(transaction: &mut PgTransaction<'_>, ident: &str) -> DbResult<()> {
         sqlx::query!("DELETE FROM users WHERE ident = $1", ident)
             .execute(transaction)
@@ -59,6 +179,21 @@ impl User {
     }
 }
 
+const TOKEN_PREFIX_LENGTH: usize = 8;
+
+fn generate_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(60)
+        .map(char::from)
+        .collect()
+}
+
+// Tokens are random enough that an unsalted hash is fine, it only has to be one-way
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
 impl UserSession {
     pub async fn by_token(
         transaction: &mut PgTransaction<'_>,
@@ -66,18 +201,52 @@ impl UserSession {
     ) -> DbResult<Option<Self>> {
         sqlx::query_as!(
             Self,
-            "SELECT ident, token FROM user_sessions WHERE token = $1",
-            token
+            "SELECT id, ident, token_hash, token_prefix, name, scopes, crate_patterns, created_at, expires_at, last_used_at \
+            FROM user_sessions WHERE token_hash = $1",
+            hash_token(token)
         )
         .fetch_optional(transaction)
         .await
     }
 
+    pub async fn all_by_ident(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, ident, token_hash, token_prefix, name, scopes, crate_patterns, created_at, expires_at, last_used_at \
+            FROM user_sessions WHERE ident = $1 ORDER BY id",
+            ident
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    pub async fn exists_by_name(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+        name: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS(SELECT 1 FROM user_sessions WHERE ident = $1 AND name = $2)",
+            ident,
+            name
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
     pub async fn delete_by_token(transaction: &mut PgTransaction<'_>, token: &str) -> DbResult<()> {
-        sqlx::query!("DELETE FROM user_sessions WHERE token = $1", token)
-            .execute(transaction)
-            .await
-            .map(|_| ())
+        sqlx::query!(
+            "DELETE FROM user_sessions WHERE token_hash = $1",
+            hash_token(token)
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
     }
 
     pub async fn delete_by_ident(transaction: &mut PgTransaction<'_>, ident: &str) -> DbResult<()> {
@@ -87,21 +256,109 @@ impl UserSession {
             .map(|_| ())
     }
 
-    pub async fn new(transaction: &mut PgTransaction<'_>, ident: &str) -> DbResult<Self> {
-        let token: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(60)
-            .map(char::from)
-            .collect();
+    // returns whether the user had a token with that id
+    pub async fn delete_by_id(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+        id: i64,
+    ) -> DbResult<bool> {
+        sqlx::query!(
+            "DELETE FROM user_sessions WHERE id = $1 AND ident = $2",
+            id,
+            ident
+        )
+        .execute(transaction)
+        .await
+        .map(|x| x.rows_affected() > 0)
+    }
 
-        sqlx::query_as!(
+    pub async fn touch(transaction: &mut PgTransaction<'_>, id: i64) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE user_sessions SET last_used_at = now() WHERE id = $1",
+            id
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+
+    // The plaintext token is only ever returned here, the database just knows its hash
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<(Self, String)> {
+        let token = generate_token();
+
+        let session = sqlx::query_as!(
             Self,
-            "INSERT INTO user_sessions (ident, token) VALUES ($1, $2) RETURNING ident, token",
+            "INSERT INTO user_sessions (ident, token_hash, token_prefix, expires_at) VALUES ($1, $2, $3, $4) \
+            RETURNING id, ident, token_hash, token_prefix, name, scopes, crate_patterns, created_at, expires_at, last_used_at",
             ident,
-            token
+            hash_token(&token),
+            &token[..TOKEN_PREFIX_LENGTH],
+            expires_at
         )
         .fetch_one(transaction)
-        .await
+        .await?;
+
+        Ok((session, token))
+    }
+
+    pub async fn new_api_token(
+        transaction: &mut PgTransaction<'_>,
+        ident: &str,
+        name: &str,
+        // without scopes and crate patterns the token can do everything a login can
+        scopes: Option<&[TokenScope]>,
+        crate_patterns: Option<&[String]>,
+        expires_at: DateTime<Utc>,
+    ) -> DbResult<(Self, String)> {
+        let token = generate_token();
+        let scopes: Option<Vec<String>> =
+            scopes.map(|x| x.iter().map(|x| String::from(x.as_str())).collect());
+
+        let session = sqlx::query_as!(
+            Self,
+            "INSERT INTO user_sessions (ident, token_hash, token_prefix, name, scopes, crate_patterns, expires_at) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7) \
+            RETURNING id, ident, token_hash, token_prefix, name, scopes, crate_patterns, created_at, expires_at, last_used_at",
+            ident,
+            hash_token(&token),
+            &token[..TOKEN_PREFIX_LENGTH],
+            name,
+            scopes.as_deref(),
+            crate_patterns,
+            expires_at
+        )
+        .fetch_one(transaction)
+        .await?;
+
+        Ok((session, token))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map(|x| x <= Utc::now()).unwrap_or(false)
+    }
+
+    // login sessions aren't limited to any scopes and can manage the user's tokens
+    pub fn has_full_access(&self) -> bool {
+        self.scopes.is_none() && self.crate_patterns.is_none()
+    }
+
+    pub fn allows(&self, scope: TokenScope, crate_name: &str) -> bool {
+        let scope_allowed = match &self.scopes {
+            Some(scopes) => scopes.iter().any(|x| x == scope.as_str()),
+            None => true,
+        };
+        let crate_allowed = match &self.crate_patterns {
+            Some(patterns) => patterns
+                .iter()
+                .any(|x| registry::crate_pattern_matches(x, crate_name)),
+            None => true,
+        };
+
+        scope_allowed && crate_allowed
     }
 }
 
@@ -120,16 +377,91 @@ impl Crate {
         .into())
     }
 
+    // The crate `name` clashes with, matching case insensitively and with `-` and `_` the same
+    pub async fn by_canonical_name(
+        transaction: &mut PgTransaction<'_>,
+        name: &str,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT name, max_version, description FROM crates WHERE lower(replace(name, '-', '_')) = $1",
+            registry::canonical_crate_name(name)
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
     pub async fn new(transaction: &mut PgTransaction<'_>, name: &str) -> DbResult<Self> {
         sqlx::query_as!(
             Self,
-            "INSERT INTO crates (name) VALUES ($1) RETURNING name",
+            "INSERT INTO crates (name) VALUES ($1) RETURNING name, max_version, description",
             name
         )
         .fetch_one(transaction)
         .await
     }
 
+    pub async fn by_name(
+        transaction: &mut PgTransaction<'_>,
+        name: &str,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT name, max_version, description FROM crates WHERE name = $1",
+            name
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn set_newest_version(
+        transaction: &mut PgTransaction<'_>,
+        name: &str,
+        max_version: &str,
+        description: Option<&str>,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE crates SET max_version = $2, description = $3 WHERE name = $1",
+            name,
+            max_version,
+            description
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn search(
+        transaction: &mut PgTransaction<'_>,
+        query: &str,
+        limit: i64,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT name, max_version, description FROM crates \
+            WHERE max_version IS NOT NULL \
+            AND (strpos(lower(name), lower($1)) > 0 OR strpos(lower(description), lower($1)) > 0) \
+            ORDER BY lower(name) = lower($1) DESC, name LIMIT $2",
+            query,
+            limit
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    pub async fn search_count(transaction: &mut PgTransaction<'_>, query: &str) -> DbResult<i64> {
+        Ok(sqlx::query_as!(
+            super::Count,
+            "SELECT COUNT(*) FROM crates \
+            WHERE max_version IS NOT NULL \
+            AND (strpos(lower(name), lower($1)) > 0 OR strpos(lower(description), lower($1)) > 0)",
+            query
+        )
+        .fetch_one(transaction)
+        .await?
+        .into())
+    }
+
     pub async fn delete(transaction: &mut PgTransaction<'_>, name: &str) -> DbResult<()> {
         sqlx::query!("DELETE FROM crates WHERE name = $1", name)
             .execute(transaction)
@@ -138,6 +470,215 @@ impl Crate {
     }
 }
 
+fn string_list(values: &[serde_json::Value]) -> Vec<String> {
+    values
+        .iter()
+        .filter_map(|x| x.as_str().map(String::from))
+        .collect()
+}
+
+impl CrateVersion {
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        pkg: &registry::PublishPackage,
+        checksum: &str,
+        // nobody for versions that weren't published here
+        published_by: Option<&str>,
+    ) -> DbResult<Self> {
+        let features = match &pkg.features {
+            serde_json::Value::Null => serde_json::json!({}),
+            features => features.clone(),
+        };
+
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO crate_versions \
+            (crate_name, version, checksum, published_by, description, license, repository, keywords, categories, rust_version, features, dependencies, readme, readme_file) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) \
+            RETURNING id, crate_name, version, checksum, published_by, published_at, yanked, \
+            description, license, repository, keywords, categories, rust_version, features, dependencies",
+            pkg.name,
+            pkg.vers,
+            checksum,
+            published_by,
+            pkg.description.as_str(),
+            pkg.license.as_str(),
+            pkg.repository.as_str(),
+            &string_list(&pkg.keywords),
+            &string_list(&pkg.categories),
+            pkg.rust_version.as_str(),
+            features,
+            serde_json::to_value(&pkg.deps).unwrap_or_default(),
+            pkg.readme.as_str(),
+            pkg.readme_file.as_str()
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    pub async fn by_version(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, version, checksum, published_by, published_at, yanked, \
+            description, license, repository, keywords, categories, rust_version, features, dependencies \
+            FROM crate_versions WHERE crate_name = $1 AND version = $2",
+            crate_name,
+            version
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn readme(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+    ) -> DbResult<Option<VersionReadme>> {
+        sqlx::query_as!(
+            VersionReadme,
+            "SELECT readme, readme_file, repository FROM crate_versions \
+            WHERE crate_name = $1 AND version = $2",
+            crate_name,
+            version
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn exists(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS(SELECT 1 FROM crate_versions WHERE crate_name = $1 AND version = $2)",
+            crate_name,
+            version
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
+    pub async fn all_by_crate(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, version, checksum, published_by, published_at, yanked, \
+            description, license, repository, keywords, categories, rust_version, features, dependencies \
+            FROM crate_versions WHERE crate_name = $1 ORDER BY id",
+            crate_name
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    pub async fn set_yanked(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+        yanked: bool,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE crate_versions SET yanked = $3 WHERE crate_name = $1 AND version = $2",
+            crate_name,
+            version,
+            yanked
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+}
+
+impl VersionDownload {
+    // Downloads of files that don't belong to exactly one known version are silently dropped,
+    // the same file can be published as several versions
+    pub async fn add(
+        transaction: &mut PgTransaction<'_>,
+        checksum: &str,
+        date: NaiveDate,
+        count: i64,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "INSERT INTO version_downloads (version_id, date, downloads) \
+            SELECT min(id), $2, $3 FROM crate_versions WHERE checksum = $1 HAVING count(*) = 1 \
+            ON CONFLICT (version_id, date) DO UPDATE \
+            SET downloads = version_downloads.downloads + EXCLUDED.downloads",
+            checksum,
+            date,
+            count
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_by_version(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        version: &str,
+        date: NaiveDate,
+        count: i64,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "INSERT INTO version_downloads (version_id, date, downloads) \
+            SELECT id, $3, $4 FROM crate_versions WHERE crate_name = $1 AND version = $2 \
+            ON CONFLICT (version_id, date) DO UPDATE \
+            SET downloads = version_downloads.downloads + EXCLUDED.downloads",
+            crate_name,
+            version,
+            date,
+            count
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn all_by_crate(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        since: NaiveDate,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT version_downloads.version_id, version_downloads.date, version_downloads.downloads \
+            FROM version_downloads JOIN crate_versions ON crate_versions.id = version_downloads.version_id \
+            WHERE crate_versions.crate_name = $1 AND version_downloads.date >= $2 \
+            ORDER BY version_downloads.date, version_downloads.version_id",
+            crate_name,
+            since
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    pub async fn totals_by_crate(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+    ) -> DbResult<Vec<VersionDownloadTotal>> {
+        sqlx::query_as!(
+            VersionDownloadTotal,
+            "SELECT version_downloads.version_id, SUM(version_downloads.downloads)::BIGINT AS \"downloads!\" \
+            FROM version_downloads JOIN crate_versions ON crate_versions.id = version_downloads.version_id \
+            WHERE crate_versions.crate_name = $1 GROUP BY version_downloads.version_id",
+            crate_name
+        )
+        .fetch_all(transaction)
+        .await
+    }
+}
+
 impl CrateOwner {
     pub async fn new(
         transaction: &mut PgTransaction<'_>,
@@ -184,6 +725,48 @@ impl CrateOwner {
         .into())
     }
 
+    // Owners can act directly or through one of the teams owning the crate
+    pub async fn exists_or_team_member(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        user_ident: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS( \
+                SELECT 1 FROM crate_owners WHERE crate_name = $1 AND user_ident = $2 \
+                UNION ALL \
+                SELECT 1 FROM crate_team_owners \
+                JOIN team_members ON team_members.team_name = crate_team_owners.team_name \
+                WHERE crate_team_owners.crate_name = $1 AND team_members.user_ident = $2 \
+            )",
+            crate_name,
+            user_ident
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
+    // Whether a user or a team still owns the crate, owner changes must not orphan it
+    pub async fn any_exists(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS( \
+                SELECT 1 FROM crate_owners WHERE crate_name = $1 \
+                UNION ALL \
+                SELECT 1 FROM crate_team_owners WHERE crate_name = $1 \
+            )",
+            crate_name
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
     pub async fn all_owners(
         transaction: &mut PgTransaction<'_>,
         crate_name: &str,
@@ -0,0 +1,184 @@
+#![allow(unused)]
+
+use super::{DbResult, PgTransaction};
+use sqlx::types::chrono::{DateTime, Utc};
+
+// Logins starting with this refer to a team wherever crate owners are listed or changed
+pub const TEAM_PREFIX: &str = "team:";
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct Team {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct TeamMember {
+    pub team_name: String,
+    pub user_ident: String,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct CrateTeamOwner {
+    pub crate_name: String,
+    pub team_name: String,
+}
+
+impl Team {
+    pub async fn new(transaction: &mut PgTransaction<'_>, name: &str) -> DbResult<Self> {
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO teams (name) VALUES ($1) RETURNING name, created_at",
+            name
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    pub async fn exists(transaction: &mut PgTransaction<'_>, name: &str) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS(SELECT 1 FROM teams WHERE name = $1)",
+            name
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
+    pub async fn all(transaction: &mut PgTransaction<'_>) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(Self, "SELECT name, created_at FROM teams ORDER BY name")
+            .fetch_all(transaction)
+            .await
+    }
+
+    // Crates only owned by this team are left without owners
+    pub async fn delete(transaction: &mut PgTransaction<'_>, name: &str) -> DbResult<()> {
+        sqlx::query!("DELETE FROM teams WHERE name = $1", name)
+            .execute(transaction)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl TeamMember {
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        team_name: &str,
+        user_ident: &str,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "INSERT INTO team_members (team_name, user_ident) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            team_name,
+            user_ident
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn delete(
+        transaction: &mut PgTransaction<'_>,
+        team_name: &str,
+        user_ident: &str,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "DELETE FROM team_members WHERE team_name = $1 AND user_ident = $2",
+            team_name,
+            user_ident
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn exists(
+        transaction: &mut PgTransaction<'_>,
+        team_name: &str,
+        user_ident: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS(SELECT 1 FROM team_members WHERE team_name = $1 AND user_ident = $2)",
+            team_name,
+            user_ident
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
+    pub async fn all_by_team(
+        transaction: &mut PgTransaction<'_>,
+        team_name: &str,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT team_name, user_ident FROM team_members WHERE team_name = $1 ORDER BY user_ident",
+            team_name
+        )
+        .fetch_all(transaction)
+        .await
+    }
+}
+
+impl CrateTeamOwner {
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        team_name: &str,
+    ) -> DbResult<Self> {
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO crate_team_owners (crate_name, team_name) VALUES ($1, $2) RETURNING crate_name, team_name",
+            crate_name,
+            team_name
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    pub async fn delete(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        team_name: &str,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "DELETE FROM crate_team_owners WHERE crate_name = $1 AND team_name = $2",
+            crate_name,
+            team_name
+        )
+        .execute(transaction)
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn exists(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+        team_name: &str,
+    ) -> DbResult<bool> {
+        Ok(sqlx::query_as!(
+            super::Exists,
+            "SELECT EXISTS(SELECT 1 FROM crate_team_owners WHERE crate_name = $1 AND team_name = $2)",
+            crate_name,
+            team_name
+        )
+        .fetch_one(&mut *transaction)
+        .await?
+        .into())
+    }
+
+    pub async fn all_owners(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: &str,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT crate_name, team_name FROM crate_team_owners WHERE crate_name = $1 ORDER BY team_name",
+            crate_name
+        )
+        .fetch_all(transaction)
+        .await
+    }
+}
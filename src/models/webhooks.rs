@@ -0,0 +1,232 @@
+#![allow(unused)]
+
+use super::{AuditEvent, DbResult, PgTransaction};
+use sqlx::types::chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl WebhookDeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookDeliveryStatus::Pending => "pending",
+            WebhookDeliveryStatus::Delivered => "delivered",
+            WebhookDeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct Webhook {
+    pub id: i64,
+    pub crate_name: Option<String>,
+    pub url: String,
+    pub secret: String,
+    pub events: Option<Vec<String>>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+// A delivery together with where it goes, as handed to the delivery worker
+#[derive(Debug, sqlx::FromRow)]
+pub struct DueWebhookDelivery {
+    pub id: i64,
+    pub event: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+impl Webhook {
+    // Without a crate name the webhook is registry-wide
+    pub async fn new(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: Option<&str>,
+        url: &str,
+        secret: &str,
+        events: Option<&[String]>,
+        created_by: &str,
+    ) -> DbResult<Self> {
+        sqlx::query_as!(
+            Self,
+            "INSERT INTO webhooks (crate_name, url, secret, events, created_by) \
+            VALUES ($1, $2, $3, $4, $5) \
+            RETURNING id, crate_name, url, secret, events, created_by, created_at",
+            crate_name,
+            url,
+            secret,
+            events,
+            created_by
+        )
+        .fetch_one(transaction)
+        .await
+    }
+
+    pub async fn by_id(
+        transaction: &mut PgTransaction<'_>,
+        id: i64,
+        crate_name: Option<&str>,
+    ) -> DbResult<Option<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, url, secret, events, created_by, created_at \
+            FROM webhooks WHERE id = $1 AND crate_name IS NOT DISTINCT FROM $2",
+            id,
+            crate_name
+        )
+        .fetch_optional(transaction)
+        .await
+    }
+
+    pub async fn all_by_crate(
+        transaction: &mut PgTransaction<'_>,
+        crate_name: Option<&str>,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, crate_name, url, secret, events, created_by, created_at \
+            FROM webhooks WHERE crate_name IS NOT DISTINCT FROM $1 ORDER BY id",
+            crate_name
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    // returns whether the crate, or the registry without one, had a webhook with that id
+    pub async fn delete(
+        transaction: &mut PgTransaction<'_>,
+        id: i64,
+        crate_name: Option<&str>,
+    ) -> DbResult<bool> {
+        sqlx::query!(
+            "DELETE FROM webhooks WHERE id = $1 AND crate_name IS NOT DISTINCT FROM $2",
+            id,
+            crate_name
+        )
+        .execute(transaction)
+        .await
+        .map(|x| x.rows_affected() > 0)
+    }
+}
+
+impl WebhookDelivery {
+    // Queues a delivery for every webhook of the crate or the registry that wants the event
+    pub async fn queue(transaction: &mut PgTransaction<'_>, event: &AuditEvent) -> DbResult<()> {
+        let payload = serde_json::json!({
+            "id": event.id,
+            "event": event.action,
+            "crate": event.crate_name,
+            "version": event.version,
+            "actor": event.actor,
+            "details": event.details,
+            "created_at": event.created_at.to_rfc3339(),
+        });
+
+        sqlx::query!(
+            "INSERT INTO webhook_deliveries (webhook_id, event, payload) \
+            SELECT id, $2, $3 FROM webhooks \
+            WHERE (crate_name IS NULL OR crate_name = $1) AND (events IS NULL OR $2 = ANY(events))",
+            event.crate_name,
+            event.action,
+            payload
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    // Pushes the next attempt of the claimed deliveries back to `lease_until`, so a worker
+    // that dies while sending gets its deliveries retried afterwards
+    pub async fn claim_due(
+        transaction: &mut PgTransaction<'_>,
+        limit: i64,
+        lease_until: DateTime<Utc>,
+    ) -> DbResult<Vec<DueWebhookDelivery>> {
+        sqlx::query_as!(
+            DueWebhookDelivery,
+            "UPDATE webhook_deliveries SET attempts = attempts + 1, next_attempt_at = $2 \
+            FROM webhooks \
+            WHERE webhook_deliveries.id IN ( \
+                SELECT id FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_at <= now() \
+                ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED \
+            ) AND webhooks.id = webhook_deliveries.webhook_id \
+            RETURNING webhook_deliveries.id, webhook_deliveries.event, webhook_deliveries.payload, \
+            webhook_deliveries.attempts, webhooks.url, webhooks.secret",
+            limit,
+            lease_until
+        )
+        .fetch_all(transaction)
+        .await
+    }
+
+    pub async fn delivered(transaction: &mut PgTransaction<'_>, id: i64) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE webhook_deliveries SET status = 'delivered', last_error = NULL, delivered_at = now() \
+            WHERE id = $1",
+            id
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    // Without a next attempt the delivery is given up on
+    pub async fn failed(
+        transaction: &mut PgTransaction<'_>,
+        id: i64,
+        error: &str,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) -> DbResult<()> {
+        sqlx::query!(
+            "UPDATE webhook_deliveries \
+            SET status = CASE WHEN $3::TIMESTAMPTZ IS NULL THEN 'failed' ELSE 'pending' END, \
+            last_error = $2, next_attempt_at = COALESCE($3, next_attempt_at) \
+            WHERE id = $1",
+            id,
+            error,
+            next_attempt_at
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn all_by_webhook(
+        transaction: &mut PgTransaction<'_>,
+        webhook_id: i64,
+        limit: i64,
+    ) -> DbResult<Vec<Self>> {
+        sqlx::query_as!(
+            Self,
+            "SELECT id, webhook_id, event, payload, status, attempts, next_attempt_at, last_error, \
+            created_at, delivered_at \
+            FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2",
+            webhook_id,
+            limit
+        )
+        .fetch_all(transaction)
+        .await
+    }
+}
@@ -1,11 +1,12 @@
 use crate::models;
-use crate::{apiresponse::ApiError, settings};
+use crate::{apiresponse::ApiError, settings, web};
 use axum::{
     extract::Extension,
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    http::{header, StatusCode},
+    response::{Headers, IntoResponse, Response},
 };
 use openid_types::token::CodeTokenClaims;
+use sqlx::types::chrono::{Duration, Utc};
 use sqlx::PgPool;
 use std::sync::Arc;
 
@@ -18,7 +19,12 @@ use openid_client::{
 const PROVIDER_SLUG: &str = "registmily";
 
 #[derive(Debug)]
-struct OpenidProvider(PgPool);
+struct OpenidProvider {
+    pool: PgPool,
+    // only send the session cookie over https when the registry is served that way
+    secure_cookie: bool,
+    session_days: u32,
+}
 
 #[async_trait::async_trait]
 impl TokenHandler for OpenidProvider {
@@ -29,7 +35,7 @@ impl TokenHandler for OpenidProvider {
         _access_token: &str,
         token_data: TokenData<CodeTokenClaims<Self::Extra>>,
     ) -> CallbackResponse {
-        let mut trans = match self.0.begin().await.map_err(ApiError::from) {
+        let mut trans = match self.pool.begin().await.map_err(ApiError::from) {
             Ok(x) => x,
             Err(why) => {
                 return why.into_response();
@@ -38,24 +44,28 @@ impl TokenHandler for OpenidProvider {
 
         let userident = format!("{}-{}", provider_slug, token_data.claims.base.sub);
 
-        match models::User::exists_by_ident(&mut trans, &userident)
+        match models::User::by_ident(&mut trans, &userident)
             .await
             .map_err(ApiError::from)
         {
-            Ok(exists) => {
-                if !exists {
-                    if let Err(why) = models::User::new(&mut trans, &userident)
-                        .await
-                        .map_err(ApiError::from)
-                    {
-                        return why.into_response();
-                    }
+            Ok(Some(user)) if user.disabled => {
+                return ApiError(String::from("This user is disabled"), StatusCode::FORBIDDEN)
+                    .into_response();
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                if let Err(why) = models::User::new(&mut trans, &userident)
+                    .await
+                    .map_err(ApiError::from)
+                {
+                    return why.into_response();
                 }
             }
             Err(why) => return why.into_response(),
         }
 
-        let session = match models::UserSession::new(&mut trans, &userident)
+        let expires_at = Utc::now() + Duration::days(self.session_days as i64);
+        let (session, token) = match models::UserSession::new(&mut trans, &userident, expires_at)
             .await
             .map_err(ApiError::from)
         {
@@ -67,13 +77,23 @@ impl TokenHandler for OpenidProvider {
             return why.into_response();
         }
 
-        Response::builder()
-            .status(StatusCode::OK)
-            .body(axum::body::boxed(axum::body::Body::from(format!(
-                "Hello! Your userid is {} and your cargo accesss token is {}",
-                session.ident, session.token
-            ))))
-            .unwrap()
+        let body = format!(
+            "<h1>Hello!</h1><p>Your userid is <code>{}</code>, log in to cargo with:</p>\
+            <pre>cargo login --registry registmily {}</pre>\
+            <p>The same token keeps you logged in here, so logging out revokes it as well. \
+            Use <a href=\"/tokens\">api tokens</a> for ci and other machines.</p>",
+            web::escape(&session.ident),
+            web::escape(&token)
+        );
+
+        (
+            Headers([(
+                header::SET_COOKIE,
+                web::session_cookie(&token, self.secure_cookie),
+            )]),
+            web::page("Logged in", Some(&session), &body),
+        )
+            .into_response()
     }
 }
 
@@ -106,12 +126,16 @@ pub async fn build_client(settings: Arc<settings::Settings>, pool: PgPool) -> Cl
         .add_provider(
             RegisteredProviderBuilder::new(
                 String::from(PROVIDER_SLUG),
-                Some(String::from("http://localhost:8080/callback")),
+                Some(settings.openid_redirect_uri()),
                 settings.openid_client_id.clone(),
                 settings.openid_client_secret.clone(),
                 Some(url::Url::parse(&settings.openid_auth_endpoint.clone()).unwrap()),
                 url::Url::parse(&settings.openid_token_endpoint.clone()).unwrap(),
-                Some(OpenidProvider(pool)),
+                Some(OpenidProvider {
+                    pool,
+                    secure_cookie: settings.api_url.starts_with("https://"),
+                    session_days: settings.session_days,
+                }),
             )
             .finish(),
         )
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_derive::Deserialize;
+use tower::{Layer, Service};
+
+use crate::apiresponse::ApiError;
+
+// How often full buckets get dropped, they're no different from new ones
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// `burst` requests right away, refilled with `per_minute` requests a minute
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+// Every limit is off unless it's configured
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitSettings {
+    // by session ident, for every request that isn't a GET
+    pub mutations: Option<RateLimit>,
+    // by client ip
+    pub downloads: Option<RateLimit>,
+    // by client ip, for starting a login and the openid callback
+    pub login: Option<RateLimit>,
+    // only when running behind a proxy that appends the client to `x-forwarded-for`
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+pub struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes a token from the bucket of `key`, or says how long until there is one
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let burst = limit.burst.max(1) as f64;
+        let per_second = limit.per_minute as f64 / 60.0;
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * per_second).min(burst)
+        };
+
+        let mut guard = self.buckets.lock().unwrap();
+        let Buckets { buckets, pruned } = &mut *guard;
+        if now.saturating_duration_since(*pruned) >= PRUNE_INTERVAL {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+            *pruned = now;
+        }
+
+        let bucket = buckets.entry(String::from(key)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        } else {
+            // a bucket that never refills, there's no right answer to when to come back
+            Err(Duration::from_secs(60))
+        }
+    }
+}
+
+pub struct RateLimiters {
+    pub mutations: Arc<RateLimiter>,
+    pub downloads: Arc<RateLimiter>,
+    pub login: Arc<RateLimiter>,
+    pub trust_forwarded_for: bool,
+}
+
+impl RateLimiters {
+    pub fn from_settings(settings: &RateLimitSettings) -> Self {
+        Self {
+            mutations: Arc::new(RateLimiter::new(settings.mutations)),
+            downloads: Arc::new(RateLimiter::new(settings.downloads)),
+            login: Arc::new(RateLimiter::new(settings.login)),
+            trust_forwarded_for: settings.trust_forwarded_for,
+        }
+    }
+}
+
+// A 429 telling the client how many seconds to wait
+#[derive(Debug)]
+pub struct RateLimited(pub Duration);
+
+impl RateLimited {
+    pub fn retry_after(&self) -> u64 {
+        (self.0.as_secs() + u64::from(self.0.subsec_nanos() > 0)).max(1)
+    }
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after();
+        let mut response = ApiError(
+            format!("Too many requests, try again in {} seconds", retry_after),
+            StatusCode::TOO_MANY_REQUESTS,
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+// The peer address, or the last `x-forwarded-for` entry as that's the one our proxy added
+pub fn client_ip<B>(req: &Request<B>, trust_forwarded_for: bool) -> String {
+    let forwarded_for = req
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.rsplit(',').next())
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty());
+
+    match (
+        forwarded_for,
+        req.extensions().get::<ConnectInfo<SocketAddr>>(),
+    ) {
+        (Some(forwarded_for), _) if trust_forwarded_for => String::from(forwarded_for),
+        (_, Some(ConnectInfo(addr))) => addr.ip().to_string(),
+        _ => String::from("unknown"),
+    }
+}
+
+// Limits a route by client ip
+#[derive(Clone)]
+pub struct IpRateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    trust_forwarded_for: bool,
+}
+
+impl IpRateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>, trust_forwarded_for: bool) -> Self {
+        Self {
+            limiter,
+            trust_forwarded_for,
+        }
+    }
+}
+
+impl<S> Layer<S> for IpRateLimitLayer {
+    type Service = IpRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IpRateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            trust_forwarded_for: self.trust_forwarded_for,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IpRateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    trust_forwarded_for: bool,
+}
+
+impl<S, B> Service<Request<B>> for IpRateLimit<S>
+where
+    S: Service<Request<B>, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let ip = client_ip(&req, self.trust_forwarded_for);
+        if let Err(wait) = self.limiter.check(&ip) {
+            return Box::pin(async move { Ok(RateLimited(wait).into_response()) });
+        }
+
+        Box::pin(self.inner.call(req))
+    }
+}
@@ -0,0 +1,104 @@
+use std::path::{Component, Path};
+
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+use url::Url;
+
+use crate::web;
+
+// Markdown is rendered and then sanitized with ammonia, anything else is shown as plain text
+pub fn render(readme: &str, readme_file: Option<&str>, repository: Option<&str>) -> String {
+    let extension = readme_file
+        .and_then(|x| Path::new(x).extension())
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase());
+    let is_markdown = match extension.as_deref() {
+        Some(extension) => matches!(extension, "md" | "markdown" | "mdown" | "mkdn" | "mkd"),
+        None => true,
+    };
+    if !is_markdown {
+        return format!("<pre>{}</pre>", web::escape(readme));
+    }
+
+    let base = repository.and_then(|x| RepositoryBase::new(x, readme_file));
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+    let events = Parser::new_ext(readme, options).map(|event| match (event, &base) {
+        (Event::Start(Tag::Link(kind, dest, title)), Some(base)) => {
+            Event::Start(Tag::Link(kind, base.rewrite(dest, false), title))
+        }
+        (Event::Start(Tag::Image(kind, dest, title)), Some(base)) => {
+            Event::Start(Tag::Image(kind, base.rewrite(dest, true), title))
+        }
+        (event, _) => event,
+    });
+
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events);
+
+    ammonia::clean(&rendered)
+}
+
+// Where relative links and images of a readme point to in the repository
+struct RepositoryBase {
+    blob: String,
+    raw: String,
+    // the readme isn't necessarily in the root of the repository
+    dir: String,
+}
+
+impl RepositoryBase {
+    fn new(repository: &str, readme_file: Option<&str>) -> Option<Self> {
+        let url = Url::parse(repository.trim_end_matches('/').trim_end_matches(".git")).ok()?;
+        if url.scheme() != "https" && url.scheme() != "http" {
+            return None;
+        }
+        let repo = url.as_str().trim_end_matches('/');
+
+        let (blob, raw) = match url.host_str() {
+            Some("github.com") => (
+                format!("{}/blob/HEAD/", repo),
+                format!(
+                    "https://raw.githubusercontent.com/{}/HEAD/",
+                    url.path().trim_matches('/')
+                ),
+            ),
+            Some(host) if host.contains("gitlab") => (
+                format!("{}/-/blob/HEAD/", repo),
+                format!("{}/-/raw/HEAD/", repo),
+            ),
+            _ => (format!("{}/", repo), format!("{}/", repo)),
+        };
+
+        // a readme outside of the crate (`../README.md`) can't be placed, so assume the root
+        let dir = readme_file
+            .and_then(|x| Path::new(x).parent())
+            .filter(|x| x.components().all(|x| matches!(x, Component::Normal(_))))
+            .and_then(|x| x.to_str())
+            .filter(|x| !x.is_empty())
+            .map(|x| format!("{}/", x))
+            .unwrap_or_default();
+
+        Some(Self { blob, raw, dir })
+    }
+
+    fn rewrite<'a>(&self, dest: CowStr<'a>, image: bool) -> CowStr<'a> {
+        if dest.is_empty()
+            || dest.starts_with('#')
+            || dest.starts_with("//")
+            || Url::parse(&dest).is_ok()
+        {
+            return dest;
+        }
+
+        let prefix = if image { &self.raw } else { &self.blob };
+        match dest.strip_prefix('/') {
+            Some(path) => CowStr::from(format!("{}{}", prefix, path)),
+            None => CowStr::from(format!(
+                "{}{}{}",
+                prefix,
+                self.dir,
+                dest.trim_start_matches("./")
+            )),
+        }
+    }
+}
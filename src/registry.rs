@@ -4,8 +4,13 @@ use serde_derive::Serialize;
 use serde_json::Value;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
-use tracing::info;
+use tracing::{error, info, warn};
+
+use crate::metrics;
+use crate::storage::{Storage, StorageError};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PublishPackage {
@@ -26,6 +31,8 @@ pub struct PublishPackage {
     pub repository: Value,
     pub badges: Value,
     pub links: Value,
+    #[serde(default)]
+    pub rust_version: Value,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -49,6 +56,7 @@ pub struct Package {
     pub cksum: String,
     pub features: Value,
     pub yanked: bool,
+    #[serde(default)]
     pub links: Value,
 }
 
@@ -59,9 +67,12 @@ pub struct PackageDep {
     pub features: Vec<String>,
     pub optional: bool,
     pub default_features: bool,
+    #[serde(default)]
     pub target: Value,
     pub kind: String,
+    #[serde(default)]
     pub registry: Value,
+    #[serde(default)]
     pub package: Value,
 }
 
@@ -81,6 +92,36 @@ impl From<PublishDep> for PackageDep {
     }
 }
 
+impl From<PackageDep> for PublishDep {
+    fn from(dep: PackageDep) -> Self {
+        Self {
+            name: dep.name,
+            version_req: dep.req,
+            features: dep.features,
+            optional: dep.optional,
+            default_features: dep.default_features,
+            target: dep.target,
+            kind: dep.kind,
+            registry: dep.registry,
+            explicit_name_in_toml: dep.package,
+        }
+    }
+}
+
+// Index entries only know the dependencies, features and links of a version
+impl From<Package> for PublishPackage {
+    fn from(pkg: Package) -> Self {
+        Self {
+            name: pkg.name,
+            vers: pkg.vers,
+            deps: pkg.deps.into_iter().map(PublishDep::from).collect(),
+            features: pkg.features,
+            links: pkg.links,
+            ..Default::default()
+        }
+    }
+}
+
 impl Package {
     pub fn from_pub(pub_pkg: PublishPackage, checksum: String) -> Self {
         Self {
@@ -96,6 +137,8 @@ impl Package {
 }
 
 pub type CrateFile = Vec<u8>;
+// How many operations can wait for the registry thread
+pub const QUEUE_SIZE: usize = u16::MAX as usize;
 pub type SyncSender =
     tokio::sync::mpsc::Sender<(Operation, tokio::sync::oneshot::Sender<RegistryResponse>)>;
 pub type SyncReciever =
@@ -109,16 +152,44 @@ pub enum Operation {
 
 #[derive(Error, Debug)]
 pub enum PublishError {
-    // #[error("error happened while writing file: {0}")]
-// WriteError(std::io::Error),
-// #[error("test")]
-// Blubb
+    #[error("invalid version `{0}`, versions need to be valid semver")]
+    InvalidVersion(String),
+    #[error("crate version `{0}` is already uploaded")]
+    VersionExists(String),
+    #[error("failed to store crate file: {0}")]
+    Storage(#[from] StorageError),
+    #[error("failed to commit to the index: {0}")]
+    Git(#[from] git2::Error),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CrateNameError {
+    #[error("crate name cannot be empty")]
+    Empty,
+    #[error("crate name cannot be longer than 64 characters")]
+    TooLong,
+    #[error("crate name must start with an ASCII letter")]
+    InvalidStart,
+    #[error("crate name contains invalid character `{0}`")]
+    InvalidCharacter(char),
+    #[error("`{0}` is a reserved name")]
+    Reserved(String),
 }
 
 #[derive(Error, Debug)]
 pub enum YankError {
     #[error("Crate not found")]
     CrateNotFound,
+    #[error("failed to commit to the index: {0}")]
+    Git(#[from] git2::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum DeleteError {
+    #[error("crate `{0}` is not in the index")]
+    CrateNotFound(String),
+    #[error("failed to commit to the index: {0}")]
+    Git(#[from] git2::Error),
 }
 
 pub enum RegistryResponse {
@@ -129,7 +200,9 @@ pub enum RegistryResponse {
 pub struct Registry {
     repo: Repository,
     pub repo_path: String,
-    storage_location: String,
+    storage: Arc<dyn Storage>,
+    // the storage backends are async, the registry thread blocks on the main runtime for them
+    runtime: tokio::runtime::Handle,
 }
 
 fn git_credentials_callback(
@@ -150,15 +223,97 @@ fn git_credentials_callback(
     git2::Cred::ssh_key(user, None, &ssh_key_path, None)
 }
 
+pub const MAX_CRATE_NAME_LENGTH: usize = 64;
+
+// device names windows won't let us create files for, no matter the extension
+const RESERVED_CRATE_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+// Same rules as crates.io, names passing this are safe to use in index and storage paths
+pub fn validate_package_name(package_name: &str) -> Result<(), CrateNameError> {
+    let first = match package_name.chars().next() {
+        Some(first) => first,
+        None => return Err(CrateNameError::Empty),
+    };
+
+    if package_name.len() > MAX_CRATE_NAME_LENGTH {
+        return Err(CrateNameError::TooLong);
+    }
+
+    if !first.is_ascii_alphabetic() {
+        return Err(CrateNameError::InvalidStart);
+    }
+
+    if let Some(c) = package_name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+    {
+        return Err(CrateNameError::InvalidCharacter(c));
+    }
+
+    if RESERVED_CRATE_NAMES.contains(&package_name.to_lowercase().as_str()) {
+        return Err(CrateNameError::Reserved(String::from(package_name)));
+    }
+
+    Ok(())
+}
+
+// Token crate patterns are either a crate name or a name prefix followed by `*`
+pub fn validate_crate_pattern(pattern: &str) -> Result<(), CrateNameError> {
+    match pattern.strip_suffix('*') {
+        Some("") => Ok(()),
+        Some(prefix) => validate_package_name(prefix),
+        None => validate_package_name(pattern),
+    }
+}
+
+pub fn crate_pattern_matches(pattern: &str, package_name: &str) -> bool {
+    let package_name = package_name.to_lowercase();
+    match pattern.strip_suffix('*') {
+        Some(prefix) => package_name.starts_with(&prefix.to_lowercase()),
+        None => package_name == pattern.to_lowercase(),
+    }
+}
+
+// Names differing only in case or `-` vs `_` are the same crate, like on crates.io
+pub fn canonical_crate_name(package_name: &str) -> String {
+    package_name.to_lowercase().replace('-', "_")
+}
+
+// Every crate's index file, skipping `config.json` and git's files
+pub fn index_files(repo_path: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::from(repo_path)];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+            } else if dir != Path::new(repo_path) {
+                files.push(path);
+            }
+        }
+    }
+
+    files
+}
+
+// Index files are named after the lowercased crate name, that's what cargo asks for
 pub fn get_package_git_path(repo_path: &str, package_name: &str) -> PathBuf {
     let mut folder = get_package_git_folder(repo_path, package_name);
-    folder.push(package_name);
+    folder.push(package_name.to_lowercase());
     folder
 }
 
 pub fn get_package_git_folder(repo_path: &str, package_name: &str) -> PathBuf {
     // ensure that there can be no path traversal bugs!
-    // proper crate name checking needs to be done elsewhere
+    // proper crate name checking is done by validate_package_name
     // if this ever panics in production it just saved me from a bad vuln :p
     assert!(!package_name.contains('.'));
     assert!(!package_name.contains('/'));
@@ -181,8 +336,25 @@ pub fn get_package_git_folder(repo_path: &str, package_name: &str) -> PathBuf {
     path
 }
 
+// Build metadata is ignored when comparing versions, so `1.0.0` and `1.0.0+build` are the same
+pub fn is_same_version(published: &str, version: &semver::Version) -> bool {
+    match semver::Version::parse(published) {
+        Ok(published) => {
+            published.major == version.major
+                && published.minor == version.minor
+                && published.patch == version.patch
+                && published.pre == version.pre
+        }
+        Err(_) => published == version.to_string(),
+    }
+}
+
 impl Registry {
-    pub fn new(git_location: &str, storage_location: &str) -> Self {
+    pub fn new(
+        git_location: &str,
+        storage: Arc<dyn Storage>,
+        runtime: tokio::runtime::Handle,
+    ) -> Self {
         info!("Opening {}", git_location);
         let repo = match Repository::open(git_location) {
             Ok(repo) => repo,
@@ -192,39 +364,86 @@ impl Registry {
         Self {
             repo,
             repo_path: String::from(git_location),
-            storage_location: String::from(storage_location),
+            storage,
+            runtime,
         }
     }
 
-    pub fn commit_git_files(&self, paths: Vec<&Path>, message: &str) {
-        let mut index = self.repo.index().unwrap();
+    // The cli changes the index next to the registry thread, whoever holds this lock goes
+    // first. It's released when the returned file is dropped.
+    fn lock_index(&self) -> fs::File {
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(self.repo.path().join("registmily.lock"))
+            .unwrap();
+        lock.lock().unwrap();
+        lock
+    }
 
-        for path in paths {
-            let path = pathdiff::diff_paths(path, Path::new(&self.repo_path)).unwrap();
+    // Index files used to keep the case of the crate name, this moves them to where cargo looks
+    pub fn lowercase_index_files(&self) {
+        let _lock = self.lock_index();
+        let mut moved = Vec::new();
 
-            index.add_path(path.as_path()).unwrap();
+        for path in index_files(&self.repo_path) {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if name == name.to_lowercase() {
+                continue;
+            }
+            let lowercase = path.with_file_name(name.to_lowercase());
+            if lowercase.exists() {
+                warn!(
+                    "Not moving {}, {} already exists",
+                    path.display(),
+                    lowercase.display()
+                );
+                continue;
+            }
+            fs::rename(&path, &lowercase).unwrap();
+            moved.push((path, lowercase));
         }
-        index.write().unwrap();
-        let sig = self.repo.signature().unwrap();
-        let tree_id = index.write_tree().unwrap();
 
-        let mut parents = Vec::new();
-        if let Some(parent) = self.repo.head().ok().map(|h| h.target().unwrap()) {
-            parents.push(self.repo.find_commit(parent).unwrap())
+        if !moved.is_empty() {
+            let committed = self.commit_git_files(
+                moved
+                    .iter()
+                    .flat_map(|(from, to)| [from.as_path(), to.as_path()])
+                    .collect(),
+                "Move index files to lowercase paths",
+            );
+            match committed {
+                Ok(()) => info!("Moved {} index files to lowercase paths", moved.len()),
+                Err(err) => error!("Failed to move index files to lowercase paths: {}", err),
+            }
         }
-        let parents = parents.iter().collect::<Vec<_>>();
+    }
 
-        self.repo
-            .commit(
-                Some("HEAD"),
-                &sig,
-                &sig,
-                message,
-                &self.repo.find_tree(tree_id).unwrap(),
-                &parents,
-            )
-            .unwrap();
+    // If committing fails the files are put back the way HEAD has them, so the index never
+    // has changes that weren't committed
+    pub fn commit_git_files(&self, paths: Vec<&Path>, message: &str) -> Result<(), git2::Error> {
+        let relative_paths: Vec<PathBuf> = paths
+            .iter()
+            .map(|path| pathdiff::diff_paths(path, Path::new(&self.repo_path)).unwrap())
+            .collect();
+
+        let start = Instant::now();
+        let commit = self.commit_paths(&relative_paths, message);
+        metrics::metrics().record_git("commit", commit.is_ok(), start.elapsed());
+        if let Err(err) = commit {
+            let mut checkout = git2::build::CheckoutBuilder::new();
+            checkout.force().remove_untracked(true);
+            for path in &relative_paths {
+                checkout.path(path);
+            }
+            if let Err(err) = self.repo.checkout_head(Some(&mut checkout)) {
+                error!("Failed to restore the index after a failed commit: {}", err);
+            }
+            return Err(err);
+        }
 
+        // the commit is there either way, the next push takes it along
         if let Ok(mut remote) = self.repo.find_remote("origin") {
             let mut callbacks = git2::RemoteCallbacks::new();
             callbacks.credentials(git_credentials_callback);
@@ -232,24 +451,58 @@ impl Registry {
             let mut opts = git2::PushOptions::new();
             opts.remote_callbacks(callbacks);
 
-            remote
-                .push(&["refs/heads/main:refs/heads/main"], Some(&mut opts))
-                .unwrap();
+            let start = Instant::now();
+            let push = remote.push(&["refs/heads/main:refs/heads/main"], Some(&mut opts));
+            metrics::metrics().record_git("push", push.is_ok(), start.elapsed());
+            if let Err(err) = push {
+                error!("Failed to push the index: {}", err);
+            }
         } else {
             info!("No remote found");
         }
+
+        Ok(())
     }
 
-    pub fn publish(&self, pkg: Package, crate_file: &CrateFile) -> Result<(), PublishError> {
-        let mut cratefile_path = PathBuf::from(&self.storage_location);
-        cratefile_path.push(&pkg.cksum);
-        cratefile_path.set_extension("crate");
+    fn commit_paths(&self, relative_paths: &[PathBuf], message: &str) -> Result<(), git2::Error> {
+        let mut index = self.repo.index()?;
+        // the other side of the lock may have committed since we last looked
+        index.read(false)?;
 
-        fs::write(cratefile_path, crate_file).unwrap();
+        for relative_path in relative_paths {
+            if Path::new(&self.repo_path).join(relative_path).exists() {
+                index.add_path(relative_path)?;
+            } else {
+                index.remove_path(relative_path)?;
+            }
+        }
+        let sig = self.repo.signature()?;
+        let tree_id = index.write_tree()?;
 
-        let repo_path = get_package_git_path(&self.repo_path, &pkg.name);
+        let mut parents = Vec::new();
+        if let Some(parent) = self.repo.head().ok().and_then(|h| h.target()) {
+            parents.push(self.repo.find_commit(parent)?)
+        }
+        let parents = parents.iter().collect::<Vec<_>>();
 
-        fs::create_dir_all(get_package_git_folder(&self.repo_path, &pkg.name)).unwrap();
+        self.repo.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            message,
+            &self.repo.find_tree(tree_id)?,
+            &parents,
+        )?;
+        // only written once the commit is there, a failed one leaves the staging area alone
+        index.write()
+    }
+
+    pub fn publish(&self, pkg: Package, crate_file: CrateFile) -> Result<(), PublishError> {
+        let version = semver::Version::parse(&pkg.vers)
+            .map_err(|_| PublishError::InvalidVersion(pkg.vers.clone()))?;
+
+        let _lock = self.lock_index();
+        let repo_path = get_package_git_path(&self.repo_path, &pkg.name);
 
         let mut all_published: Vec<Package> = if let Ok(oldfile) = fs::read_to_string(&repo_path) {
             oldfile
@@ -260,7 +513,19 @@ impl Registry {
             vec![]
         };
 
-        all_published.retain(|x| x.vers != pkg.vers);
+        // versions are immutable, yanked ones included, or we'd break every lockfile using them
+        if all_published
+            .iter()
+            .any(|x| is_same_version(&x.vers, &version))
+        {
+            return Err(PublishError::VersionExists(pkg.vers));
+        }
+
+        self.runtime
+            .block_on(self.storage.put(&pkg.cksum, crate_file))?;
+
+        fs::create_dir_all(get_package_git_folder(&self.repo_path, &pkg.name)).unwrap();
+
         all_published.push(pkg);
 
         let published_strings: Vec<String> = all_published
@@ -269,7 +534,7 @@ impl Registry {
             .collect();
         fs::write(&repo_path, published_strings.join("\n")).unwrap();
 
-        self.commit_git_files(vec![repo_path.as_path()], "added crate");
+        self.commit_git_files(vec![repo_path.as_path()], "added crate")?;
 
         Ok(())
     }
@@ -280,6 +545,7 @@ impl Registry {
         version: String,
         yank_val: bool,
     ) -> Result<(), YankError> {
+        let _lock = self.lock_index();
         let repo_path = get_package_git_path(&self.repo_path, &crate_name);
 
         let mut found_version = false;
@@ -316,13 +582,27 @@ impl Registry {
                 } else {
                     "unyanked crate"
                 };
-                self.commit_git_files(vec![repo_path.as_path()], message);
+                self.commit_git_files(vec![repo_path.as_path()], message)?;
             }
             Ok(())
         } else {
             Err(YankError::CrateNotFound)
         }
     }
+
+    // Removes every version of the crate from the index, the crate files stay in storage
+    pub fn delete(&self, crate_name: &str) -> Result<(), DeleteError> {
+        let _lock = self.lock_index();
+        let repo_path = get_package_git_path(&self.repo_path, crate_name);
+
+        if fs::remove_file(&repo_path).is_err() {
+            return Err(DeleteError::CrateNotFound(String::from(crate_name)));
+        }
+
+        self.commit_git_files(vec![repo_path.as_path()], "deleted crate")?;
+
+        Ok(())
+    }
 }
 
 pub async fn run_task(
@@ -337,19 +617,39 @@ pub async fn run_task(
     recv.await.map_err(|_| "Oneshot channel died")
 }
 
-pub fn handler(git_location: &str, storage_location: &str, mut recv: SyncReciever) {
+pub fn handler(
+    git_location: &str,
+    storage: Arc<dyn Storage>,
+    runtime: tokio::runtime::Handle,
+    mut recv: SyncReciever,
+) {
     // The git2-rs library is not thread safe and needs to stay on the same thread at all points in time due to it's use of environment variables
 
-    let registry = Registry::new(git_location, storage_location);
+    let registry = Registry::new(git_location, storage, runtime);
+    registry.lowercase_index_files();
 
     while let Some((op, oneshot_sender)) = recv.blocking_recv() {
-        let _ = oneshot_sender.send(match op {
+        let start = Instant::now();
+        let response = match op {
             Operation::Publish(pkg, crate_file) => {
-                RegistryResponse::Publish(registry.publish(pkg, &crate_file))
+                let result = registry.publish(pkg, crate_file);
+                metrics::metrics().record_registry_operation(
+                    "publish",
+                    result.is_ok(),
+                    start.elapsed(),
+                );
+                RegistryResponse::Publish(result)
             }
             Operation::Yank(crate_name, version, yank_val) => {
-                RegistryResponse::Yank(registry.yank(crate_name, version, yank_val))
+                let result = registry.yank(crate_name, version, yank_val);
+                metrics::metrics().record_registry_operation(
+                    "yank",
+                    result.is_ok(),
+                    start.elapsed(),
+                );
+                RegistryResponse::Yank(result)
             }
-        });
+        };
+        let _ = oneshot_sender.send(response);
     }
 }
@@ -1,7 +1,14 @@
 use config::Config;
 
+use crate::{ratelimit, storage};
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Settings {
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    // where cargo and browsers reach the api server, used for the index config and the login redirect
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
     pub repo_path: String,
     pub storage_path: String,
     pub database_url: String,
@@ -11,7 +18,117 @@ pub struct Settings {
     pub openid_client_id: String,
     pub openid_client_secret: String,
     pub openid_nonce: String,
+    // defaults to `<api_url>/callback`
+    pub openid_redirect_uri: Option<String>,
     pub jwt_key_config: openid_client::config::JwtParsedKeyConfig,
+    #[serde(default)]
+    pub admins: Vec<String>,
+    pub mirror_index_path: Option<String>,
+    pub mirror_crates_path: Option<String>,
+    // crate files go to S3 instead of `storage_path` when set
+    #[serde(default)]
+    pub s3: Option<storage::S3Settings>,
+    // rustdoc only gets built for new versions when set, it's the builder's scratch space
+    pub docs_build_path: Option<String>,
+    // the command `cargo doc` runs in, see `Builder::sandbox_args` for the placeholders
+    #[serde(default = "default_docs_sandbox")]
+    pub docs_sandbox: Vec<String>,
+    // how long the token from logging in stays valid, for cargo as well as the web interface
+    #[serde(default = "default_session_days")]
+    pub session_days: u32,
+    // how long someone has to accept becoming a crate owner
+    #[serde(default = "default_owner_invitation_days")]
+    pub owner_invitation_days: u32,
+    // publishes whose crate file unpacks to more than this are rejected
+    #[serde(default = "default_max_unpacked_crate_size")]
+    pub max_unpacked_crate_size: u64,
+    #[serde(default)]
+    pub rate_limits: ratelimit::RateLimitSettings,
+    // only when running behind a proxy that sets `x-request-id` and drops the client's
+    #[serde(default)]
+    pub trust_request_id: bool,
+    // hosts webhooks may call even though they're internal, e.g. `ci.internal` or `10.0.0.5`
+    #[serde(default)]
+    pub webhook_allowed_hosts: Vec<String>,
+}
+
+fn default_bind_address() -> String {
+    String::from("0.0.0.0:8080")
+}
+
+fn default_api_url() -> String {
+    String::from("http://localhost:8080")
+}
+
+fn default_session_days() -> u32 {
+    30
+}
+
+fn default_owner_invitation_days() -> u32 {
+    30
+}
+
+fn default_max_unpacked_crate_size() -> u64 {
+    512 * 1024 * 1024
+}
+
+// Only the toolchain, the local registry and the build directory are visible to the build
+fn default_docs_sandbox() -> Vec<String> {
+    [
+        "bwrap",
+        "--ro-bind",
+        "/usr",
+        "/usr",
+        "--ro-bind-try",
+        "/lib",
+        "/lib",
+        "--ro-bind-try",
+        "/lib64",
+        "/lib64",
+        "--ro-bind-try",
+        "/bin",
+        "/bin",
+        "--ro-bind-try",
+        "{cargo_home}",
+        "{cargo_home}",
+        "--ro-bind-try",
+        "{rustup_home}",
+        "{rustup_home}",
+        "--ro-bind",
+        "{registry}",
+        "{registry}",
+        "--ro-bind",
+        "{index}",
+        "{index}",
+        "--dev",
+        "/dev",
+        "--proc",
+        "/proc",
+        "--tmpfs",
+        "/tmp",
+        "--bind",
+        "{dir}",
+        "{dir}",
+        "--unshare-all",
+        "--die-with-parent",
+        "--",
+    ]
+    .iter()
+    .map(|x| String::from(*x))
+    .collect()
+}
+
+impl Settings {
+    pub fn is_admin(&self, ident: &str) -> bool {
+        self.admins.iter().any(|x| x == ident)
+    }
+
+    pub fn openid_redirect_uri(&self) -> String {
+        match &self.openid_redirect_uri {
+            Some(redirect_uri) => redirect_uri.clone(),
+            None => format!("{}/callback", self.api_url.trim_end_matches('/')),
+        }
+    }
 }
 
 pub fn read() -> Result<Settings, config::ConfigError> {
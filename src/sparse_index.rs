@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::{
+    body::{boxed, Empty, Full},
+    extract::{Extension, Path},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{registry, settings};
+
+// Serves the index files written by the registry thread over cargo's sparse protocol
+// (`sparse+https://<host>/index/`), so clients don't need to clone the git repo.
+pub async fn index_file(
+    Path(path): Path<String>,
+    settings: Extension<Arc<settings::Settings>>,
+    headers: HeaderMap,
+) -> Response {
+    let file_path = match resolve_index_path(&settings.repo_path, path.trim_start_matches('/')) {
+        Some(file_path) => file_path,
+        None => return (StatusCode::NOT_FOUND, "File not found!").into_response(),
+    };
+
+    let (content, modified) = match read_index_file(&file_path).await {
+        Ok(x) => x,
+        Err(_) => return (StatusCode::NOT_FOUND, "File not found!").into_response(),
+    };
+
+    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&content)));
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    let builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::CACHE_CONTROL, "no-cache");
+
+    let response = if is_not_modified(&headers, &etag, modified) {
+        builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(boxed(Empty::new()))
+    } else {
+        builder
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(boxed(Full::from(content)))
+    };
+
+    match response {
+        Ok(res) => res,
+        Err(err) => {
+            tracing::error!("Failed to build response: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Maps a sparse index request path onto the file in the index repo,
+// rejecting anything that isn't `config.json` or laid out like `get_package_git_path`.
+pub fn resolve_index_path(repo_path: &str, request_path: &str) -> Option<PathBuf> {
+    if request_path == "config.json" {
+        let mut path = PathBuf::from(repo_path);
+        path.push("config.json");
+        return Some(path);
+    }
+
+    // cargo always requests lowercased paths, the index files are lowercased too
+    let request_path = request_path.to_lowercase();
+    let package_name = request_path.rsplit('/').next()?;
+    if registry::validate_package_name(package_name).is_err() {
+        return None;
+    }
+
+    let path = registry::get_package_git_path(repo_path, package_name);
+    let mut expected = PathBuf::from(repo_path);
+    expected.push(&request_path);
+
+    if path == expected {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+async fn read_index_file(path: &std::path::Path) -> std::io::Result<(Vec<u8>, SystemTime)> {
+    let content = tokio::fs::read(path).await?;
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    Ok((content, modified))
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since (RFC 7232 section 6)
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return match if_none_match.to_str() {
+            Ok(value) => value
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag),
+            Err(_) => false,
+        };
+    }
+
+    if let Some(if_modified_since) = headers.get(header::IF_MODIFIED_SINCE) {
+        if let Some(since) = if_modified_since
+            .to_str()
+            .ok()
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+        {
+            // http dates only have second precision
+            let modified =
+                httpdate::parse_http_date(&httpdate::fmt_http_date(modified)).unwrap_or(modified);
+            return modified <= since;
+        }
+    }
+
+    false
+}
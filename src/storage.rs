@@ -0,0 +1,405 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use hmac::{Hmac, Mac};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::OffsetDateTime;
+use url::Url;
+
+use crate::settings;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid storage url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("storage backend responded with status {0}")]
+    Status(reqwest::StatusCode),
+}
+
+pub enum CrateDownload {
+    File(tokio::fs::File),
+    Bytes(Bytes),
+    Redirect(String),
+}
+
+// Where the `.crate` files live, they are always stored under their sha256 checksum.
+// The generated rustdoc html sits next to them under `docs/<crate>/<version>/`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, cksum: &str, crate_file: Vec<u8>) -> Result<(), StorageError>;
+    async fn get(&self, cksum: &str) -> Result<Option<CrateDownload>, StorageError>;
+    async fn put_doc(&self, path: &str, content: Vec<u8>) -> Result<(), StorageError>;
+    async fn get_doc(&self, path: &str) -> Result<Option<CrateDownload>, StorageError>;
+}
+
+// Enough for what rustdoc generates
+pub fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("txt") | Some("md") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn from_settings(settings: &settings::Settings) -> Arc<dyn Storage> {
+    match &settings.s3 {
+        Some(s3) => Arc::new(S3Storage::new(s3.clone())),
+        None => Arc::new(LocalStorage::new(&settings.storage_path)),
+    }
+}
+
+pub struct LocalStorage {
+    storage_path: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(storage_path: &str) -> Self {
+        Self {
+            storage_path: PathBuf::from(storage_path),
+        }
+    }
+
+    fn crate_path(&self, cksum: &str) -> PathBuf {
+        let mut path = self.storage_path.clone();
+        path.push(cksum);
+        path.set_extension("crate");
+        path
+    }
+
+    fn doc_path(&self, path: &str) -> PathBuf {
+        let mut doc_path = self.storage_path.clone();
+        doc_path.push("docs");
+        doc_path.push(path);
+        doc_path
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, cksum: &str, crate_file: Vec<u8>) -> Result<(), StorageError> {
+        tokio::fs::write(self.crate_path(cksum), crate_file).await?;
+        Ok(())
+    }
+
+    async fn get(&self, cksum: &str) -> Result<Option<CrateDownload>, StorageError> {
+        open_file(self.crate_path(cksum)).await
+    }
+
+    async fn put_doc(&self, path: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        let doc_path = self.doc_path(path);
+        if let Some(parent) = doc_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(doc_path, content).await?;
+        Ok(())
+    }
+
+    async fn get_doc(&self, path: &str) -> Result<Option<CrateDownload>, StorageError> {
+        open_file(self.doc_path(path)).await
+    }
+}
+
+async fn open_file(path: PathBuf) -> Result<Option<CrateDownload>, StorageError> {
+    match tokio::fs::File::open(path).await {
+        Ok(file) => Ok(Some(CrateDownload::File(file))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Settings {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    #[serde(default)]
+    pub key_prefix: String,
+    // MinIO and most self hosted S3 implementations need path style urls
+    #[serde(default = "default_path_style")]
+    pub path_style: bool,
+    // downloads redirect to presigned urls instead of going through the api server
+    #[serde(default)]
+    pub presign_downloads: bool,
+    #[serde(default = "default_presign_expires")]
+    pub presign_expires: u32,
+}
+
+fn default_path_style() -> bool {
+    true
+}
+
+fn default_presign_expires() -> u32 {
+    300
+}
+
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+pub struct S3Storage {
+    settings: S3Settings,
+    client: reqwest::Client,
+}
+
+impl S3Storage {
+    pub fn new(settings: S3Settings) -> Self {
+        Self {
+            settings,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn object_key(&self, cksum: &str) -> String {
+        format!("{}{}.crate", self.settings.key_prefix, cksum)
+    }
+
+    fn doc_key(&self, path: &str) -> String {
+        format!("{}docs/{}", self.settings.key_prefix, path)
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url, StorageError> {
+        let mut url = Url::parse(&self.settings.endpoint)?;
+
+        if self.settings.path_style {
+            url.set_path(&format!("/{}/{}", self.settings.bucket, key));
+        } else {
+            let host = format!(
+                "{}.{}",
+                self.settings.bucket,
+                url.host_str().unwrap_or_default()
+            );
+            url.set_host(Some(&host))?;
+            url.set_path(&format!("/{}", key));
+        }
+
+        Ok(url)
+    }
+
+    // Signs the request with AWS signature version 4, returning the headers to send along
+    fn sign_headers(
+        &self,
+        method: &str,
+        url: &Url,
+        payload_hash: &str,
+        now: OffsetDateTime,
+    ) -> Vec<(&'static str, String)> {
+        let amz_date = amz_date(now);
+        let scope = self.scope(now);
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method,
+            url.path(),
+            host_header(url),
+            payload_hash,
+            amz_date,
+            payload_hash
+        );
+        let signature = self.signature(&canonical_request, &amz_date, &scope);
+
+        vec![
+            ("x-amz-content-sha256", String::from(payload_hash)),
+            ("x-amz-date", amz_date),
+            (
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.settings.access_key, scope, signature
+                ),
+            ),
+        ]
+    }
+
+    pub fn presigned_url(&self, key: &str, now: OffsetDateTime) -> Result<Url, StorageError> {
+        let mut url = self.object_url(key)?;
+        let amz_date = amz_date(now);
+        let scope = self.scope(now);
+
+        // query parameters have to be sorted for the canonical request
+        let query = [
+            ("X-Amz-Algorithm", String::from("AWS4-HMAC-SHA256")),
+            (
+                "X-Amz-Credential",
+                format!("{}/{}", self.settings.access_key, scope),
+            ),
+            ("X-Amz-Date", amz_date.clone()),
+            ("X-Amz-Expires", self.settings.presign_expires.to_string()),
+            ("X-Amz-SignedHeaders", String::from("host")),
+        ]
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, uri_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+        let canonical_request = format!(
+            "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            url.path(),
+            query,
+            host_header(&url)
+        );
+        let signature = self.signature(&canonical_request, &amz_date, &scope);
+
+        url.set_query(Some(&format!("{}&X-Amz-Signature={}", query, signature)));
+        Ok(url)
+    }
+
+    fn scope(&self, now: OffsetDateTime) -> String {
+        format!(
+            "{}/{}/s3/aws4_request",
+            &amz_date(now)[..8],
+            self.settings.region
+        )
+    }
+
+    fn signature(&self, canonical_request: &str, amz_date: &str, scope: &str) -> String {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = format!("AWS4{}", self.settings.secret_key);
+        let key = hmac_sha256(key.as_bytes(), &amz_date[..8]);
+        let key = hmac_sha256(&key, &self.settings.region);
+        let key = hmac_sha256(&key, "s3");
+        let key = hmac_sha256(&key, "aws4_request");
+
+        hex::encode(hmac_sha256(&key, &string_to_sign))
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, cksum: &str, crate_file: Vec<u8>) -> Result<(), StorageError> {
+        self.put_object(&self.object_key(cksum), crate_file, None)
+            .await
+    }
+
+    async fn get(&self, cksum: &str) -> Result<Option<CrateDownload>, StorageError> {
+        self.get_object(&self.object_key(cksum), self.settings.presign_downloads)
+            .await
+    }
+
+    async fn put_doc(&self, path: &str, content: Vec<u8>) -> Result<(), StorageError> {
+        self.put_object(&self.doc_key(path), content, Some(content_type(path)))
+            .await
+    }
+
+    // never presigned, the relative links between the doc pages wouldn't carry a signature
+    async fn get_doc(&self, path: &str) -> Result<Option<CrateDownload>, StorageError> {
+        self.get_object(&self.doc_key(path), false).await
+    }
+}
+
+impl S3Storage {
+    async fn put_object(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let url = self.object_url(key)?;
+        let payload_hash = hex::encode(Sha256::digest(&content));
+
+        let mut request = self.client.put(url.clone());
+        for (name, value) in
+            self.sign_headers("PUT", &url, &payload_hash, OffsetDateTime::now_utc())
+        {
+            request = request.header(name, value);
+        }
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+
+        let response = request.body(content).send().await?;
+        if !response.status().is_success() {
+            return Err(StorageError::Status(response.status()));
+        }
+
+        Ok(())
+    }
+
+    async fn get_object(
+        &self,
+        key: &str,
+        presign: bool,
+    ) -> Result<Option<CrateDownload>, StorageError> {
+        if presign {
+            let url = self.presigned_url(key, OffsetDateTime::now_utc())?;
+            return Ok(Some(CrateDownload::Redirect(url.to_string())));
+        }
+
+        let url = self.object_url(key)?;
+        let mut request = self.client.get(url.clone());
+        for (name, value) in
+            self.sign_headers("GET", &url, EMPTY_PAYLOAD_HASH, OffsetDateTime::now_utc())
+        {
+            request = request.header(name, value);
+        }
+
+        let response = request.send().await?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                Ok(Some(CrateDownload::Bytes(response.bytes().await?)))
+            }
+            status => Err(StorageError::Status(status)),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    // hmac accepts keys of any length, so this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn amz_date(now: OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+fn host_header(url: &Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => String::from(url.host_str().unwrap_or_default()),
+    }
+}
+
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,671 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{Extension, Form, FromRequest, Path, Query, RequestParts},
+    http::{header, Method, StatusCode, Uri},
+    response::{Headers, Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use serde_derive::Deserialize;
+use sqlx::PgPool;
+
+use crate::{apiresponse::ApiError, apiserver, models, ratelimit, readme, registry};
+
+pub const SESSION_COOKIE: &str = "registmily_session";
+
+const CRATES_PER_PAGE: i64 = 100;
+
+const STYLE: &str =
+    "body{font-family:sans-serif;max-width:60em;margin:0 auto;padding:0 1em;color:#222}\
+nav{display:flex;gap:1em;align-items:center;border-bottom:1px solid #ccc;padding:1em 0}\
+nav form{margin:0}table{border-collapse:collapse;width:100%}\
+td,th{text-align:left;padding:.3em .6em;border-bottom:1px solid #eee}\
+code,pre{background:#f4f4f4;padding:.1em .3em}.yanked{color:#a00}.error{color:#a00}\
+.muted{color:#777}";
+
+// The login session from the cookie set by the openid callback, only the web ui reads it
+// so the cargo api can't be driven by a browser that happens to be logged in.
+pub struct WebSession(pub Option<models::UserSession>);
+
+#[async_trait]
+impl<B> FromRequest<B> for WebSession
+where
+    B: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let session = session_from_cookie(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        // shares the api's limit, the forms here create tokens and answer invitations too
+        if let Some(session) = &session {
+            if req.method() == Method::POST {
+                let limiters: Option<&Arc<ratelimit::RateLimiters>> =
+                    req.extensions().unwrap().get();
+                limiters
+                    .unwrap()
+                    .mutations
+                    .check(&session.ident)
+                    .map_err(|wait| ratelimit::RateLimited(wait).into_response())?;
+            }
+        }
+
+        Ok(Self(session))
+    }
+}
+
+async fn session_from_cookie<B: Send>(
+    req: &mut RequestParts<B>,
+) -> Result<Option<models::UserSession>, ApiError> {
+    let pool: Option<&PgPool> = req.extensions().unwrap().get();
+    let pool = pool.unwrap().clone();
+
+    let token = req
+        .headers()
+        .and_then(|headers| headers.get(header::COOKIE))
+        .and_then(|cookies| cookies.to_str().ok())
+        .and_then(|cookies| {
+            cookies.split(';').find_map(|cookie| {
+                let (name, value) = cookie.trim().split_once('=')?;
+                (name == SESSION_COOKIE).then(|| String::from(value))
+            })
+        });
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let mut trans = pool.begin().await?;
+    let session = match models::UserSession::by_token(&mut trans, &token).await? {
+        Some(session) if !session.is_expired() && session.has_full_access() => session,
+        _ => return Ok(None),
+    };
+    models::UserSession::touch(&mut trans, session.id).await?;
+    trans.commit().await?;
+
+    Ok(Some(session))
+}
+
+pub fn session_cookie(token: &str, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax{}",
+        SESSION_COOKIE,
+        token,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn page(title: &str, session: Option<&models::UserSession>, body: &str) -> Html<String> {
+    let user = match session {
+        Some(session) => format!(
+            "<span class=\"muted\">{}</span> <a href=\"/invitations\">Invitations</a> \
+            <a href=\"/tokens\">Tokens</a> \
+            <form method=\"post\" action=\"/logout\"><button>Log out</button></form>",
+            escape(&session.ident)
+        ),
+        None => String::from("<a href=\"/me\">Log in</a>"),
+    };
+
+    Html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+        <title>{} - registmily</title><style>{}</style></head><body>\
+        <nav><a href=\"/\"><b>registmily</b></a><span style=\"flex:1\"></span>{}</nav>\
+        <main>{}</main></body></html>",
+        escape(title),
+        STYLE,
+        user,
+        body
+    ))
+}
+
+fn not_found(session: Option<&models::UserSession>, what: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        page(
+            "Not found",
+            session,
+            &format!("<h1>Not found</h1><p>{}</p>", escape(what)),
+        ),
+    )
+        .into_response()
+}
+
+// Only http links are rendered so a repository url can't smuggle in javascript
+fn link(url: &str) -> String {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        format!("<a href=\"{0}\">{0}</a>", escape(url))
+    } else {
+        escape(url)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CrateListQuery {
+    q: Option<String>,
+}
+
+async fn crate_list(
+    Query(query): Query<CrateListQuery>,
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Html<String>, ApiError> {
+    let q = query.q.unwrap_or_default();
+
+    let mut trans = pool.begin().await?;
+    let crates = models::Crate::search(&mut trans, q.trim(), CRATES_PER_PAGE).await?;
+    let total = models::Crate::search_count(&mut trans, q.trim()).await?;
+
+    let mut body = format!(
+        "<h1>Crates</h1><form><input name=\"q\" value=\"{}\" placeholder=\"Search crates\"> \
+        <button>Search</button></form><p class=\"muted\">{} crates</p><table>",
+        escape(&q),
+        total
+    );
+    for krate in crates {
+        let _ = write!(
+            body,
+            "<tr><td><a href=\"/crates/{0}\">{0}</a></td><td>{1}</td><td>{2}</td></tr>",
+            escape(&krate.name),
+            escape(krate.max_version.as_deref().unwrap_or_default()),
+            escape(krate.description.as_deref().unwrap_or_default())
+        );
+    }
+    body.push_str("</table>");
+    if total > CRATES_PER_PAGE {
+        body.push_str("<p class=\"muted\">Refine your search to see more crates.</p>");
+    }
+
+    Ok(page("Crates", session.as_ref(), &body))
+}
+
+async fn crate_page(
+    Path(crate_name): Path<String>,
+    pool: Extension<PgPool>,
+    session: WebSession,
+) -> Result<Response, ApiError> {
+    render_crate(crate_name, None, pool, session).await
+}
+
+async fn crate_version_page(
+    Path((crate_name, version)): Path<(String, String)>,
+    pool: Extension<PgPool>,
+    session: WebSession,
+) -> Result<Response, ApiError> {
+    render_crate(crate_name, Some(version), pool, session).await
+}
+
+async fn render_crate(
+    crate_name: String,
+    version: Option<String>,
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Response, ApiError> {
+    let mut trans = pool.begin().await?;
+    let krate = match models::Crate::by_name(&mut trans, &crate_name).await? {
+        Some(krate) => krate,
+        None => return Ok(not_found(session.as_ref(), "This crate does not exist.")),
+    };
+    let owners = apiserver::owner_logins(&mut trans, &crate_name).await?;
+    let mut versions = models::CrateVersion::all_by_crate(&mut trans, &crate_name).await?;
+    let downloads = apiserver::download_totals(&mut trans, &crate_name).await?;
+    apiserver::sort_versions(&mut versions);
+
+    let shown = match &version {
+        Some(version) => versions.iter().find(|x| &x.version == version),
+        None => krate
+            .max_version
+            .as_ref()
+            .and_then(|max| versions.iter().find(|x| &x.version == max))
+            .or_else(|| versions.first()),
+    };
+    let shown = match shown {
+        Some(shown) => shown,
+        None => return Ok(not_found(session.as_ref(), "This version does not exist.")),
+    };
+
+    let mut body = format!(
+        "<h1>{} <span class=\"muted\">{}</span></h1>",
+        escape(&krate.name),
+        escape(&shown.version)
+    );
+    if shown.yanked {
+        body.push_str("<p class=\"yanked\">This version has been yanked.</p>");
+    }
+    if let Some(description) = &shown.description {
+        let _ = write!(body, "<p>{}</p>", escape(description));
+    }
+    let _ = write!(
+        body,
+        "<pre>{} = \"{}\"</pre><table>",
+        escape(&krate.name),
+        escape(&shown.version)
+    );
+    if let Some(license) = &shown.license {
+        let _ = write!(
+            body,
+            "<tr><th>License</th><td>{}</td></tr>",
+            escape(license)
+        );
+    }
+    if let Some(repository) = &shown.repository {
+        let _ = write!(
+            body,
+            "<tr><th>Repository</th><td>{}</td></tr>",
+            link(repository)
+        );
+    }
+    if let Some(rust_version) = &shown.rust_version {
+        let _ = write!(
+            body,
+            "<tr><th>Rust version</th><td>{}</td></tr>",
+            escape(rust_version)
+        );
+    }
+    if !shown.keywords.is_empty() {
+        let _ = write!(
+            body,
+            "<tr><th>Keywords</th><td>{}</td></tr>",
+            escape(&shown.keywords.join(", "))
+        );
+    }
+    if let Some(build) =
+        models::DocBuild::by_version(&mut trans, &crate_name, &shown.version).await?
+    {
+        let _ = write!(
+            body,
+            "<tr><th>Docs</th><td><a href=\"/docs/{}/{}\">{}</a></td></tr>",
+            escape(&shown.crate_name),
+            escape(&shown.version),
+            if build.doc_path.is_some() {
+                String::from("Documentation")
+            } else {
+                format!("Build {}", escape(&build.status))
+            }
+        );
+    }
+    let _ = write!(
+        body,
+        "<tr><th>Downloads</th><td>{}</td></tr><tr><th>Owners</th><td>{}</td></tr></table>",
+        downloads.values().sum::<i64>(),
+        escape(
+            &owners
+                .iter()
+                .map(|(login, _)| login.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    );
+
+    if let Some(models::VersionReadme {
+        readme: Some(text),
+        readme_file,
+        repository,
+    }) = models::CrateVersion::readme(&mut trans, &crate_name, &shown.version).await?
+    {
+        let _ = write!(
+            body,
+            "<h2>Readme</h2><article>{}</article>",
+            readme::render(&text, readme_file.as_deref(), repository.as_deref())
+        );
+    }
+
+    body.push_str("<h2>Dependencies</h2>");
+    let deps: Vec<registry::PublishDep> =
+        serde_json::from_value(shown.dependencies.clone()).unwrap_or_default();
+    if deps.is_empty() {
+        body.push_str("<p class=\"muted\">None</p>");
+    } else {
+        body.push_str("<table><tr><th>Crate</th><th>Requirement</th><th>Kind</th><th></th></tr>");
+        for dep in deps {
+            let mut notes = Vec::new();
+            if dep.optional {
+                notes.push(String::from("optional"));
+            }
+            if let Some(target) = dep.target.as_str() {
+                notes.push(format!("for {}", target));
+            }
+            if let Some(rename) = dep.explicit_name_in_toml.as_str() {
+                notes.push(format!("as {}", rename));
+            }
+            let _ = write!(
+                body,
+                "<tr><td>{}</td><td><code>{}</code></td><td>{}</td><td class=\"muted\">{}</td></tr>",
+                escape(&dep.name),
+                escape(&dep.version_req),
+                escape(&dep.kind),
+                escape(&notes.join(", "))
+            );
+        }
+        body.push_str("</table>");
+    }
+
+    body.push_str("<h2>Features</h2>");
+    let features: BTreeMap<String, Vec<String>> =
+        serde_json::from_value(shown.features.clone()).unwrap_or_default();
+    if features.is_empty() {
+        body.push_str("<p class=\"muted\">None</p>");
+    } else {
+        body.push_str("<table>");
+        for (feature, enables) in features {
+            let _ = write!(
+                body,
+                "<tr><td><code>{}</code></td><td>{}</td></tr>",
+                escape(&feature),
+                escape(&enables.join(", "))
+            );
+        }
+        body.push_str("</table>");
+    }
+
+    body.push_str(
+        "<h2>Versions</h2><table><tr><th>Version</th><th>Published</th><th>By</th>\
+        <th>Downloads</th><th></th></tr>",
+    );
+    for version in &versions {
+        let _ = write!(
+            body,
+            "<tr><td><a href=\"/crates/{0}/{1}\">{1}</a></td><td>{2}</td><td>{3}</td>\
+            <td>{4}</td><td class=\"yanked\">{5}</td></tr>",
+            escape(&version.crate_name),
+            escape(&version.version),
+            version.published_at.format("%Y-%m-%d"),
+            escape(version.published_by.as_deref().unwrap_or_default()),
+            downloads.get(&version.id).copied().unwrap_or_default(),
+            if version.yanked { "yanked" } else { "" }
+        );
+    }
+    body.push_str("</table>");
+
+    Ok(page(&krate.name, session.as_ref(), &body).into_response())
+}
+
+fn tokens_body(tokens: &[models::UserSession], message: &str) -> String {
+    let mut body = format!(
+        "<h1>Tokens</h1>{}<table><tr><th>Name</th><th>Scopes</th><th>Crates</th>\
+        <th>Expires</th><th>Last used</th><th></th></tr>",
+        message
+    );
+    for token in tokens {
+        let _ = write!(
+            body,
+            "<tr><td>{}<br><code>{}...</code></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+            <td><form method=\"post\" action=\"/tokens/{}/revoke\"><button>Revoke</button></form>\
+            </td></tr>",
+            escape(&token.name),
+            escape(&token.token_prefix),
+            escape(
+                &token
+                    .scopes
+                    .as_ref()
+                    .map(|x| x.join(", "))
+                    .unwrap_or_else(|| String::from("all"))
+            ),
+            escape(
+                &token
+                    .crate_patterns
+                    .as_ref()
+                    .map(|x| x.join(", "))
+                    .unwrap_or_else(|| String::from("all"))
+            ),
+            token
+                .expires_at
+                .map(|x| x.format("%Y-%m-%d").to_string())
+                .unwrap_or_else(|| String::from("never")),
+            token
+                .last_used_at
+                .map(|x| x.format("%Y-%m-%d").to_string())
+                .unwrap_or_else(|| String::from("never")),
+            token.id
+        );
+    }
+    body.push_str(
+        "</table><h2>New api token</h2><form method=\"post\" action=\"/tokens\"><p>\
+        <input name=\"name\" placeholder=\"Name\" required></p><p>",
+    );
+    for scope in [
+        models::TokenScope::PublishNew,
+        models::TokenScope::PublishUpdate,
+        models::TokenScope::Yank,
+        models::TokenScope::ChangeOwners,
+    ] {
+        let _ = write!(
+            body,
+            "<label><input type=\"checkbox\" name=\"scope\" value=\"{0}\"> {0}</label> ",
+            scope.as_str()
+        );
+    }
+    body.push_str(
+        "</p><p><input name=\"crate_patterns\" placeholder=\"Crates, e.g. foo-*, empty for all\">\
+        </p><p><input name=\"expires_in_days\" type=\"number\" min=\"1\" value=\"90\"> days</p>\
+        <button>Create</button></form>",
+    );
+    body
+}
+
+async fn tokens(
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Response, ApiError> {
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(Redirect::to(Uri::from_static("/me")).into_response()),
+    };
+
+    let mut trans = pool.begin().await?;
+    let tokens = models::UserSession::all_by_ident(&mut trans, &session.ident).await?;
+
+    Ok(page("Tokens", Some(&session), &tokens_body(&tokens, "")).into_response())
+}
+
+// Checkboxes send one `scope` pair each, which a struct can't collect
+async fn new_token(
+    Form(form): Form<Vec<(String, String)>>,
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Response, ApiError> {
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(Redirect::to(Uri::from_static("/me")).into_response()),
+    };
+
+    let mut new_token = apiserver::NewApiToken {
+        name: String::new(),
+        scopes: Vec::new(),
+        crate_patterns: None,
+        expires_in_days: None,
+    };
+    for (key, value) in form {
+        match key.as_str() {
+            "name" => new_token.name = value,
+            "scope" => new_token.scopes.push(value),
+            "crate_patterns" => {
+                let patterns: Vec<String> = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|x| !x.is_empty())
+                    .map(String::from)
+                    .collect();
+                new_token.crate_patterns = Some(patterns).filter(|x| !x.is_empty());
+            }
+            "expires_in_days" => new_token.expires_in_days = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    let mut trans = pool.begin().await?;
+    let message = match apiserver::create_api_token(&mut trans, &session.ident, &new_token).await {
+        Ok((_, plaintext)) => format!(
+            "<p>Your new token, it won't be shown again:</p><pre>{}</pre>",
+            escape(&plaintext)
+        ),
+        Err(ApiError(why, _)) => format!("<p class=\"error\">{}</p>", escape(&why)),
+    };
+    let tokens = models::UserSession::all_by_ident(&mut trans, &session.ident).await?;
+    trans.commit().await?;
+
+    Ok(page("Tokens", Some(&session), &tokens_body(&tokens, &message)).into_response())
+}
+
+async fn revoke_token(
+    Path(id): Path<i64>,
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Response, ApiError> {
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(Redirect::to(Uri::from_static("/me")).into_response()),
+    };
+
+    let mut trans = pool.begin().await?;
+    models::UserSession::delete_by_id(&mut trans, &session.ident, id).await?;
+    trans.commit().await?;
+
+    Ok(Redirect::see_other(Uri::from_static("/tokens")).into_response())
+}
+
+fn invitations_body(invitations: &[models::OwnerInvitation], message: &str) -> String {
+    let mut body = format!("<h1>Owner invitations</h1>{}", message);
+    if invitations.is_empty() {
+        body.push_str("<p class=\"muted\">Nobody invited you to own a crate.</p>");
+        return body;
+    }
+
+    body.push_str("<table><tr><th>Crate</th><th>Invited by</th><th>Expires</th><th></th></tr>");
+    for invitation in invitations {
+        let _ = write!(
+            body,
+            "<tr><td><a href=\"/crates/{0}\">{0}</a></td><td>{1}</td><td>{2}</td><td>\
+            <form method=\"post\" action=\"/invitations/{0}/accept\"><button>Accept</button></form> \
+            <form method=\"post\" action=\"/invitations/{0}/decline\"><button>Decline</button></form>\
+            </td></tr>",
+            escape(&invitation.crate_name),
+            escape(&invitation.inviter_ident),
+            invitation.expires_at.format("%Y-%m-%d")
+        );
+    }
+    body.push_str("</table>");
+    body
+}
+
+async fn invitations(
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Response, ApiError> {
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(Redirect::to(Uri::from_static("/me")).into_response()),
+    };
+
+    let mut trans = pool.begin().await?;
+    let invitations = models::OwnerInvitation::all_by_invitee(&mut trans, &session.ident).await?;
+
+    Ok(page(
+        "Owner invitations",
+        Some(&session),
+        &invitations_body(&invitations, ""),
+    )
+    .into_response())
+}
+
+async fn answer_invitation(
+    Path((crate_name, answer)): Path<(String, String)>,
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+    request_id: apiserver::RequestId,
+) -> Result<Response, ApiError> {
+    let session = match session {
+        Some(session) => session,
+        None => return Ok(Redirect::to(Uri::from_static("/me")).into_response()),
+    };
+    let accepted = match answer.as_str() {
+        "accept" => true,
+        "decline" => false,
+        _ => return Ok(not_found(Some(&session), "This page does not exist.")),
+    };
+
+    let mut trans = pool.begin().await?;
+    let outcome =
+        apiserver::respond_to_invitation(&mut trans, &session, &request_id, &crate_name, accepted)
+            .await;
+    // a stale invitation is dropped like an answered one, nothing else is kept after an error
+    let error = match outcome {
+        Ok(outcome) => {
+            trans.commit().await?;
+            match outcome {
+                apiserver::InvitationOutcome::Accepted | apiserver::InvitationOutcome::Declined => {
+                    None
+                }
+                apiserver::InvitationOutcome::Stale(ApiError(why, _)) => Some(why),
+            }
+        }
+        Err(ApiError(why, _)) => Some(why),
+    };
+    let message = match error {
+        Some(why) => format!("<p class=\"error\">{}</p>", escape(&why)),
+        None if accepted => format!("<p>You are now an owner of {}.</p>", escape(&crate_name)),
+        None => format!("<p>Declined owning {}.</p>", escape(&crate_name)),
+    };
+
+    let mut trans = pool.begin().await?;
+    let invitations = models::OwnerInvitation::all_by_invitee(&mut trans, &session.ident).await?;
+
+    Ok(page(
+        "Owner invitations",
+        Some(&session),
+        &invitations_body(&invitations, &message),
+    )
+    .into_response())
+}
+
+async fn logout(
+    pool: Extension<PgPool>,
+    WebSession(session): WebSession,
+) -> Result<Response, ApiError> {
+    if let Some(session) = session {
+        let mut trans = pool.begin().await?;
+        models::UserSession::delete_by_id(&mut trans, &session.ident, session.id).await?;
+        trans.commit().await?;
+    }
+
+    Ok((
+        Headers([(
+            header::SET_COOKIE,
+            format!("{}=; Path=/; HttpOnly; Max-Age=0", SESSION_COOKIE),
+        )]),
+        Redirect::see_other(Uri::from_static("/")),
+    )
+        .into_response())
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(crate_list))
+        .route("/crates/:crate_name", get(crate_page))
+        .route("/crates/:crate_name/:version", get(crate_version_page))
+        .route("/tokens", get(tokens).post(new_token))
+        .route("/tokens/:id/revoke", post(revoke_token))
+        .route("/invitations", get(invitations))
+        .route("/invitations/:crate_name/:answer", post(answer_invitation))
+        .route("/logout", post(logout))
+}
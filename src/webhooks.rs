@@ -0,0 +1,289 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::types::chrono::{self, Utc};
+use sqlx::PgPool;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use url::{Host, Url};
+
+use crate::models;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 20;
+// deliveries in flight at once, so one slow receiver doesn't hold up everyone else
+const CONCURRENT_DELIVERIES: usize = 20;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// a claimed delivery is tried again after this if the worker never reports back
+const LEASE: Duration = Duration::from_secs(5 * 60);
+// with the backoff below that's a little over a day of retrying
+pub const MAX_ATTEMPTS: i32 = 15;
+
+pub const SIGNATURE_HEADER: &str = "x-registmily-signature-256";
+pub const EVENT_HEADER: &str = "x-registmily-event";
+pub const DELIVERY_HEADER: &str = "x-registmily-delivery";
+
+// Sends queued deliveries until they get a 2xx answer or run out of attempts
+#[derive(Clone)]
+pub struct Deliverer {
+    pool: PgPool,
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+pub fn spawn(pool: PgPool, allowed_hosts: Vec<String>) -> JoinHandle<()> {
+    let deliverer = Deliverer {
+        pool,
+        allowed_hosts: Arc::new(allowed_hosts),
+    };
+
+    tokio::spawn(deliverer.run())
+}
+
+// `sha256=<hex>`, the HMAC of the body with the webhook secret as the key
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    // hmac accepts keys of any length, so this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// Webhooks can only call http(s) urls on hosts that aren't obviously internal, unless
+// they're in `allowed_hosts`
+pub fn validate_url(url: &str, allowed_hosts: &[String]) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|why| why.to_string())?;
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return Err(format!("unsupported url scheme {}", url.scheme()));
+    }
+    if is_allowed_host(&url, allowed_hosts) {
+        return Ok(url);
+    }
+
+    match url.host() {
+        None => return Err(String::from("the url has no host")),
+        Some(Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_lowercase();
+            if domain == "localhost" || domain.ends_with(".localhost") {
+                return Err(format!("{} is not a public host", domain));
+            }
+        }
+        Some(Host::Ipv4(ip)) if !is_public_address(IpAddr::V4(ip)) => {
+            return Err(format!("{} is not a public address", ip))
+        }
+        Some(Host::Ipv6(ip)) if !is_public_address(IpAddr::V6(ip)) => {
+            return Err(format!("{} is not a public address", ip))
+        }
+        Some(_) => {}
+    }
+
+    Ok(url)
+}
+
+// Hosts are compared without the brackets around ipv6 addresses and a trailing dot
+fn normalize_host(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+// Admins can let webhooks call internal hosts, e.g. the CI server in the same network
+fn is_allowed_host(url: &Url, allowed_hosts: &[String]) -> bool {
+    let host = match url.host_str() {
+        Some(host) => normalize_host(host),
+        None => return false,
+    };
+    allowed_hosts.iter().any(|x| normalize_host(x) == host)
+}
+
+// Resolves the host as well, a public name can still point into the internal network.
+// Done again right before every delivery since dns answers change, the delivery then
+// connects to the returned address so a second lookup can't answer differently.
+pub async fn check_url(url: &str, allowed_hosts: &[String]) -> Result<(Url, SocketAddr), String> {
+    let url = validate_url(url, allowed_hosts)?;
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs: Vec<SocketAddr> =
+        tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port))
+            .await
+            .map_err(|why| format!("failed to resolve {}: {}", host, why))?
+            .collect();
+    if !is_allowed_host(&url, allowed_hosts) {
+        if let Some(addr) = addrs.iter().find(|x| !is_public_address(x.ip())) {
+            return Err(format!(
+                "{} resolves to {}, which is not a public address",
+                host,
+                addr.ip()
+            ));
+        }
+    }
+
+    match addrs.first() {
+        Some(addr) => Ok((url, *addr)),
+        None => Err(format!("{} doesn't resolve to any address", host)),
+    }
+}
+
+// Sends requests for the url's host to `addr` only. A redirect could point anywhere,
+// including places `check_url` turned down, so they aren't followed.
+fn pinned_client(url: &Url, addr: SocketAddr) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(REQUEST_TIMEOUT);
+    match url.host() {
+        Some(Host::Domain(domain)) => builder.resolve(domain, addr),
+        _ => builder,
+    }
+    .build()
+}
+
+// Whether the address is reachable over the internet, as opposed to loopback, private,
+// link local and other special purpose ranges
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(ip);
+            }
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // link local fe80::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                // documentation 2001:db8::/32
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                // ipv4 compatible and translated addresses
+                || segments[..6] == [0, 0, 0, 0, 0, 0]
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0])
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network" 0.0.0.0/8
+        || octets[0] == 0
+        // shared address space 100.64.0.0/10
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // ietf protocol assignments 192.0.0.0/24
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // benchmarking 198.18.0.0/15
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        // reserved 240.0.0.0/4
+        || octets[0] >= 240)
+}
+
+// 30 seconds after the first attempt, doubling up to 6 hours
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.clamp(1, 16) as u32 - 1;
+    Duration::from_secs(30 * 2u64.pow(exponent)).min(Duration::from_secs(6 * 60 * 60))
+}
+
+impl Deliverer {
+    async fn run(self) {
+        info!("Webhook deliverer started");
+        let permits = Arc::new(Semaphore::new(CONCURRENT_DELIVERIES));
+
+        loop {
+            match self.claim_due().await {
+                Ok(deliveries) if !deliveries.is_empty() => {
+                    for delivery in deliveries {
+                        let permit = permits.clone().acquire_owned().await.unwrap();
+                        let deliverer = self.clone();
+                        tokio::spawn(async move {
+                            deliverer.deliver(delivery).await;
+                            drop(permit);
+                        });
+                    }
+                }
+                Ok(_) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(err) => {
+                    error!("Failed to fetch due webhook deliveries: {}", err);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn claim_due(&self) -> models::DbResult<Vec<models::DueWebhookDelivery>> {
+        let lease_until = Utc::now() + chrono::Duration::from_std(LEASE).unwrap();
+
+        let mut trans = self.pool.begin().await?;
+        let deliveries =
+            models::WebhookDelivery::claim_due(&mut trans, BATCH_SIZE, lease_until).await?;
+        trans.commit().await?;
+        Ok(deliveries)
+    }
+
+    async fn deliver(&self, delivery: models::DueWebhookDelivery) {
+        let result = self.send(&delivery).await;
+
+        let next_attempt_at = if delivery.attempts < MAX_ATTEMPTS {
+            Some(Utc::now() + chrono::Duration::from_std(retry_delay(delivery.attempts)).unwrap())
+        } else {
+            None
+        };
+        if let Err(why) = &result {
+            warn!(
+                "Webhook delivery {} to {} failed (attempt {}): {}",
+                delivery.id, delivery.url, delivery.attempts, why
+            );
+        }
+
+        let stored: models::DbResult<()> = async {
+            let mut trans = self.pool.begin().await?;
+            match &result {
+                Ok(()) => models::WebhookDelivery::delivered(&mut trans, delivery.id).await?,
+                Err(why) => {
+                    models::WebhookDelivery::failed(&mut trans, delivery.id, why, next_attempt_at)
+                        .await?
+                }
+            }
+            trans.commit().await
+        }
+        .await;
+        if let Err(err) = stored {
+            error!("Failed to store the webhook delivery result: {}", err);
+        }
+    }
+
+    async fn send(&self, delivery: &models::DueWebhookDelivery) -> Result<(), String> {
+        let (url, addr) = check_url(&delivery.url, &self.allowed_hosts).await?;
+        let client = pinned_client(&url, addr).map_err(|x| x.to_string())?;
+
+        // signed exactly as sent, receivers have to check the raw body
+        let body = serde_json::to_vec(&delivery.payload).map_err(|x| x.to_string())?;
+
+        let response = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &delivery.event)
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .header(SIGNATURE_HEADER, signature(&delivery.secret, &body))
+            .body(body)
+            .send()
+            .await
+            .map_err(|x| x.to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("the webhook answered with {}", response.status()))
+        }
+    }
+}
@@ -0,0 +1,49 @@
+// Every test crate only uses some of these
+#![allow(dead_code)]
+
+use flate2::{write::GzEncoder, Compression};
+
+pub enum Entry<'a> {
+    File(&'a str, &'a [u8]),
+    Symlink(&'a str, &'a str),
+}
+
+// A gzipped `.crate` tarball. The paths are written into the headers by hand since
+// `tar::Builder` refuses the bad ones.
+pub fn crate_file(entries: &[Entry]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        let (path, data): (&str, &[u8]) = match entry {
+            Entry::File(path, data) => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(data.len() as u64);
+                (path, data)
+            }
+            Entry::Symlink(path, target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+                (path, &[])
+            }
+        };
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+// A `.crate` file with just enough in it to pass the publish checks
+pub fn minimal_crate_file(name: &str, vers: &str) -> Vec<u8> {
+    let root = format!("{}-{}", name, vers);
+    let manifest = format!("[package]\nname = \"{}\"\nversion = \"{}\"\n", name, vers);
+
+    crate_file(&[
+        Entry::File(&format!("{}/Cargo.toml", root), manifest.as_bytes()),
+        Entry::File(&format!("{}/src/lib.rs", root), b""),
+    ])
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use std::io::Write;
+
+use common::{crate_file, Entry};
+use flate2::{write::GzEncoder, Compression};
+use registmily::crate_file::{validate, CrateFileError};
+
+const MANIFEST: &[u8] = b"[package]\nname = \"foo\"\nversion = \"1.0.0\"\n";
+const MAX_SIZE: u64 = 1024 * 1024;
+
+#[test]
+fn test_valid() {
+    let file = crate_file(&[
+        Entry::File("foo-1.0.0/Cargo.toml", MANIFEST),
+        Entry::File("foo-1.0.0/src/lib.rs", b"pub fn foo() {}"),
+        Entry::Symlink("foo-1.0.0/src/link.rs", "../src/lib.rs"),
+    ]);
+
+    assert!(validate(&file, "foo", "1.0.0", MAX_SIZE).is_ok());
+}
+
+#[test]
+fn test_outside_root() {
+    for path in [
+        "bar-1.0.0/src/lib.rs",
+        "/etc/passwd",
+        "foo-1.0.0/../evil.rs",
+    ] {
+        let file = crate_file(&[
+            Entry::File("foo-1.0.0/Cargo.toml", MANIFEST),
+            Entry::File(path, b"evil"),
+        ]);
+
+        assert!(
+            matches!(
+                validate(&file, "foo", "1.0.0", MAX_SIZE),
+                Err(CrateFileError::OutsideRoot(..))
+            ),
+            "{}",
+            path
+        );
+    }
+}
+
+#[test]
+fn test_link_outside_root() {
+    for target in ["../../etc/passwd", "/etc/passwd", "../bar-1.0.0/src/lib.rs"] {
+        let file = crate_file(&[
+            Entry::File("foo-1.0.0/Cargo.toml", MANIFEST),
+            Entry::Symlink("foo-1.0.0/link", target),
+        ]);
+
+        assert!(
+            matches!(
+                validate(&file, "foo", "1.0.0", MAX_SIZE),
+                Err(CrateFileError::LinkOutsideRoot(..))
+            ),
+            "{}",
+            target
+        );
+    }
+}
+
+#[test]
+fn test_manifest() {
+    let file = crate_file(&[Entry::File("foo-1.0.0/src/lib.rs", b"")]);
+    assert!(matches!(
+        validate(&file, "foo", "1.0.0", MAX_SIZE),
+        Err(CrateFileError::MissingManifest)
+    ));
+
+    let file = crate_file(&[Entry::File("foo-1.0.0/Cargo.toml", b"[package")]);
+    assert!(matches!(
+        validate(&file, "foo", "1.0.0", MAX_SIZE),
+        Err(CrateFileError::InvalidManifest(_))
+    ));
+
+    let file = crate_file(&[Entry::File(
+        "foo-1.0.0/Cargo.toml",
+        b"[package]\nname = \"bar\"\nversion = \"1.0.0\"\n",
+    )]);
+    assert!(matches!(
+        validate(&file, "foo", "1.0.0", MAX_SIZE),
+        Err(CrateFileError::NameMismatch(..))
+    ));
+
+    let file = crate_file(&[Entry::File(
+        "foo-1.0.0/Cargo.toml",
+        b"[package]\nname = \"foo\"\nversion = \"2.0.0\"\n",
+    )]);
+    assert!(matches!(
+        validate(&file, "foo", "1.0.0", MAX_SIZE),
+        Err(CrateFileError::VersionMismatch(..))
+    ));
+}
+
+#[test]
+fn test_too_large() {
+    let zeroes = vec![0; 4 * 1024 * 1024];
+    let file = crate_file(&[
+        Entry::File("foo-1.0.0/Cargo.toml", MANIFEST),
+        Entry::File("foo-1.0.0/zeroes", &zeroes),
+    ]);
+
+    assert!(matches!(
+        validate(&file, "foo", "1.0.0", MAX_SIZE),
+        Err(CrateFileError::TooLarge(MAX_SIZE))
+    ));
+}
+
+#[test]
+fn test_not_a_tarball() {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"not a tarball").unwrap();
+    let file = encoder.finish().unwrap();
+
+    assert!(validate(&file, "foo", "1.0.0", MAX_SIZE).is_err());
+    assert!(matches!(
+        validate(b"not even gzip", "foo", "1.0.0", MAX_SIZE),
+        Err(CrateFileError::InvalidArchive(_))
+    ));
+}
@@ -1,4 +1,6 @@
 use registmily::models;
+use registmily::registry;
+use sqlx::types::chrono::{Duration, Utc};
 
 #[sqlx_database_tester::test(pool(variable = "pool"))]
 pub async fn db_test() -> Result<(), Box<dyn std::error::Error>> {
@@ -7,13 +9,27 @@ pub async fn db_test() -> Result<(), Box<dyn std::error::Error>> {
     assert!(!models::User::exists_by_ident(&mut trans, "peter").await?);
     models::User::new(&mut trans, "peter").await?;
     assert!(models::User::exists_by_ident(&mut trans, "peter").await?);
-    let session = models::UserSession::new(&mut trans, "peter").await?;
-    let session_from_tok = models::UserSession::by_token(&mut trans, &session.token)
+    assert!(
+        !models::User::by_ident(&mut trans, "peter")
+            .await?
+            .unwrap()
+            .disabled
+    );
+    let (session, token) =
+        models::UserSession::new(&mut trans, "peter", Utc::now() + Duration::days(30)).await?;
+    assert_ne!(session.token_hash, token);
+    assert!(token.starts_with(&session.token_prefix));
+    let session_from_tok = models::UserSession::by_token(&mut trans, &token)
         .await?
         .unwrap();
 
     assert_eq!(session.ident, session_from_tok.ident);
-    assert_eq!(session.token, session_from_tok.token);
+    assert_eq!(session.token_hash, session_from_tok.token_hash);
+    assert!(
+        models::UserSession::by_token(&mut trans, &session.token_hash)
+            .await?
+            .is_none()
+    );
 
     let new_crate = models::Crate::new(&mut trans, "owo").await?;
     models::CrateOwner::new(&mut trans, &new_crate.name, "peter").await?;
@@ -22,12 +38,517 @@ pub async fn db_test() -> Result<(), Box<dyn std::error::Error>> {
     assert_eq!(owners.len(), 1);
     assert_eq!(owners[0].user_ident, "peter");
 
+    assert!(models::Crate::search(&mut trans, "ow", 10)
+        .await?
+        .is_empty());
+    models::Crate::set_newest_version(&mut trans, "owo", "0.1.0", Some("cute crate")).await?;
+    let found = models::Crate::search(&mut trans, "CUTE", 10).await?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].max_version.as_deref(), Some("0.1.0"));
+    assert_eq!(models::Crate::search_count(&mut trans, "ow").await?, 1);
+
+    let pkg = registry::PublishPackage {
+        name: String::from("owo"),
+        vers: String::from("0.1.0"),
+        description: serde_json::json!("cute crate"),
+        keywords: vec![serde_json::json!("cute")],
+        rust_version: serde_json::json!("1.56"),
+        ..Default::default()
+    };
+    let version = models::CrateVersion::new(&mut trans, &pkg, "abcd", Some("peter")).await?;
+    assert_eq!(version.published_by.as_deref(), Some("peter"));
+    assert_eq!(version.keywords, vec![String::from("cute")]);
+    assert_eq!(version.license, None);
+    assert_eq!(version.features, serde_json::json!({}));
+    assert!(models::CrateVersion::exists(&mut trans, "owo", "0.1.0").await?);
+
+    models::CrateVersion::set_yanked(&mut trans, "owo", "0.1.0", true).await?;
+    let versions = models::CrateVersion::all_by_crate(&mut trans, "owo").await?;
+    assert_eq!(versions.len(), 1);
+    assert!(versions[0].yanked);
+    assert_eq!(versions[0].rust_version.as_deref(), Some("1.56"));
+
     models::Crate::delete(&mut trans, "owo").await?;
 
     assert!(!models::Crate::exists_by_ident(&mut trans, "owo").await?);
 
     let owners = models::CrateOwner::all_owners(&mut trans, "owo").await?;
     assert_eq!(owners.len(), 0);
+    assert!(!models::CrateVersion::exists(&mut trans, "owo", "0.1.0").await?);
+
+    models::User::set_disabled(&mut trans, "peter", true).await?;
+    let users = models::User::all(&mut trans).await?;
+    assert_eq!(users.len(), 1);
+    assert!(users[0].disabled);
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn token_test() -> Result<(), Box<dyn std::error::Error>> {
+    use models::TokenScope;
+
+    let mut trans = pool.begin().await?;
+
+    models::User::new(&mut trans, "peter").await?;
+    let (login, _) =
+        models::UserSession::new(&mut trans, "peter", Utc::now() + Duration::days(30)).await?;
+    assert!(login.has_full_access());
+    assert!(!login.is_expired());
+    assert!(login.allows(TokenScope::ChangeOwners, "owo"));
+
+    let (token, _) = models::UserSession::new_api_token(
+        &mut trans,
+        "peter",
+        "ci",
+        Some(&[TokenScope::PublishUpdate]),
+        Some(&[String::from("owo-*")][..]),
+        Utc::now() + Duration::days(1),
+    )
+    .await?;
+    assert!(!token.has_full_access());
+    assert!(!token.is_expired());
+    assert!(token.allows(TokenScope::PublishUpdate, "owo-macros"));
+    assert!(!token.allows(TokenScope::PublishNew, "owo-macros"));
+    assert!(!token.allows(TokenScope::PublishUpdate, "owo"));
+    assert!(models::UserSession::exists_by_name(&mut trans, "peter", "ci").await?);
+
+    let (expired, _) = models::UserSession::new_api_token(
+        &mut trans,
+        "peter",
+        "old",
+        Some(&[TokenScope::Yank]),
+        None,
+        Utc::now() - Duration::days(1),
+    )
+    .await?;
+    assert!(expired.is_expired());
+    assert!(expired.allows(TokenScope::Yank, "uwu"));
+
+    let tokens = models::UserSession::all_by_ident(&mut trans, "peter").await?;
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[1].scopes, Some(vec![String::from("publish-update")]));
+
+    assert!(!models::UserSession::delete_by_id(&mut trans, "emily", expired.id).await?);
+    assert!(models::UserSession::delete_by_id(&mut trans, "peter", expired.id).await?);
+    assert_eq!(
+        models::UserSession::all_by_ident(&mut trans, "peter")
+            .await?
+            .len(),
+        2
+    );
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn download_test() -> Result<(), Box<dyn std::error::Error>> {
+    use registmily::downloads;
+
+    let mut trans = pool.begin().await?;
+
+    models::User::new(&mut trans, "peter").await?;
+    models::Crate::new(&mut trans, "owo").await?;
+    let pkg = registry::PublishPackage {
+        name: String::from("owo"),
+        vers: String::from("0.1.0"),
+        ..Default::default()
+    };
+    let version = models::CrateVersion::new(&mut trans, &pkg, "abcd", Some("peter")).await?;
+
+    trans.commit().await?;
+
+    let (counter, handle) = downloads::spawn(pool.clone(), std::time::Duration::from_secs(3600));
+    counter.record(downloads::Download::Checksum(String::from("abcd")));
+    counter.record(downloads::Download::Version(
+        String::from("owo"),
+        String::from("0.1.0"),
+    ));
+    counter.record(downloads::Download::Checksum(String::from("unknown")));
+    counter.record(downloads::Download::Version(
+        String::from("owo"),
+        String::from("0.2.0"),
+    ));
+    // dropping the last counter flushes everything that's still pending
+    drop(counter);
+    handle.await?;
+
+    let mut trans = pool.begin().await?;
+
+    let totals = models::VersionDownload::totals_by_crate(&mut trans, "owo").await?;
+    assert_eq!(totals.len(), 1);
+    assert_eq!(totals[0].version_id, version.id);
+    assert_eq!(totals[0].downloads, 2);
+
+    let today = Utc::now().naive_utc().date();
+    models::VersionDownload::add(&mut trans, "abcd", today, 3).await?;
+    models::VersionDownload::add(&mut trans, "abcd", today - Duration::days(10), 1).await?;
+    let days = models::VersionDownload::all_by_crate(&mut trans, "owo", today).await?;
+    assert_eq!(days.len(), 1);
+    assert_eq!(days[0].downloads, 5);
+    let totals = models::VersionDownload::totals_by_crate(&mut trans, "owo").await?;
+    assert_eq!(totals[0].downloads, 6);
+
+    // the same file published as another version, its checksum doesn't tell them apart
+    let pkg = registry::PublishPackage {
+        name: String::from("owo"),
+        vers: String::from("0.1.1"),
+        ..Default::default()
+    };
+    models::CrateVersion::new(&mut trans, &pkg, "abcd", Some("peter")).await?;
+    models::VersionDownload::add(&mut trans, "abcd", today, 1).await?;
+    models::VersionDownload::add_by_version(&mut trans, "owo", "0.1.1", today, 2).await?;
+    let totals = models::VersionDownload::totals_by_crate(&mut trans, "owo").await?;
+    assert_eq!(totals.iter().map(|x| x.downloads).sum::<i64>(), 8);
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn doc_build_test() -> Result<(), Box<dyn std::error::Error>> {
+    let mut trans = pool.begin().await?;
+
+    models::User::new(&mut trans, "peter").await?;
+    models::Crate::new(&mut trans, "owo").await?;
+    let pkg = registry::PublishPackage {
+        name: String::from("owo"),
+        vers: String::from("0.1.0"),
+        ..Default::default()
+    };
+    let version = models::CrateVersion::new(&mut trans, &pkg, "abcd", Some("peter")).await?;
+
+    assert!(models::DocBuild::start_next(&mut trans).await?.is_none());
+    models::DocBuild::queue(&mut trans, version.id).await?;
+
+    let build = models::DocBuild::start_next(&mut trans).await?.unwrap();
+    assert_eq!(build.crate_name, "owo");
+    assert_eq!(build.checksum, "abcd");
+    assert_eq!(build.status, "building");
+    assert!(models::DocBuild::start_next(&mut trans).await?.is_none());
+
+    // still within its timeout, so another builder might be working on it
+    models::DocBuild::requeue_unfinished(&mut trans, std::time::Duration::from_secs(60)).await?;
+    assert!(models::DocBuild::start_next(&mut trans).await?.is_none());
+
+    models::DocBuild::requeue_unfinished(&mut trans, std::time::Duration::ZERO).await?;
+    let build = models::DocBuild::start_next(&mut trans).await?.unwrap();
+    models::DocBuild::finish(
+        &mut trans,
+        build.version_id,
+        models::DocBuildStatus::Succeeded,
+        "Documenting owo",
+        Some("owo/index.html"),
+    )
+    .await?;
+
+    let build = models::DocBuild::by_version(&mut trans, "owo", "0.1.0")
+        .await?
+        .unwrap();
+    assert_eq!(build.status, "succeeded");
+    assert_eq!(build.doc_path.as_deref(), Some("owo/index.html"));
+    assert!(build.finished_at.is_some());
+
+    models::DocBuild::queue(&mut trans, version.id).await?;
+    let build = models::DocBuild::by_version(&mut trans, "owo", "0.1.0")
+        .await?
+        .unwrap();
+    assert_eq!(build.status, "queued");
+    assert_eq!(build.doc_path, None);
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn audit_test() -> Result<(), Box<dyn std::error::Error>> {
+    let mut trans = pool.begin().await?;
+
+    let actor = models::AuditActor {
+        ident: "peter",
+        token_id: Some(1),
+        token_name: Some("ci"),
+        request_id: Some("req1"),
+    };
+    models::AuditEvent::record(
+        &mut trans,
+        &actor,
+        models::AuditAction::Publish,
+        "owo",
+        Some("0.1.0"),
+        serde_json::json!({ "checksum": "abcd" }),
+    )
+    .await?;
+    models::AuditEvent::record(
+        &mut trans,
+        &actor,
+        models::AuditAction::Yank,
+        "owo",
+        Some("0.1.0"),
+        serde_json::json!({}),
+    )
+    .await?;
+    models::AuditEvent::record(
+        &mut trans,
+        &models::AuditActor::cli(),
+        models::AuditAction::Delete,
+        "uwu",
+        None,
+        serde_json::json!({}),
+    )
+    .await?;
+
+    let all = models::AuditFilter::default();
+    let events = models::AuditEvent::search(&mut trans, &all, None, 2).await?;
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].action, "delete");
+    assert_eq!(events[0].actor, "cli");
+    assert_eq!(events[0].token_id, None);
+    let events = models::AuditEvent::search(&mut trans, &all, Some(events[1].id), 2).await?;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].action, "publish");
+    assert_eq!(events[0].request_id.as_deref(), Some("req1"));
+    assert_eq!(events[0].details["checksum"], "abcd");
+
+    let filter = models::AuditFilter {
+        crate_name: Some(String::from("owo")),
+        action: Some(models::AuditAction::Yank),
+        ..Default::default()
+    };
+    let events = models::AuditEvent::export(&mut trans, &filter).await?;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].token_name.as_deref(), Some("ci"));
+
+    let filter = models::AuditFilter {
+        since: Some(Utc::now() + Duration::days(1)),
+        ..Default::default()
+    };
+    assert!(models::AuditEvent::export(&mut trans, &filter)
+        .await?
+        .is_empty());
+    assert_eq!(models::AuditEvent::export(&mut trans, &all).await?.len(), 3);
+    trans.commit().await?;
+
+    // nothing can be changed or removed after the fact
+    let mut trans = pool.begin().await?;
+    assert!(sqlx::query("DELETE FROM audit_events")
+        .execute(&mut trans)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn team_test() -> Result<(), Box<dyn std::error::Error>> {
+    let mut trans = pool.begin().await?;
+
+    models::User::new(&mut trans, "peter").await?;
+    models::User::new(&mut trans, "paul").await?;
+    models::Crate::new(&mut trans, "owo").await?;
+    models::CrateOwner::new(&mut trans, "owo", "peter").await?;
+
+    models::Team::new(&mut trans, "backend").await?;
+    assert!(models::Team::exists(&mut trans, "backend").await?);
+    models::TeamMember::new(&mut trans, "backend", "paul").await?;
+    models::TeamMember::new(&mut trans, "backend", "paul").await?;
+    assert_eq!(
+        models::TeamMember::all_by_team(&mut trans, "backend")
+            .await?
+            .len(),
+        1
+    );
+
+    assert!(models::CrateOwner::exists_or_team_member(&mut trans, "owo", "peter").await?);
+    assert!(!models::CrateOwner::exists_or_team_member(&mut trans, "owo", "paul").await?);
+    models::CrateTeamOwner::new(&mut trans, "owo", "backend").await?;
+    assert!(models::CrateOwner::exists_or_team_member(&mut trans, "owo", "paul").await?);
+    assert!(!models::CrateOwner::exists(&mut trans, "owo", "paul").await?);
+
+    // the team keeps the crate when its last user owner is gone
+    models::CrateOwner::delete(&mut trans, "owo", "peter").await?;
+    assert!(models::CrateOwner::any_exists(&mut trans, "owo").await?);
+    let teams = models::CrateTeamOwner::all_owners(&mut trans, "owo").await?;
+    assert_eq!(teams.len(), 1);
+    assert_eq!(teams[0].team_name, "backend");
+
+    models::TeamMember::delete(&mut trans, "backend", "paul").await?;
+    assert!(!models::CrateOwner::exists_or_team_member(&mut trans, "owo", "paul").await?);
+
+    models::Team::delete(&mut trans, "backend").await?;
+    assert!(models::CrateTeamOwner::all_owners(&mut trans, "owo")
+        .await?
+        .is_empty());
+    assert!(!models::CrateOwner::any_exists(&mut trans, "owo").await?);
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn owner_invitation_test() -> Result<(), Box<dyn std::error::Error>> {
+    let mut trans = pool.begin().await?;
+
+    models::User::new(&mut trans, "peter").await?;
+    models::User::new(&mut trans, "paul").await?;
+    models::Crate::new(&mut trans, "owo").await?;
+    models::CrateOwner::new(&mut trans, "owo", "peter").await?;
+
+    models::OwnerInvitation::new(
+        &mut trans,
+        "owo",
+        "paul",
+        "peter",
+        Utc::now() - Duration::days(1),
+    )
+    .await?;
+    // expired invitations can't be accepted anymore
+    assert!(models::OwnerInvitation::pending(&mut trans, "owo", "paul")
+        .await?
+        .is_none());
+    assert!(models::OwnerInvitation::all_by_invitee(&mut trans, "paul")
+        .await?
+        .is_empty());
+
+    // inviting again replaces the expired invitation
+    models::OwnerInvitation::new(
+        &mut trans,
+        "owo",
+        "paul",
+        "peter",
+        Utc::now() + Duration::days(30),
+    )
+    .await?;
+    let invitations = models::OwnerInvitation::all_by_invitee(&mut trans, "paul").await?;
+    assert_eq!(invitations.len(), 1);
+    assert_eq!(invitations[0].crate_name, "owo");
+    assert_eq!(invitations[0].inviter_ident, "peter");
+    assert!(models::OwnerInvitation::pending(&mut trans, "owo", "paul")
+        .await?
+        .is_some());
+    assert!(!models::CrateOwner::exists(&mut trans, "owo", "paul").await?);
+
+    assert!(models::OwnerInvitation::delete(&mut trans, "owo", "paul").await?);
+    assert!(!models::OwnerInvitation::delete(&mut trans, "owo", "paul").await?);
+
+    models::OwnerInvitation::new(
+        &mut trans,
+        "owo",
+        "paul",
+        "peter",
+        Utc::now() + Duration::days(30),
+    )
+    .await?;
+    models::OwnerInvitation::delete_by_inviter(&mut trans, "owo", "peter").await?;
+    assert!(models::OwnerInvitation::pending(&mut trans, "owo", "paul")
+        .await?
+        .is_none());
+
+    Ok(())
+}
+
+#[sqlx_database_tester::test(pool(variable = "pool"))]
+pub async fn webhook_test() -> Result<(), Box<dyn std::error::Error>> {
+    let mut trans = pool.begin().await?;
+
+    models::User::new(&mut trans, "peter").await?;
+    models::Crate::new(&mut trans, "owo").await?;
+    models::Crate::new(&mut trans, "uwu").await?;
+
+    let crate_hook = models::Webhook::new(
+        &mut trans,
+        Some("owo"),
+        "https://ci.example.com/owo",
+        "secret",
+        Some(&[String::from("publish")]),
+        "peter",
+    )
+    .await?;
+    let registry_hook = models::Webhook::new(
+        &mut trans,
+        None,
+        "https://ci.example.com/all",
+        "secret",
+        None,
+        "cli",
+    )
+    .await?;
+    assert_eq!(
+        models::Webhook::all_by_crate(&mut trans, None).await?.len(),
+        1
+    );
+    assert!(
+        models::Webhook::by_id(&mut trans, crate_hook.id, Some("uwu"))
+            .await?
+            .is_none()
+    );
+
+    let actor = models::AuditActor::cli();
+    models::AuditEvent::record(
+        &mut trans,
+        &actor,
+        models::AuditAction::Publish,
+        "owo",
+        Some("0.1.0"),
+        serde_json::json!({}),
+    )
+    .await?;
+    models::AuditEvent::record(
+        &mut trans,
+        &actor,
+        models::AuditAction::Yank,
+        "owo",
+        Some("0.1.0"),
+        serde_json::json!({}),
+    )
+    .await?;
+    models::AuditEvent::record(
+        &mut trans,
+        &actor,
+        models::AuditAction::Publish,
+        "uwu",
+        Some("0.1.0"),
+        serde_json::json!({}),
+    )
+    .await?;
+
+    // the crate webhook only wants publishes of owo, the registry webhook wants everything
+    let crate_deliveries =
+        models::WebhookDelivery::all_by_webhook(&mut trans, crate_hook.id, 10).await?;
+    assert_eq!(crate_deliveries.len(), 1);
+    assert_eq!(crate_deliveries[0].payload["crate"], "owo");
+    assert_eq!(crate_deliveries[0].payload["version"], "0.1.0");
+    let registry_deliveries =
+        models::WebhookDelivery::all_by_webhook(&mut trans, registry_hook.id, 10).await?;
+    assert_eq!(registry_deliveries.len(), 3);
+
+    let lease = Utc::now() + Duration::minutes(5);
+    let due = models::WebhookDelivery::claim_due(&mut trans, 10, lease).await?;
+    assert_eq!(due.len(), 4);
+    assert!(due.iter().all(|x| x.attempts == 1));
+    assert!(models::WebhookDelivery::claim_due(&mut trans, 10, lease)
+        .await?
+        .is_empty());
+
+    models::WebhookDelivery::delivered(&mut trans, due[0].id).await?;
+    // due right away, even though now() stays at the start of the transaction
+    let retry_at = Utc::now() - Duration::minutes(1);
+    models::WebhookDelivery::failed(&mut trans, due[1].id, "timed out", Some(retry_at)).await?;
+    models::WebhookDelivery::failed(&mut trans, due[2].id, "gone", None).await?;
+
+    let due = models::WebhookDelivery::claim_due(&mut trans, 10, lease).await?;
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].attempts, 2);
+
+    let statuses: Vec<String> =
+        models::WebhookDelivery::all_by_webhook(&mut trans, registry_hook.id, 10)
+            .await?
+            .into_iter()
+            .chain(models::WebhookDelivery::all_by_webhook(&mut trans, crate_hook.id, 10).await?)
+            .map(|x| x.status)
+            .collect();
+    assert_eq!(statuses.iter().filter(|x| *x == "delivered").count(), 1);
+    assert_eq!(statuses.iter().filter(|x| *x == "failed").count(), 1);
+
+    assert!(models::Webhook::delete(&mut trans, crate_hook.id, Some("owo")).await?);
+    assert!(!models::Webhook::delete(&mut trans, registry_hook.id, Some("owo")).await?);
 
     Ok(())
 }
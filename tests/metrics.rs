@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use registmily::metrics;
+
+#[test]
+fn test_encode() {
+    let metrics = metrics::metrics();
+    metrics.record_request(
+        "GET",
+        "/api/v1/crates/:crate_name",
+        StatusCode::OK,
+        Duration::from_millis(5),
+    );
+    metrics.record_registry_operation("publish", true, Duration::from_secs(1));
+    metrics.record_git("push", false, Duration::from_secs(2));
+    metrics.record_download(1234);
+
+    let encoded = String::from_utf8(metrics.encode().unwrap()).unwrap();
+    assert!(encoded.contains(
+        "registmily_http_requests_total{method=\"GET\",route=\"/api/v1/crates/:crate_name\",status=\"200\"} 1"
+    ));
+    assert!(encoded
+        .contains("registmily_registry_operations_total{operation=\"publish\",result=\"ok\"} 1"));
+    assert!(encoded.contains("registmily_git_failures_total{step=\"push\"} 1"));
+    assert!(encoded.contains("registmily_download_bytes_total 1234"));
+}
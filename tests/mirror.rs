@@ -0,0 +1,131 @@
+mod common;
+
+use common::{crate_file, Entry};
+use registmily::mirror;
+use registmily::models;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+#[test]
+pub fn test_parse_index_line() {
+    let pkg = mirror::parse_index_line(
+        r#"{"name":"foo","vers":"1.0.0","deps":[{"name":"bar","req":"^1","features":[],"optional":true,"default_features":true,"target":null,"kind":"normal"}],"cksum":"abc","features":{"default":["std"]},"features2":{"bar":["dep:bar"]},"yanked":false,"v":2}"#,
+    )
+    .unwrap();
+
+    assert_eq!(pkg.name, "foo");
+    assert_eq!(pkg.vers, "1.0.0");
+    assert_eq!(pkg.deps[0].registry, serde_json::Value::Null);
+    assert_eq!(
+        pkg.features,
+        serde_json::json!({"default": ["std"], "bar": ["dep:bar"]})
+    );
+    assert_eq!(pkg.links, serde_json::Value::Null);
+}
+
+#[tokio::test]
+pub async fn test_snapshot() {
+    let _ = fs::remove_dir_all("mirror_test_snapshot");
+    fs::create_dir_all("mirror_test_snapshot/index/3/f").unwrap();
+    fs::create_dir_all("mirror_test_snapshot/crates").unwrap();
+
+    let crate_file = b"owo";
+    let cksum = hex::encode(Sha256::digest(crate_file));
+    let lines = [
+        format!(
+            r#"{{"name":"foo","vers":"0.1.0","deps":[],"cksum":"{}","features":{{}},"yanked":false,"links":null}}"#,
+            cksum
+        ),
+        String::from(
+            r#"{"name":"foo","vers":"0.2.0","deps":[],"cksum":"0000","features":{},"yanked":false,"links":null}"#,
+        ),
+    ];
+    fs::write("mirror_test_snapshot/index/3/f/foo", lines.join("\n")).unwrap();
+    fs::write("mirror_test_snapshot/crates/foo-0.1.0.crate", crate_file).unwrap();
+    fs::write("mirror_test_snapshot/crates/foo-0.2.0.crate", crate_file).unwrap();
+
+    let snapshot =
+        mirror::Snapshot::new("mirror_test_snapshot/index", "mirror_test_snapshot/crates");
+
+    let versions = snapshot.versions("Foo").await.unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(snapshot.crate_file(&versions[0]).await.unwrap(), crate_file);
+    assert!(matches!(
+        snapshot.crate_file(&versions[1]).await,
+        Err(mirror::MirrorError::ChecksumMismatch(_, _))
+    ));
+    assert!(matches!(
+        snapshot.versions("bar").await,
+        Err(mirror::MirrorError::CrateNotFound(_))
+    ));
+    assert!(matches!(
+        snapshot.versions("../foo").await,
+        Err(mirror::MirrorError::InvalidName(_))
+    ));
+}
+
+#[test]
+pub fn test_diff_crates() {
+    let old = mirror::unpack_crate(
+        &crate_file(&[
+            Entry::File(
+                "foo-0.1.0/Cargo.toml",
+                b"[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+            ),
+            Entry::File("foo-0.1.0/src/lib.rs", b"pub fn foo() {}\n"),
+            Entry::File("foo-0.1.0/build.rs", b"fn main() {}\n"),
+        ]),
+        1024 * 1024,
+    )
+    .unwrap();
+    let new = mirror::unpack_crate(
+        &crate_file(&[
+            Entry::File(
+                "foo-0.2.0/Cargo.toml",
+                b"[package]\nname = \"foo\"\nversion = \"0.2.0\"\n",
+            ),
+            Entry::File("foo-0.2.0/src/lib.rs", b"pub fn foo() {}\n"),
+            Entry::File("foo-0.2.0/src/evil.rs", b"pub fn evil() {}\n"),
+        ]),
+        1024 * 1024,
+    )
+    .unwrap();
+
+    assert_eq!(
+        new.keys().collect::<Vec<_>>(),
+        vec!["Cargo.toml", "src/evil.rs", "src/lib.rs"]
+    );
+
+    let diff = mirror::diff_crates(&old, &new);
+    assert_eq!(diff.len(), 3);
+
+    assert_eq!(diff[0].path, "Cargo.toml");
+    assert_eq!(diff[0].change, models::FileChange::Modified);
+    assert!(diff[0]
+        .diff
+        .contains("-version = \"0.1.0\"\n+version = \"0.2.0\"\n"));
+
+    assert_eq!(diff[1].path, "build.rs");
+    assert_eq!(diff[1].change, models::FileChange::Removed);
+
+    assert_eq!(diff[2].path, "src/evil.rs");
+    assert_eq!(diff[2].change, models::FileChange::Added);
+    assert!(diff[2]
+        .diff
+        .starts_with("--- a/src/evil.rs\n+++ b/src/evil.rs\n"));
+    assert!(diff[2].diff.contains("+pub fn evil() {}\n"));
+}
+
+#[test]
+pub fn test_unpack_crate_too_large() {
+    let big = "a".repeat(4096);
+    let file = crate_file(&[Entry::File("foo-0.1.0/src/lib.rs", big.as_bytes())]);
+
+    assert!(matches!(
+        mirror::unpack_crate(&file, 1024),
+        Err(mirror::MirrorError::CrateFile(
+            registmily::crate_file::CrateFileError::TooLarge(1024)
+        ))
+    ));
+    assert!(mirror::unpack_crate(&file, 16 * 1024).is_ok());
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    http::{header, Request, StatusCode},
+    response::IntoResponse,
+};
+use registmily::ratelimit::{client_ip, RateLimit, RateLimited, RateLimiter};
+
+#[test]
+fn test_token_bucket() {
+    let limiter = RateLimiter::new(Some(RateLimit {
+        burst: 2,
+        per_minute: 60,
+    }));
+    let start = Instant::now();
+
+    assert!(limiter.check_at("alice", start).is_ok());
+    assert!(limiter.check_at("alice", start).is_ok());
+    assert_eq!(
+        limiter.check_at("alice", start),
+        Err(Duration::from_secs(1))
+    );
+    // every key has its own bucket
+    assert!(limiter.check_at("bob", start).is_ok());
+
+    assert_eq!(
+        limiter.check_at("alice", start + Duration::from_millis(500)),
+        Err(Duration::from_millis(500))
+    );
+    assert!(limiter
+        .check_at("alice", start + Duration::from_secs(1))
+        .is_ok());
+    // the bucket doesn't fill up past the burst
+    let later = start + Duration::from_secs(3600);
+    assert!(limiter.check_at("alice", later).is_ok());
+    assert!(limiter.check_at("alice", later).is_ok());
+    assert!(limiter.check_at("alice", later).is_err());
+}
+
+#[test]
+fn test_unlimited() {
+    let limiter = RateLimiter::new(None);
+    let start = Instant::now();
+
+    for _ in 0..1000 {
+        assert!(limiter.check_at("alice", start).is_ok());
+    }
+}
+
+#[test]
+fn test_rate_limited_response() {
+    let response = RateLimited(Duration::from_millis(1500)).into_response();
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+}
+
+#[test]
+fn test_client_ip() {
+    let request = Request::builder()
+        .header("x-forwarded-for", "203.0.113.7, 198.51.100.1")
+        .body(())
+        .unwrap();
+
+    assert_eq!(client_ip(&request, true), "198.51.100.1");
+    assert_eq!(client_ip(&request, false), "unknown");
+}
@@ -0,0 +1,42 @@
+use registmily::readme;
+
+#[test]
+fn test_render_markdown() {
+    let rendered = readme::render(
+        "# Foo\n\n[guide](docs/guide.md) ![logo](./logo.png) [abs](https://example.com) [top](#foo)",
+        Some("README.md"),
+        Some("https://github.com/emily/foo.git"),
+    );
+    assert!(rendered.contains("<h1>Foo</h1>"));
+    assert!(rendered.contains("href=\"https://github.com/emily/foo/blob/HEAD/docs/guide.md\""));
+    assert!(rendered.contains("src=\"https://raw.githubusercontent.com/emily/foo/HEAD/logo.png\""));
+    assert!(rendered.contains("href=\"https://example.com\""));
+    assert!(rendered.contains("href=\"#foo\""));
+}
+
+#[test]
+fn test_render_relative_to_readme() {
+    let rendered = readme::render(
+        "[changes](CHANGELOG.md) [license](/LICENSE)",
+        Some("crates/foo/README.md"),
+        Some("https://gitlab.example.com/team/foo/"),
+    );
+    assert!(rendered.contains(
+        "href=\"https://gitlab.example.com/team/foo/-/blob/HEAD/crates/foo/CHANGELOG.md\""
+    ));
+    assert!(rendered.contains("href=\"https://gitlab.example.com/team/foo/-/blob/HEAD/LICENSE\""));
+}
+
+#[test]
+fn test_render_sanitizes() {
+    let rendered = readme::render(
+        "<script>alert(1)</script><a href=\"javascript:alert(1)\">hi</a>",
+        None,
+        None,
+    );
+    assert!(!rendered.contains("<script>"));
+    assert!(!rendered.contains("javascript:"));
+
+    let rendered = readme::render("<b>not markdown</b>", Some("README.txt"), None);
+    assert_eq!(rendered, "<pre>&lt;b&gt;not markdown&lt;/b&gt;</pre>");
+}
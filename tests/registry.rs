@@ -1,3 +1,5 @@
+mod common;
+
 use openid_client::config::AlgorithmWrappedKeys;
 use openid_client::config::JwtKeyConfig;
 use openid_client::config::SymmetricKeyConfig;
@@ -6,7 +8,11 @@ use registmily::init_registry;
 use registmily::models;
 use registmily::registry;
 use registmily::settings;
+use registmily::sparse_index;
+use registmily::storage;
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::types::chrono::{self, Utc};
 use std::fs;
 use std::path::Path;
 use time::Duration;
@@ -37,6 +43,233 @@ pub fn test_registry() {
     );
 }
 
+#[test]
+pub fn test_crate_names() {
+    use registry::CrateNameError;
+
+    assert_eq!(registry::validate_package_name("foo"), Ok(()));
+    assert_eq!(registry::validate_package_name("Foo-bar_2"), Ok(()));
+    assert_eq!(registry::canonical_crate_name("Foo-bar_2"), "foo_bar_2");
+    assert_eq!(
+        registry::validate_package_name(""),
+        Err(CrateNameError::Empty)
+    );
+    assert_eq!(
+        registry::validate_package_name(&"a".repeat(65)),
+        Err(CrateNameError::TooLong)
+    );
+    assert_eq!(
+        registry::validate_package_name("1foo"),
+        Err(CrateNameError::InvalidStart)
+    );
+    assert_eq!(
+        registry::validate_package_name("_foo"),
+        Err(CrateNameError::InvalidStart)
+    );
+    assert_eq!(
+        registry::validate_package_name("foo.bar"),
+        Err(CrateNameError::InvalidCharacter('.'))
+    );
+    assert_eq!(
+        registry::validate_package_name("foo/bar"),
+        Err(CrateNameError::InvalidCharacter('/'))
+    );
+    assert_eq!(
+        registry::validate_package_name("föo"),
+        Err(CrateNameError::InvalidCharacter('ö'))
+    );
+    assert_eq!(
+        registry::validate_package_name("NUL"),
+        Err(CrateNameError::Reserved(String::from("NUL")))
+    );
+    assert_eq!(
+        registry::validate_package_name("com1"),
+        Err(CrateNameError::Reserved(String::from("com1")))
+    );
+}
+
+#[test]
+pub fn test_crate_patterns() {
+    assert!(registry::validate_crate_pattern("foo").is_ok());
+    assert!(registry::validate_crate_pattern("foo-*").is_ok());
+    assert!(registry::validate_crate_pattern("*").is_ok());
+    assert!(registry::validate_crate_pattern("foo*bar").is_err());
+    assert!(registry::validate_crate_pattern("../*").is_err());
+
+    assert!(registry::crate_pattern_matches("foo", "Foo"));
+    assert!(!registry::crate_pattern_matches("foo", "foo-bar"));
+    assert!(registry::crate_pattern_matches("foo-*", "foo-bar"));
+    assert!(!registry::crate_pattern_matches("foo-*", "foobar"));
+    assert!(registry::crate_pattern_matches("*", "anything"));
+}
+
+#[test]
+pub fn test_same_version() {
+    let version = semver::Version::parse("1.0.0").unwrap();
+    assert!(registry::is_same_version("1.0.0", &version));
+    assert!(registry::is_same_version("1.0.0+build.5", &version));
+    assert!(!registry::is_same_version("1.0.0-alpha", &version));
+    assert!(!registry::is_same_version("1.0.1", &version));
+
+    let version = semver::Version::parse("1.0.0-alpha+build").unwrap();
+    assert!(registry::is_same_version("1.0.0-alpha", &version));
+    assert!(!registry::is_same_version("1.0.0", &version));
+}
+
+#[test]
+pub fn test_sparse_index_paths() {
+    assert_eq!(
+        sparse_index::resolve_index_path("testgit", "config.json"),
+        Some(Path::new("testgit/config.json").to_path_buf())
+    );
+    assert_eq!(
+        sparse_index::resolve_index_path("testgit", "3/f/foo"),
+        Some(Path::new("testgit/3/f/foo").to_path_buf())
+    );
+    assert_eq!(
+        sparse_index::resolve_index_path("testgit", "ab/cd/abcd"),
+        Some(Path::new("testgit/ab/cd/abcd").to_path_buf())
+    );
+    assert_eq!(
+        sparse_index::resolve_index_path("testgit", "ab/cd/abce"),
+        None
+    );
+    assert_eq!(
+        sparse_index::resolve_index_path("testgit", "3/f/Foo"),
+        Some(Path::new("testgit/3/f/foo").to_path_buf())
+    );
+    assert_eq!(
+        sparse_index::resolve_index_path("testgit", "../3/f/foo"),
+        None
+    );
+    assert_eq!(sparse_index::resolve_index_path("testgit", "2/.."), None);
+    assert_eq!(sparse_index::resolve_index_path("testgit", ""), None);
+}
+
+#[test]
+pub fn test_setup_registry() {
+    let _ = fs::remove_dir_all("init_test_repo");
+    let _ = fs::remove_dir_all("init_test_storage");
+
+    let commit_count = || {
+        let repo = git2::Repository::open("init_test_repo").unwrap();
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        revwalk.count()
+    };
+
+    init_registry::setup_registry(
+        Path::new("init_test_repo"),
+        Path::new("init_test_storage"),
+        "http://localhost:8080/",
+    )
+    .unwrap();
+    assert_eq!(commit_count(), 1);
+    assert!(Path::new("init_test_storage").is_dir());
+
+    // running it again doesn't change anything
+    init_registry::setup_registry(
+        Path::new("init_test_repo"),
+        Path::new("init_test_storage"),
+        "http://localhost:8080",
+    )
+    .unwrap();
+    assert_eq!(commit_count(), 1);
+
+    // other keys survive updating the urls
+    fs::write(
+        "init_test_repo/config.json",
+        r#"{"dl":"old","api":"old","auth-required":true}"#,
+    )
+    .unwrap();
+    init_registry::setup_registry(
+        Path::new("init_test_repo"),
+        Path::new("init_test_storage"),
+        "https://crates.example.com",
+    )
+    .unwrap();
+    assert_eq!(commit_count(), 2);
+
+    let config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string("init_test_repo/config.json").unwrap()).unwrap();
+    assert_eq!(
+        config,
+        json!({
+            "dl": "https://crates.example.com/api/v1/crates/{crate}/{version}/download",
+            "api": "https://crates.example.com",
+            "auth-required": true
+        })
+    );
+
+    fs::write("init_test_repo/config.json", "[]").unwrap();
+    assert!(matches!(
+        init_registry::setup_registry(
+            Path::new("init_test_repo"),
+            Path::new("init_test_storage"),
+            "https://crates.example.com",
+        ),
+        Err(init_registry::InitError::ConfigNotObject)
+    ));
+}
+
+#[test]
+pub fn test_failed_commit() {
+    let _ = fs::remove_dir_all("commit_test_repo");
+    init_registry::setup_registry(
+        Path::new("commit_test_repo"),
+        Path::new("commit_test_storage"),
+        "http://localhost:8080",
+    )
+    .unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let registry = registry::Registry::new(
+        "commit_test_repo",
+        std::sync::Arc::new(storage::LocalStorage::new("commit_test_storage")),
+        runtime.handle().clone(),
+    );
+    let index_file = Path::new("commit_test_repo/3/f/foo");
+    let entry = r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"abcd","features":{},"yanked":false,"links":null}"#;
+    fs::create_dir_all("commit_test_repo/3/f").unwrap();
+    fs::write(index_file, entry).unwrap();
+    registry
+        .commit_git_files(vec![index_file], "added crate")
+        .unwrap();
+
+    // git can't move the branch while someone else holds its lock
+    fs::write("commit_test_repo/.git/refs/heads/main.lock", "").unwrap();
+    assert!(matches!(
+        registry.yank(String::from("foo"), String::from("0.1.0"), true),
+        Err(registry::YankError::Git(_))
+    ));
+    assert_eq!(fs::read_to_string(index_file).unwrap(), entry);
+
+    fs::remove_file("commit_test_repo/.git/refs/heads/main.lock").unwrap();
+    registry
+        .yank(String::from("foo"), String::from("0.1.0"), true)
+        .unwrap();
+    assert!(fs::read_to_string(index_file)
+        .unwrap()
+        .contains(r#""yanked":true"#));
+}
+
+fn build_publish_body(
+    json: &serde_json::Value,
+    crate_file: &[u8],
+) -> Result<Vec<u8>, serde_json::Error> {
+    let mut body: Vec<u8> = Vec::new();
+
+    let json_str = serde_json::to_string(json)?;
+    let json_len = json_str.len() as u32;
+    body.extend_from_slice(&json_len.to_le_bytes());
+    body.extend_from_slice(json_str.as_bytes());
+    let crate_file_len = crate_file.len() as u32;
+    body.extend_from_slice(&crate_file_len.to_le_bytes());
+    body.extend_from_slice(crate_file);
+
+    Ok(body)
+}
+
 #[sqlx_database_tester::test(pool(variable = "pool"))]
 pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
     let username = "emily";
@@ -44,7 +277,9 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
 
     models::User::new(&mut trans, username).await?;
 
-    let session = models::UserSession::new(&mut trans, username).await?;
+    let (_, token) =
+        models::UserSession::new(&mut trans, username, Utc::now() + chrono::Duration::days(1))
+            .await?;
 
     trans.commit().await?;
 
@@ -105,7 +340,7 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
              "package":null
           }
        ],
-       "cksum":"43cae2eafda4d7a9b31768c8a6f086d7942e97d3a96c75326b3a1f4b17b1cffd",
+       "cksum":"",
        "features":{
           "extras":[
              "rand/simd_support"
@@ -117,16 +352,9 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
-    // Scary
-    let _ = fs::remove_dir_all("e2e_test_repo");
-    let _ = fs::remove_dir_all("e2e_test_storage");
-    init_registry::setup_registry(
-        Path::new("e2e_test_repo"),
-        Path::new("e2e_test_storage"),
-        "http://localhost:8080",
-    );
-
     let config = settings::Settings {
+        bind_address: String::from("0.0.0.0:8080"),
+        api_url: String::from("http://localhost:8080"),
         repo_path: String::from("e2e_test_repo"),
         storage_path: String::from("e2e_test_storage"),
         database_url: String::from(""),
@@ -136,6 +364,7 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
         openid_client_id: String::from(""),
         openid_client_secret: String::from(""),
         openid_nonce: String::from("uwu"),
+        openid_redirect_uri: None,
         jwt_key_config: JwtKeyConfig {
             algorithm_wrapped_key: AlgorithmWrappedKeys::HS512 {
                 key: SymmetricKeyConfig {
@@ -146,17 +375,59 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
         }
         .parse_keys()
         .unwrap(),
+        admins: vec![],
+        mirror_index_path: None,
+        mirror_crates_path: None,
+        s3: None,
+        docs_build_path: None,
+        docs_sandbox: vec![],
+        session_days: 30,
+        owner_invitation_days: 30,
+        max_unpacked_crate_size: 512 * 1024 * 1024,
+        rate_limits: Default::default(),
+        trust_request_id: false,
+        webhook_allowed_hosts: vec![],
     };
 
+    // Scary
+    let _ = fs::remove_dir_all("e2e_test_repo");
+    let _ = fs::remove_dir_all("e2e_test_storage");
+    init_registry::setup_registry(
+        Path::new(&config.repo_path),
+        Path::new(&config.storage_path),
+        &config.api_url,
+    )?;
+
+    {
+        let index_config: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string("e2e_test_repo/config.json")?)?;
+        assert_eq!(
+            index_config,
+            json!({
+                "dl": "http://localhost:8080/api/v1/crates/{crate}/{version}/download",
+                "api": "http://localhost:8080"
+            })
+        );
+    }
+
     let config_repo_path = config.repo_path.clone();
-    let config_storage_path = config.storage_path.clone();
+    let config_storage = storage::from_settings(&config);
+    let handler_storage = config_storage.clone();
+    let runtime = tokio::runtime::Handle::current();
 
     let (sender, recv) = tokio::sync::mpsc::channel(u16::MAX as usize);
-    std::thread::spawn(move || registry::handler(&config_repo_path, &config_storage_path, recv));
+    std::thread::spawn(move || {
+        registry::handler(&config_repo_path, handler_storage, runtime, recv)
+    });
 
     info!("Registry handler spawned");
 
-    task::spawn(apiserver::serve(sender, config, pool));
+    task::spawn(apiserver::serve(
+        sender,
+        config_storage,
+        config,
+        pool.clone(),
+    ));
     task::yield_now().await;
 
     info!("Apiserver spawned");
@@ -166,24 +437,18 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
     //     assert_eq!(resp.text().await?, "uwu");
     // }
 
-    let crate_file = "owo";
+    let crate_file = common::minimal_crate_file("foo", "0.1.0");
+    let checksum = hex::encode(Sha256::digest(&crate_file));
+    expected_index_json["cksum"] = json!(checksum);
 
     {
-        let mut new_body: Vec<u8> = Vec::new();
-
-        let new_json_str = serde_json::to_string(&new_post_json)?;
-        let json_len = new_json_str.len() as u32;
-        new_body.extend_from_slice(&json_len.to_le_bytes());
-        new_body.extend_from_slice(new_json_str.as_bytes());
-        let crate_file_len = crate_file.len() as u32;
-        new_body.extend_from_slice(&crate_file_len.to_le_bytes());
-        new_body.extend_from_slice(crate_file.as_bytes());
+        let new_body = build_publish_body(&new_post_json, &crate_file)?;
 
         let client = reqwest::Client::new();
         // todo: ensure response fine
         client
             .put("http://localhost:8080/api/v1/crates/new")
-            .header("authorization", &session.token)
+            .header("authorization", &token)
             .body(new_body)
             .send()
             .await?;
@@ -194,17 +459,208 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     {
-        let crate_file_real = reqwest::get("http://localhost:8080/api/v1/dl/43cae2eafda4d7a9b31768c8a6f086d7942e97d3a96c75326b3a1f4b17b1cffd").await?;
-        let crate_file_real = crate_file_real.text().await?;
+        let mut bad_name_json = new_post_json.clone();
+        bad_name_json["name"] = json!("foo.bar");
+        let new_body = build_publish_body(&bad_name_json, &crate_file)?;
+
+        let client = reqwest::Client::new();
+        let resp: serde_json::Value = serde_json::from_str(
+            &client
+                .put("http://localhost:8080/api/v1/crates/new")
+                .header("authorization", &token)
+                .body(new_body)
+                .send()
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(
+            resp["errors"][0]["detail"],
+            "Invalid crate name: crate name contains invalid character `.`"
+        );
+    }
+
+    {
+        let mut republish_json = new_post_json.clone();
+        republish_json["vers"] = json!("0.1.0+rebuilt");
+        let new_body = build_publish_body(
+            &republish_json,
+            &common::minimal_crate_file("foo", "0.1.0+rebuilt"),
+        )?;
+
+        let client = reqwest::Client::new();
+        let resp: serde_json::Value = serde_json::from_str(
+            &client
+                .put("http://localhost:8080/api/v1/crates/new")
+                .header("authorization", &token)
+                .body(new_body)
+                .send()
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(
+            resp["errors"][0]["detail"],
+            "crate version `0.1.0+rebuilt` is already uploaded"
+        );
+
+        let real_json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string("e2e_test_repo/3/f/foo")?)?;
+        assert_eq!(real_json, expected_index_json);
+    }
+
+    {
+        let search_json: serde_json::Value = serde_json::from_str(
+            &reqwest::get("http://localhost:8080/api/v1/crates?q=fo&per_page=5")
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(
+            search_json,
+            json!({
+                "crates": [{"name": "foo", "max_version": "0.1.0", "description": null}],
+                "meta": {"total": 1}
+            })
+        );
+    }
+
+    {
+        let crate_json: serde_json::Value = serde_json::from_str(
+            &reqwest::get("http://localhost:8080/api/v1/crates/foo")
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(crate_json["crate"]["name"], "foo");
+        assert_eq!(crate_json["crate"]["max_version"], "0.1.0");
+        assert_eq!(crate_json["versions"][0]["num"], "0.1.0");
+        assert_eq!(
+            crate_json["versions"][0]["dl_path"],
+            "/api/v1/crates/foo/0.1.0/download"
+        );
+        assert_eq!(crate_json["versions"][0]["published_by"]["login"], username);
+        assert_eq!(crate_json["owners"][0]["login"], username);
+
+        let version_json: serde_json::Value = serde_json::from_str(
+            &reqwest::get("http://localhost:8080/api/v1/crates/foo/0.1.0")
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(version_json["version"]["yanked"], false);
+        assert_eq!(
+            version_json["dependencies"],
+            json!([{
+                "crate_id": "rand",
+                "req": "^0.6",
+                "optional": false,
+                "default_features": true,
+                "features": ["i128_support"],
+                "target": null,
+                "kind": "normal"
+            }])
+        );
+
+        let resp = reqwest::get("http://localhost:8080/api/v1/crates/foo/0.2.0").await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        let list = client
+            .get("http://localhost:8080/?q=fo")
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert!(list.contains("<a href=\"/crates/foo\">foo</a>"));
+
+        let crate_page = client
+            .get("http://localhost:8080/crates/foo")
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert!(crate_page.contains("<code>^0.6</code>"));
+        assert!(crate_page.contains("rand/simd_support"));
+
+        let resp = client
+            .get("http://localhost:8080/crates/foo/0.2.0")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let resp = client.get("http://localhost:8080/tokens").send().await?;
+        assert!(resp.status().is_redirection());
+
+        let tokens_page = client
+            .get("http://localhost:8080/tokens")
+            .header("cookie", format!("registmily_session={}", token))
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert!(tokens_page.contains(username));
+        assert!(tokens_page.contains("New api token"));
+    }
+
+    {
+        let client = reqwest::Client::new();
+        let resp = client
+            .get("http://localhost:8080/index/3/f/foo")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let etag = resp.headers()["etag"].clone();
+        let sparse_json: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(sparse_json, expected_index_json);
+
+        let resp = client
+            .get("http://localhost:8080/index/3/f/foo")
+            .header("if-none-match", etag)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+
+        let resp = client
+            .get("http://localhost:8080/index/config.json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let resp = client
+            .get("http://localhost:8080/index/3/b/bar")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    {
+        let crate_file_real =
+            reqwest::get("http://localhost:8080/api/v1/crates/foo/0.1.0/download").await?;
+        let crate_file_real = crate_file_real.bytes().await?;
         assert_eq!(crate_file, crate_file_real);
-        assert_eq!(crate_file, &fs::read_to_string("e2e_test_storage/43cae2eafda4d7a9b31768c8a6f086d7942e97d3a96c75326b3a1f4b17b1cffd.crate")?);
+        let crate_file_real =
+            reqwest::get(format!("http://localhost:8080/api/v1/dl/{}", checksum)).await?;
+        let crate_file_real = crate_file_real.bytes().await?;
+        assert_eq!(crate_file, crate_file_real);
+        let resp = reqwest::get("http://localhost:8080/api/v1/crates/foo/0.2.0/download").await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(
+            crate_file,
+            fs::read(format!("e2e_test_storage/{}.crate", checksum))?
+        );
     }
 
     {
         let client = reqwest::Client::new();
         client
             .delete("http://localhost:8080/api/v1/crates/foo/0.1.0/yank")
-            .header("authorization", &session.token)
+            .header("authorization", &token)
             .send()
             .await?;
         expected_index_json["yanked"] = serde_json::Value::Bool(true);
@@ -217,7 +673,7 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
         let client = reqwest::Client::new();
         client
             .put("http://localhost:8080/api/v1/crates/foo/0.1.0/unyank")
-            .header("authorization", &session.token)
+            .header("authorization", &token)
             .send()
             .await?;
         expected_index_json["yanked"] = serde_json::Value::Bool(false);
@@ -226,5 +682,115 @@ pub async fn e2e_tests() -> Result<(), Box<dyn std::error::Error>> {
         assert_eq!(real_json, expected_index_json);
     }
 
+    {
+        let mut uppercase_json = new_post_json.clone();
+        uppercase_json["name"] = json!("Foo_Bar");
+        let new_body = build_publish_body(
+            &uppercase_json,
+            &common::minimal_crate_file("Foo_Bar", "0.1.0"),
+        )?;
+
+        let client = reqwest::Client::new();
+        client
+            .put("http://localhost:8080/api/v1/crates/new")
+            .header("authorization", &token)
+            .body(new_body)
+            .send()
+            .await?;
+        assert!(Path::new("e2e_test_repo/fo/o_/foo_bar").exists());
+
+        // cargo asks for the lowercased path
+        let resp = client
+            .get("http://localhost:8080/index/fo/o_/foo_bar")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let sparse_json: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(sparse_json["name"], "Foo_Bar");
+
+        let mut clashing_json = new_post_json.clone();
+        clashing_json["name"] = json!("foo-bar");
+        let new_body = build_publish_body(
+            &clashing_json,
+            &common::minimal_crate_file("foo-bar", "0.1.0"),
+        )?;
+        let resp: serde_json::Value = serde_json::from_str(
+            &client
+                .put("http://localhost:8080/api/v1/crates/new")
+                .header("authorization", &token)
+                .body(new_body)
+                .send()
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(
+            resp["errors"][0]["detail"],
+            "crate was previously named `Foo_Bar`"
+        );
+    }
+
+    {
+        // bob invited carol and dave to foo without being an owner (anymore)
+        let mut trans = pool.begin().await?;
+        let expires_at = Utc::now() + chrono::Duration::days(1);
+        let mut tokens = Vec::new();
+        for user in ["bob", "carol", "dave"] {
+            models::User::new(&mut trans, user).await?;
+            tokens.push(
+                models::UserSession::new(&mut trans, user, expires_at)
+                    .await?
+                    .1,
+            );
+        }
+        models::OwnerInvitation::new(&mut trans, "foo", "carol", "bob", expires_at).await?;
+        models::OwnerInvitation::new(&mut trans, "foo", "dave", "bob", expires_at).await?;
+        trans.commit().await?;
+        let stale = "bob is no longer an owner of foo, so the invitation isn't valid anymore";
+
+        let client = reqwest::Client::new();
+        let resp: serde_json::Value = serde_json::from_str(
+            &client
+                .put("http://localhost:8080/api/v1/me/crate_owner_invitations/foo")
+                .header("authorization", &tokens[1])
+                .header("content-type", "application/json")
+                .body(json!({"crate_owner_invite": {"accepted": true}}).to_string())
+                .send()
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(resp["errors"][0]["detail"], stale);
+
+        let resp: serde_json::Value = serde_json::from_str(
+            &client
+                .get("http://localhost:8080/api/v1/me/crate_owner_invitations")
+                .header("authorization", &tokens[1])
+                .send()
+                .await?
+                .text()
+                .await?,
+        )?;
+        assert_eq!(resp["crate_owner_invitations"], json!([]));
+
+        let invitations_page = client
+            .post("http://localhost:8080/invitations/foo/accept")
+            .header("cookie", format!("registmily_session={}", tokens[2]))
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert!(invitations_page.contains("bob is no longer an owner of foo"));
+        assert!(!invitations_page.contains("/invitations/foo/accept"));
+
+        let mut trans = pool.begin().await?;
+        for user in ["carol", "dave"] {
+            assert!(models::OwnerInvitation::all_by_invitee(&mut trans, user)
+                .await?
+                .is_empty());
+            assert!(!models::CrateOwner::exists(&mut trans, "foo", user).await?);
+        }
+    }
+
     Ok(())
 }
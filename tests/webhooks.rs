@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use registmily::webhooks;
+
+#[test]
+fn test_signature() {
+    assert_eq!(
+        webhooks::signature("key", b"The quick brown fox jumps over the lazy dog"),
+        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+}
+
+#[test]
+fn test_retry_delay() {
+    assert_eq!(webhooks::retry_delay(1), Duration::from_secs(30));
+    assert_eq!(webhooks::retry_delay(2), Duration::from_secs(60));
+    assert_eq!(webhooks::retry_delay(5), Duration::from_secs(480));
+    assert_eq!(webhooks::retry_delay(12), Duration::from_secs(6 * 60 * 60));
+    assert_eq!(
+        webhooks::retry_delay(webhooks::MAX_ATTEMPTS),
+        Duration::from_secs(6 * 60 * 60)
+    );
+}
+
+#[test]
+fn test_validate_url() {
+    let validate_url = |url| webhooks::validate_url(url, &[]);
+    assert!(validate_url("https://ci.example.com/hooks/registmily").is_ok());
+    assert!(validate_url("http://93.184.216.34:8000/hook").is_ok());
+    assert!(validate_url("http://localhost:8000").is_err());
+    assert!(validate_url("http://api.localhost.").is_err());
+    assert!(validate_url("http://127.0.0.1:8080").is_err());
+    assert!(validate_url("http://169.254.169.254/latest/meta-data").is_err());
+    assert!(validate_url("http://[::1]/").is_err());
+    assert!(validate_url("file:///etc/passwd").is_err());
+    assert!(validate_url("not a url").is_err());
+}
+
+#[test]
+fn test_validate_url_allowed_hosts() {
+    let allowed_hosts = vec![
+        String::from("CI.internal."),
+        String::from("10.0.0.5"),
+        String::from("[fd00::1]"),
+    ];
+    let validate_url = |url| webhooks::validate_url(url, &allowed_hosts);
+    assert!(validate_url("http://ci.internal:8080/hook").is_ok());
+    assert!(validate_url("http://10.0.0.5/hook").is_ok());
+    assert!(validate_url("http://[fd00::1]/hook").is_ok());
+    assert!(validate_url("http://10.0.0.6/hook").is_err());
+    assert!(validate_url("http://localhost/hook").is_err());
+    assert!(validate_url("file://ci.internal/etc/passwd").is_err());
+}
+
+#[tokio::test]
+async fn test_check_url() {
+    assert!(webhooks::check_url("http://localhost:8000", &[])
+        .await
+        .is_err());
+    assert!(webhooks::check_url("http://10.0.0.1/hook", &[])
+        .await
+        .is_err());
+
+    // the address deliveries get pinned to
+    let allowed_hosts = [String::from("localhost")];
+    let (url, addr) = webhooks::check_url("http://localhost:8000/hook", &allowed_hosts)
+        .await
+        .unwrap();
+    assert_eq!(url.path(), "/hook");
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 8000);
+}
+
+#[test]
+fn test_is_public_address() {
+    for ip in [
+        "93.184.216.34",
+        "2606:2800:220:1:248:1893:25c8:1946",
+        "1.1.1.1",
+    ] {
+        assert!(webhooks::is_public_address(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "255.255.255.255",
+        "::",
+        "::1",
+        "::ffff:127.0.0.1",
+        "fd00::1",
+        "fe80::1",
+        "ff02::1",
+    ] {
+        assert!(!webhooks::is_public_address(ip.parse().unwrap()), "{}", ip);
+    }
+}